@@ -0,0 +1,64 @@
+use std::collections::BTreeSet;
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::types::Pair;
+use crate::utils::pair_key;
+
+// Trading controls of the owner. In withdraw-only mode nothing can be deposited
+// or traded, but balances can still be withdrawn and orders cancelled.
+#[derive(CandidType, Clone, Deserialize, Serialize, Default)]
+pub struct AdminState {
+    pub withdraw_only: bool,
+    paused_pairs: BTreeSet<(Principal, Principal)>,
+}
+
+impl AdminState {
+    // Returns false if the pair was already paused.
+    pub fn pause(&mut self, a: Principal, b: Principal) -> bool {
+        self.paused_pairs.insert(pair_key(a, b))
+    }
+
+    // Returns false if the pair was not paused.
+    pub fn resume(&mut self, a: Principal, b: Principal) -> bool {
+        self.paused_pairs.remove(&pair_key(a, b))
+    }
+
+    pub fn is_trading(&self, a: Principal, b: Principal) -> bool {
+        !self.withdraw_only && !self.paused_pairs.contains(&pair_key(a, b))
+    }
+
+    pub fn paused_pairs(&self) -> Vec<Pair> {
+        self.paused_pairs
+            .iter()
+            .map(|(a, b)| Pair {
+                tokenA: *a,
+                tokenB: *b,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+
+    #[test]
+    fn pairs_are_paused_in_both_directions() {
+        let mut admin = AdminState::default();
+        assert!(admin.pause(principal(2), principal(1)));
+        assert!(!admin.pause(principal(1), principal(2)));
+        assert!(!admin.is_trading(principal(1), principal(2)));
+        assert!(admin.is_trading(principal(1), principal(3)));
+
+        admin.withdraw_only = true;
+        assert!(!admin.is_trading(principal(1), principal(3)));
+
+        assert!(admin.resume(principal(1), principal(2)));
+        assert!(!admin.resume(principal(1), principal(2)));
+        admin.withdraw_only = false;
+        assert!(admin.is_trading(principal(2), principal(1)));
+    }
+}
@@ -0,0 +1,211 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use candid::{CandidType, Principal};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+
+use crate::types::*;
+use crate::utils::pair_key;
+
+// A price of the base token in the quote token, as the ratio of two amounts so
+// that clearing doesn't depend on rounding.
+#[derive(Clone, Copy, Debug)]
+pub struct Price {
+    pub quote: u128,
+    pub base: u128,
+}
+
+impl Price {
+    pub fn to_f64(self) -> f64 {
+        self.quote as f64 / self.base as f64
+    }
+
+    // The amount of quote for `base` of the base token, rounded down.
+    pub fn quote_for(self, base: u128) -> u128 {
+        mul_div(base, self.quote, self.base)
+    }
+
+    // The amount of base that `quote` of the quote token buys, rounded down.
+    pub fn base_for(self, quote: u128) -> u128 {
+        mul_div(quote, self.base, self.quote)
+    }
+}
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (BigUint::from(self.quote) * BigUint::from(other.base))
+            .cmp(&(BigUint::from(other.quote) * BigUint::from(self.base)))
+    }
+}
+
+fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    (BigUint::from(a) * BigUint::from(b) / BigUint::from(c))
+        .try_into()
+        .unwrap_or(u128::MAX)
+}
+
+// The single price that a batch auction clears at. Bids offer an amount of the
+// quote token down to their price, asks an amount of the base token up to
+// theirs. The price trades the most base, then leaves the least of it
+// unmatched on the long side, then is the lowest. None if the book doesn't cross.
+pub fn clearing_price(bids: &[(Price, u128)], asks: &[(Price, u128)]) -> Option<Price> {
+    let mut bids = bids.to_vec();
+    let mut asks = asks.to_vec();
+    bids.sort_by_key(|o| o.0);
+    asks.sort_by_key(|o| o.0);
+    let mut candidates: Vec<Price> = bids.iter().chain(asks.iter()).map(|o| o.0).collect();
+    candidates.sort();
+    candidates.dedup();
+
+    // Bids below the candidate price and asks up to it, ascending with the price.
+    let mut bid_index = 0;
+    let mut ask_index = 0;
+    let mut budget: u128 = bids.iter().map(|o| o.1).sum();
+    let mut supply = 0;
+    let mut best: Option<(u128, u128, Price)> = None;
+    for price in candidates {
+        while bid_index < bids.len() && bids[bid_index].0 < price {
+            budget -= bids[bid_index].1;
+            bid_index += 1;
+        }
+        while ask_index < asks.len() && asks[ask_index].0 <= price {
+            supply += asks[ask_index].1;
+            ask_index += 1;
+        }
+        let demand = price.base_for(budget);
+        let volume = demand.min(supply);
+        let imbalance = demand.max(supply) - volume;
+        if volume == 0 {
+            continue;
+        }
+        match best {
+            Some((v, i, _)) if v > volume || (v == volume && i <= imbalance) => {}
+            _ => best = Some((volume, imbalance, price)),
+        }
+    }
+
+    best.map(|(_, _, price)| price)
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize)]
+pub struct ClearingState {
+    pub timestamp: u64,
+    pub price: Option<(u128, u128)>,
+    pub volume: u128,
+    pub quote_volume: u128,
+    pub trades: u32,
+}
+
+// A pair that is matched in batch auctions. The base token is the lower one of
+// the pair.
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize)]
+pub struct AuctionState {
+    pub epoch: u64,
+    pub next_clearing: u64,
+    pub last_clearing: Option<ClearingState>,
+}
+
+// The pairs in batch auction mode. Their orders rest on the book without being
+// matched until the end of each epoch.
+#[derive(CandidType, Clone, Deserialize, Serialize, Default)]
+pub struct BatchAuctions(BTreeMap<(Principal, Principal), AuctionState>);
+
+impl BatchAuctions {
+    pub fn is_batched(&self, a: Principal, b: Principal) -> bool {
+        self.0.contains_key(&pair_key(a, b))
+    }
+
+    // Starts batch auctions in the pair, or changes their epoch.
+    pub fn enable(&mut self, a: Principal, b: Principal, epoch: u64, now: u64) {
+        let auction = self.0.entry(pair_key(a, b)).or_insert(AuctionState {
+            epoch,
+            next_clearing: now + epoch,
+            last_clearing: None,
+        });
+        auction.epoch = epoch;
+        auction.next_clearing = auction.next_clearing.min(now + epoch);
+    }
+
+    pub fn disable(&mut self, a: Principal, b: Principal) -> bool {
+        self.0.remove(&pair_key(a, b)).is_some()
+    }
+
+    // The pairs whose epoch is over, as (base, quote).
+    pub fn due(&self, now: u64) -> Vec<(Principal, Principal)> {
+        self.0
+            .iter()
+            .filter(|(_, a)| a.next_clearing <= now)
+            .map(|(pair, _)| *pair)
+            .collect()
+    }
+
+    // Records a clearing and starts the next epoch.
+    pub fn cleared(&mut self, pair: (Principal, Principal), clearing: ClearingState) {
+        if let Some(auction) = self.0.get_mut(&pair) {
+            auction.next_clearing = clearing.timestamp + auction.epoch;
+            auction.last_clearing = Some(clearing);
+        }
+    }
+
+    pub fn get(&self, a: Principal, b: Principal) -> Option<BatchAuction> {
+        let (base, quote) = pair_key(a, b);
+        self.0.get(&(base, quote)).map(|a| BatchAuction {
+            base,
+            quote,
+            epoch: a.epoch,
+            nextClearing: a.next_clearing,
+            lastClearing: a.last_clearing.map(|c| AuctionClearing {
+                timestamp: c.timestamp,
+                price: c.price.map(|(quote, base)| Price { quote, base }.to_f64()),
+                volume: c.volume.into(),
+                quoteVolume: c.quote_volume.into(),
+                trades: c.trades,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price(quote: u128, base: u128) -> Price {
+        Price { quote, base }
+    }
+
+    #[test]
+    fn clearing_trades_the_most_at_one_price() {
+        // Bids for 100 base at 3, 100 base at 2, asks of 150 base at 1 and 50 at 4.
+        let bids = [(price(3, 1), 300), (price(2, 1), 200)];
+        let asks = [(price(1, 1), 150), (price(4, 1), 50)];
+        // At 1 and 2 all 150 base of the first ask are taken, with the least
+        // left over at 2.
+        assert_eq!(clearing_price(&bids, &asks), Some(price(2, 1)));
+
+        // Nothing crosses.
+        let asks = [(price(4, 1), 50)];
+        assert_eq!(clearing_price(&bids, &asks), None);
+        assert_eq!(clearing_price(&bids, &[]), None);
+
+        // Equal prices are compared exactly.
+        let bids = [(price(20, 10), 20)];
+        let asks = [(price(2, 1), 10)];
+        assert_eq!(clearing_price(&bids, &asks), Some(price(2, 1)));
+    }
+}
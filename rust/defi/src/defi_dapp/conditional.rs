@@ -0,0 +1,226 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::exchange::OrderState;
+use crate::trades::TradeState;
+use crate::types::*;
+use crate::OrderId;
+
+// An order that is placed once the last traded price of its pair crosses the
+// trigger. What it offers is reserved while it waits.
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize)]
+pub struct ConditionalOrderState {
+    pub order: OrderState,
+    pub order_type: OrderType,
+    pub trigger: Trigger,
+}
+
+impl ConditionalOrderState {
+    // Whether `trade` was in the pair of the order at a price that crosses the trigger.
+    fn is_triggered_by(&self, trade: &TradeState) -> bool {
+        let from = self.order.from_token_canister_id;
+        let to = self.order.to_token_canister_id;
+        if !(trade.taker_token_canister_id == from && trade.maker_token_canister_id == to
+            || trade.taker_token_canister_id == to && trade.maker_token_canister_id == from)
+        {
+            return false;
+        }
+        match (trade.price(&from), self.trigger.condition) {
+            (Some(price), TriggerCondition::StopLoss) => price <= self.trigger.price,
+            (Some(price), TriggerCondition::TakeProfit) => price >= self.trigger.price,
+            (None, _) => false,
+        }
+    }
+}
+
+impl From<ConditionalOrderState> for ConditionalOrder {
+    fn from(o: ConditionalOrderState) -> ConditionalOrder {
+        ConditionalOrder {
+            order: o.order.into(),
+            orderType: o.order_type,
+            trigger: o.trigger,
+        }
+    }
+}
+
+// How many triggered orders keep their outcome.
+const MAX_TRIGGERED: usize = 10_000;
+
+// A conditional order that a trade triggered, and what became of it.
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize)]
+pub struct TriggeredOrderState {
+    pub order: ConditionalOrderState,
+    pub triggered_at: u64,
+    pub status: OrderStatus,
+}
+
+impl From<TriggeredOrderState> for TriggeredOrder {
+    fn from(o: TriggeredOrderState) -> TriggeredOrder {
+        TriggeredOrder {
+            order: o.order.into(),
+            triggeredAt: o.triggered_at,
+            status: o.status,
+        }
+    }
+}
+
+// Conditional orders are kept apart from the book, so that matching never sees them.
+#[derive(CandidType, Clone, Deserialize, Serialize, Default)]
+pub struct ConditionalOrders {
+    waiting: BTreeMap<OrderId, ConditionalOrderState>,
+    // The most recently triggered orders, oldest first.
+    triggered: VecDeque<TriggeredOrderState>,
+}
+
+impl ConditionalOrders {
+    pub fn get(&self, id: &OrderId) -> Option<&ConditionalOrderState> {
+        self.waiting.get(id)
+    }
+
+    pub fn insert(&mut self, order: ConditionalOrderState) {
+        self.waiting.insert(order.order.id, order);
+    }
+
+    pub fn remove(&mut self, id: &OrderId) -> Option<ConditionalOrderState> {
+        self.waiting.remove(id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &ConditionalOrderState> {
+        self.waiting.values()
+    }
+
+    pub fn of(&self, owner: &Principal) -> Vec<ConditionalOrder> {
+        self.waiting
+            .values()
+            .filter(|o| o.order.owner == *owner)
+            .map(|o| (*o).into())
+            .collect()
+    }
+
+    // Records what became of a triggered order, forgetting the oldest outcome
+    // beyond MAX_TRIGGERED.
+    pub fn record_triggered(
+        &mut self,
+        order: ConditionalOrderState,
+        status: OrderStatus,
+        now: u64,
+    ) {
+        if self.triggered.len() == MAX_TRIGGERED {
+            self.triggered.pop_front();
+        }
+        self.triggered.push_back(TriggeredOrderState {
+            order,
+            triggered_at: now,
+            status,
+        });
+    }
+
+    // The triggered orders of `owner`, most recent first.
+    pub fn triggered_of(&self, owner: &Principal) -> Vec<TriggeredOrder> {
+        self.triggered
+            .iter()
+            .rev()
+            .filter(|o| o.order.order.owner == *owner)
+            .map(|o| (*o).into())
+            .collect()
+    }
+
+    // Removes the orders whose waiting time ran out before they were triggered.
+    pub fn take_expired(&mut self, now: u64) -> Vec<ConditionalOrderState> {
+        self.take_where(|o| o.order.expires_at.is_some_and(|t| t <= now))
+    }
+
+    // Removes the orders that `trade` triggers, oldest first.
+    pub fn take_triggered(&mut self, trade: &TradeState) -> Vec<ConditionalOrderState> {
+        self.take_where(|o| o.is_triggered_by(trade))
+    }
+
+    #[cfg(feature = "test")]
+    pub fn clear(&mut self) {
+        self.waiting.clear();
+    }
+
+    fn take_where(
+        &mut self,
+        f: impl Fn(&ConditionalOrderState) -> bool,
+    ) -> Vec<ConditionalOrderState> {
+        let ids: Vec<OrderId> = self
+            .waiting
+            .values()
+            .filter(|o| f(o))
+            .map(|o| o.order.id)
+            .collect();
+        ids.iter()
+            .filter_map(|id| self.waiting.remove(id))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+
+    const TOKEN_A: u8 = 2;
+    const TOKEN_B: u8 = 3;
+    const TOKEN_C: u8 = 4;
+
+    fn conditional(id: OrderId, condition: TriggerCondition, price: f64) -> ConditionalOrderState {
+        ConditionalOrderState {
+            order: OrderState {
+                id,
+                owner: principal(1),
+                from_token_canister_id: principal(TOKEN_A),
+                from_amount: 100,
+                to_token_canister_id: principal(TOKEN_B),
+                to_amount: 0,
+                expires_at: Some(10),
+            },
+            order_type: OrderType::Market,
+            trigger: Trigger { condition, price },
+        }
+    }
+
+    // A trade of `a_amount` of A against `other_amount` of `other`.
+    fn trade(a_amount: u128, other: u8, other_amount: u128) -> TradeState {
+        TradeState {
+            id: 0,
+            timestamp: 0,
+            taker_order_id: 10,
+            taker: principal(1),
+            taker_token_canister_id: principal(other),
+            taker_amount: other_amount,
+            taker_fee: 0,
+            maker_order_id: 11,
+            maker: principal(1),
+            maker_token_canister_id: principal(TOKEN_A),
+            maker_amount: a_amount,
+            maker_fee: 0,
+        }
+    }
+
+    fn ids(orders: Vec<ConditionalOrderState>) -> Vec<OrderId> {
+        orders.iter().map(|o| o.order.id).collect()
+    }
+
+    #[test]
+    fn orders_trigger_when_the_price_crosses() {
+        let mut orders = ConditionalOrders::default();
+        orders.insert(conditional(1, TriggerCondition::StopLoss, 2.0));
+        orders.insert(conditional(2, TriggerCondition::TakeProfit, 3.0));
+        orders.insert(conditional(3, TriggerCondition::StopLoss, 1.0));
+
+        // A trades at 2.5 B, which crosses neither trigger, and other pairs don't count.
+        assert!(ids(orders.take_triggered(&trade(10, TOKEN_B, 25))).is_empty());
+        assert!(ids(orders.take_triggered(&trade(10, TOKEN_C, 5))).is_empty());
+
+        assert_eq!(ids(orders.take_triggered(&trade(10, TOKEN_B, 20))), vec![1]);
+        assert_eq!(ids(orders.take_triggered(&trade(10, TOKEN_B, 30))), vec![2]);
+        assert!(orders.get(&1).is_none() && orders.get(&2).is_none());
+
+        assert!(orders.take_expired(9).is_empty());
+        assert_eq!(ids(orders.take_expired(10)), vec![3]);
+    }
+}
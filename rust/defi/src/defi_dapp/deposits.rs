@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::types::*;
+
+// Upper bound on the deposit accounts of a principal, which also bounds the
+// work of sweeping them.
+pub const MAX_DEPOSIT_ACCOUNTS: usize = 8;
+const MAX_LABEL_LENGTH: usize = 32;
+
+// The deposit accounts that principals opened, each with a label such as
+// "trading" or "savings". All of them are credited to the same balance. They are
+// swept in turns, a batch at a time.
+#[derive(CandidType, Clone, Deserialize, Serialize, Default)]
+pub struct DepositAccounts {
+    labels: BTreeMap<(Principal, DepositAccountIndex), String>,
+    // The account the next sweep starts with.
+    next_sweep: Option<(Principal, DepositAccountIndex)>,
+    pub last_sweep: u64,
+}
+
+impl DepositAccounts {
+    // Opens the lowest unused index of `owner`.
+    pub fn open(
+        &mut self,
+        owner: Principal,
+        label: String,
+    ) -> Result<DepositAccountIndex, DepositAccountErr> {
+        if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+            return Err(DepositAccountErr::InvalidLabel);
+        }
+        let opened = self.of(&owner);
+        if opened.len() >= MAX_DEPOSIT_ACCOUNTS {
+            return Err(DepositAccountErr::TooManyAccounts);
+        }
+        let index = (0..).find(|i| !opened.iter().any(|(j, _)| j == i)).unwrap();
+        self.labels.insert((owner, index), label);
+        Ok(index)
+    }
+
+    pub fn of(&self, owner: &Principal) -> Vec<(DepositAccountIndex, String)> {
+        self.labels
+            .range((*owner, 0)..=(*owner, DepositAccountIndex::MAX))
+            .map(|((_, index), label)| (*index, label.clone()))
+            .collect()
+    }
+
+    // The indices of `owner` that tokens may have been sent to. Index 0 is the
+    // account of `getDepositAccount`, which can be used without opening it.
+    pub fn indices_of(&self, owner: &Principal) -> Vec<DepositAccountIndex> {
+        let mut indices: Vec<DepositAccountIndex> =
+            self.of(owner).into_iter().map(|(i, _)| i).collect();
+        if indices.first() != Some(&0) {
+            indices.insert(0, 0);
+        }
+        indices
+    }
+
+    // The next `limit` accounts to sweep, starting over after the last one.
+    pub fn next_batch(&mut self, limit: usize) -> Vec<(Principal, DepositAccountIndex)> {
+        let start = self
+            .next_sweep
+            .unwrap_or((Principal::management_canister(), 0));
+        let batch: Vec<(Principal, DepositAccountIndex)> = self
+            .labels
+            .range(start..)
+            .chain(self.labels.range(..start))
+            .map(|(k, _)| *k)
+            .take(limit)
+            .collect();
+        let next = batch
+            .last()
+            .and_then(|last| self.labels.range(*last..).nth(1).map(|(k, _)| *k));
+        self.next_sweep = next;
+        batch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+
+    #[test]
+    fn accounts_take_the_lowest_unused_index() {
+        let mut accounts = DepositAccounts::default();
+        assert_eq!(accounts.indices_of(&principal(1)), vec![0]);
+        assert_eq!(
+            accounts.open(principal(1), "trading".to_string()).ok(),
+            Some(0)
+        );
+        assert_eq!(
+            accounts.open(principal(1), "savings".to_string()).ok(),
+            Some(1)
+        );
+        assert_eq!(
+            accounts.open(principal(2), "trading".to_string()).ok(),
+            Some(0)
+        );
+        assert!(matches!(
+            accounts.open(principal(1), String::new()),
+            Err(DepositAccountErr::InvalidLabel)
+        ));
+        for _ in 2..MAX_DEPOSIT_ACCOUNTS {
+            accounts.open(principal(1), "more".to_string()).unwrap();
+        }
+        assert!(matches!(
+            accounts.open(principal(1), "full".to_string()),
+            Err(DepositAccountErr::TooManyAccounts)
+        ));
+        assert_eq!(accounts.of(&principal(1))[1].1, "savings");
+        assert_eq!(accounts.indices_of(&principal(2)), vec![0]);
+    }
+
+    #[test]
+    fn sweeps_take_turns() {
+        let mut accounts = DepositAccounts::default();
+        assert!(accounts.next_batch(2).is_empty());
+        accounts.open(principal(1), "a".to_string()).unwrap();
+        accounts.open(principal(1), "b".to_string()).unwrap();
+        accounts.open(principal(2), "c".to_string()).unwrap();
+
+        assert_eq!(
+            accounts.next_batch(2),
+            vec![(principal(1), 0), (principal(1), 1)]
+        );
+        assert_eq!(
+            accounts.next_batch(2),
+            vec![(principal(2), 0), (principal(1), 0)]
+        );
+        assert_eq!(accounts.next_batch(5).len(), 3);
+    }
+}
@@ -1,4 +1,10 @@
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
 use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+
+use crate::journal::TransferOutcome;
+use crate::types::{DepositErr, WithdrawErr};
+use crate::utils;
 
 pub struct DIP20 {
     principal: Principal,
@@ -30,41 +36,225 @@ pub struct Metadata {
     pub fee: Nat,
 }
 
+#[derive(Debug)]
+pub enum DIP20Error {
+    // The token canister could not be called, or its reply could not be decoded.
+    Call(RejectionCode, String),
+    // The token canister refused the transaction.
+    Tx(TxError),
+}
+pub type DIP20Result<T> = Result<T, DIP20Error>;
+
+impl DIP20Error {
+    // DIP20 tokens can't deduplicate transfers, so a transfer that is sent again
+    // may be executed twice.
+    pub fn outcome(&self) -> TransferOutcome {
+        match self {
+            DIP20Error::Call(RejectionCode::Unknown, _) => TransferOutcome::Unknown,
+            _ => TransferOutcome::Failed,
+        }
+    }
+}
+
+impl From<DIP20Error> for DepositErr {
+    fn from(e: DIP20Error) -> DepositErr {
+        match e {
+            DIP20Error::Call(..) => DepositErr::CallFailure,
+            DIP20Error::Tx(TxError::InsufficientBalance)
+            | DIP20Error::Tx(TxError::InsufficientAllowance) => DepositErr::BalanceLow,
+            DIP20Error::Tx(_) => DepositErr::TransferFailure,
+        }
+    }
+}
+
+impl From<DIP20Error> for WithdrawErr {
+    fn from(e: DIP20Error) -> WithdrawErr {
+        match e {
+            DIP20Error::Call(..) => WithdrawErr::CallFailure,
+            DIP20Error::Tx(_) => WithdrawErr::TransferFailure,
+        }
+    }
+}
+
+// The DIP20 methods used by the exchange, so that tests can swap in a mock token.
+pub trait DIP20Client {
+    async fn transfer(&self, target: Principal, amount: Nat) -> DIP20Result<Nat>;
+    async fn transfer_from(
+        &self,
+        source: Principal,
+        target: Principal,
+        amount: Nat,
+    ) -> DIP20Result<Nat>;
+    async fn allowance(&self, owner: Principal, spender: Principal) -> DIP20Result<Nat>;
+    async fn get_metadata(&self) -> DIP20Result<Metadata>;
+}
+
 impl DIP20 {
     pub fn new(principal: Principal) -> Self {
         DIP20 { principal }
     }
 
-    pub async fn transfer(&self, target: Principal, amount: Nat) -> TxReceipt {
-        let call_result: Result<(TxReceipt,), _> =
-            ic_cdk::api::call::call(self.principal, "transfer", (target, amount)).await;
+    async fn call<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+        &self,
+        method: &str,
+        args: T,
+    ) -> DIP20Result<R> {
+        utils::call(self.principal, method, args)
+            .await
+            .map_err(|(code, msg)| DIP20Error::Call(code, msg))
+    }
+}
 
-        call_result.unwrap().0
+impl DIP20Client for DIP20 {
+    async fn transfer(&self, target: Principal, amount: Nat) -> DIP20Result<Nat> {
+        let (receipt,): (TxReceipt,) = self.call("transfer", (target, amount)).await?;
+
+        receipt.map_err(DIP20Error::Tx)
     }
 
-    pub async fn transfer_from(
+    async fn transfer_from(
         &self,
         source: Principal,
         target: Principal,
         amount: Nat,
-    ) -> TxReceipt {
-        let call_result: Result<(TxReceipt,), _> =
-            ic_cdk::api::call::call(self.principal, "transferFrom", (source, target, amount)).await;
+    ) -> DIP20Result<Nat> {
+        let (receipt,): (TxReceipt,) = self.call("transferFrom", (source, target, amount)).await?;
+
+        receipt.map_err(DIP20Error::Tx)
+    }
 
-        call_result.unwrap().0
+    async fn allowance(&self, owner: Principal, spender: Principal) -> DIP20Result<Nat> {
+        let (allowance,): (Nat,) = self.call("allowance", (owner, spender)).await?;
+
+        Ok(allowance)
     }
 
-    pub async fn allowance(&self, owner: Principal, spender: Principal) -> Nat {
-        let call_result: Result<(Nat,), _> =
-            ic_cdk::api::call::call(self.principal, "allowance", (owner, spender)).await;
+    async fn get_metadata(&self) -> DIP20Result<Metadata> {
+        let (metadata,): (Metadata,) = self.call("getMetadata", ()).await?;
 
-        call_result.unwrap().0
+        Ok(metadata)
     }
+}
 
-    pub async fn get_metadata(&self) -> Metadata {
-        let call_result: Result<(Metadata,), _> =
-            ic_cdk::api::call::call(self.principal, "getMetadata", ()).await;
+// An in-memory DIP20 token. Every call can be made to be rejected, like a token
+// canister that is stopped or traps.
+#[cfg(test)]
+pub struct MockDIP20 {
+    pub fee: Nat,
+    pub balances: std::cell::RefCell<std::collections::HashMap<Principal, Nat>>,
+    pub allowances: std::cell::RefCell<std::collections::HashMap<(Principal, Principal), Nat>>,
+    pub reject_calls: std::cell::Cell<bool>,
+    // The principal that transfers are made from, i.e. the exchange canister.
+    pub caller: Principal,
+}
+
+#[cfg(test)]
+impl MockDIP20 {
+    pub fn new(caller: Principal, fee: u128) -> Self {
+        MockDIP20 {
+            fee: fee.into(),
+            balances: Default::default(),
+            allowances: Default::default(),
+            reject_calls: std::cell::Cell::new(false),
+            caller,
+        }
+    }
+
+    pub fn balance_of(&self, owner: &Principal) -> Nat {
+        self.balances
+            .borrow()
+            .get(owner)
+            .cloned()
+            .unwrap_or_else(|| 0u32.into())
+    }
+
+    pub fn approve(&self, owner: Principal, spender: Principal, amount: u128) {
+        self.allowances
+            .borrow_mut()
+            .insert((owner, spender), amount.into());
+    }
+
+    pub fn mint(&self, owner: Principal, amount: u128) {
+        let balance = self.balance_of(&owner) + Nat::from(amount);
+        self.balances.borrow_mut().insert(owner, balance);
+    }
+
+    fn check_rejected(&self) -> DIP20Result<()> {
+        if self.reject_calls.get() {
+            Err(DIP20Error::Call(
+                RejectionCode::CanisterError,
+                "canister trapped".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Moves `amount` and burns the fee on top of it, like the DIP20 reference token.
+    fn move_tokens(&self, source: Principal, target: Principal, amount: Nat) -> DIP20Result<Nat> {
+        let total = amount.clone() + self.fee.clone();
+        let balance = self.balance_of(&source);
+        if balance < total {
+            return Err(DIP20Error::Tx(TxError::InsufficientBalance));
+        }
+        self.balances.borrow_mut().insert(source, balance - total);
+        self.mint(target, nat_u128(&amount));
+        Ok(0u32.into())
+    }
+}
+
+#[cfg(test)]
+fn nat_u128(n: &Nat) -> u128 {
+    crate::utils::nat_to_u128(n.clone())
+}
+
+#[cfg(test)]
+impl DIP20Client for MockDIP20 {
+    async fn transfer(&self, target: Principal, amount: Nat) -> DIP20Result<Nat> {
+        self.check_rejected()?;
+        self.move_tokens(self.caller, target, amount)
+    }
+
+    async fn transfer_from(
+        &self,
+        source: Principal,
+        target: Principal,
+        amount: Nat,
+    ) -> DIP20Result<Nat> {
+        self.check_rejected()?;
+        let allowance = self.allowance(source, self.caller).await?;
+        if allowance < amount.clone() + self.fee.clone() {
+            return Err(DIP20Error::Tx(TxError::InsufficientAllowance));
+        }
+        self.move_tokens(source, target, amount.clone())?;
+        self.approve(
+            source,
+            self.caller,
+            nat_u128(&allowance) - nat_u128(&amount) - nat_u128(&self.fee),
+        );
+        Ok(0u32.into())
+    }
+
+    async fn allowance(&self, owner: Principal, spender: Principal) -> DIP20Result<Nat> {
+        self.check_rejected()?;
+        Ok(self
+            .allowances
+            .borrow()
+            .get(&(owner, spender))
+            .cloned()
+            .unwrap_or_else(|| 0u32.into()))
+    }
 
-        call_result.unwrap().0
+    async fn get_metadata(&self) -> DIP20Result<Metadata> {
+        self.check_rejected()?;
+        Ok(Metadata {
+            logo: String::new(),
+            name: "Mock".to_string(),
+            symbol: "MOCK".to_string(),
+            decimals: 8,
+            totalSupply: 0u32.into(),
+            owner: self.caller,
+            fee: self.fee.clone(),
+        })
     }
 }
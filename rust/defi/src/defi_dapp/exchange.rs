@@ -1,13 +1,25 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::convert::TryInto;
 
-use candid::{CandidType, Nat, Principal};
-use ic_cdk::caller;
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 
+use ic_stable_structures::memory_manager::MemoryManager;
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+
+use crate::auction::{clearing_price, BatchAuctions, ClearingState, Price};
+use crate::conditional::{ConditionalOrderState, ConditionalOrders};
+use crate::env::{CanisterEnvironment, Environment};
+use crate::pool::{PoolChangeKind, PoolLog, PoolState};
+use crate::stable::{
+    key_principal, principal_key, Memory, PrincipalKey, BALANCES, ORDERS, ORDERS_BY_OWNER,
+    ORDERS_BY_PAIR, RESERVED,
+};
+use crate::trades::{SavedTradeLog, TradeLog, TradeState};
 use crate::types::*;
-use crate::utils::nat_to_u128;
+use crate::utils::{nat_to_u128, pair_key};
 use crate::OrderId;
 
 #[derive(CandidType, Clone, Deserialize, Serialize, Copy)]
@@ -18,6 +30,30 @@ pub struct OrderState {
     pub from_amount: u128,
     pub to_token_canister_id: Principal,
     pub to_amount: u128,
+    pub expires_at: Option<u64>,
+}
+
+impl OrderState {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    fn is_filled(&self) -> bool {
+        self.from_amount == 0
+    }
+
+    // Takes a fill off the order. The remainder keeps the order's price, so any
+    // price improvement of the fill goes to the owner.
+    fn fill(&mut self, from_amount: u128) {
+        let remaining = self.from_amount - from_amount;
+        let to_amount = BigUint::from(remaining) * BigUint::from(self.to_amount);
+        let from = BigUint::from(self.from_amount);
+        // Rounded up, so the remainder never asks for a worse price.
+        self.to_amount = ((to_amount + &from - BigUint::from(1u32)) / from)
+            .try_into()
+            .unwrap();
+        self.from_amount = remaining;
+    }
 }
 
 impl From<OrderState> for Order {
@@ -29,30 +65,142 @@ impl From<OrderState> for Order {
             fromAmount: o.from_amount.into(),
             to: o.to_token_canister_id,
             toAmount: o.to_amount.into(),
+            expiresAt: o.expires_at,
         }
     }
 }
 
-#[derive(CandidType, Clone, Deserialize, Serialize, Default)]
-pub struct BalancesState(pub HashMap<Principal, HashMap<Principal, u128>>); // owner -> token_canister_id -> amount
-type OrdersState = HashMap<OrderId, OrderState>;
+// Orders are encoded with candid, so that fields can be added to them. This
+// bounds their encoding with room to spare.
+const MAX_ORDER_SIZE: u32 = 512;
+
+impl Storable for OrderState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), OrderState).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderState {
+    const MAX_SIZE: u32 = MAX_ORDER_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// owner -> token_canister_id -> amount, kept in stable memory.
+pub struct BalancesState(StableBTreeMap<(PrincipalKey, PrincipalKey), u128, Memory>);
 
-#[derive(CandidType, Clone, Deserialize, Serialize, Default)]
+pub struct OrdersState {
+    orders: StableBTreeMap<OrderId, OrderState, Memory>,
+    // Counts the orders on the book, and the conditional orders that are
+    // added to it.
+    pub counts: OrderCounts,
+}
+
+// The number of open orders of each owner and in each pair, kept up to date as
+// orders come and go so that order limits don't go through all orders.
+pub struct OrderCounts {
+    by_owner: StableBTreeMap<PrincipalKey, u32, Memory>,
+    by_pair: StableBTreeMap<(PrincipalKey, PrincipalKey), u32, Memory>,
+}
+
+// Balances, orders, trades and pool changes are kept in stable memory, so
+// that upgrades don't have to serialize them. Everything else is saved on
+// upgrades as `SavedExchange`.
 pub struct Exchange {
     pub next_id: OrderId,
+    // Free balances, which can be withdrawn or put into orders and pools.
     pub balances: BalancesState,
+    // What open orders still offer, taken out of the free balances when they are placed.
+    pub reserved: BalancesState,
     pub orders: OrdersState,
+    pub trades: TradeLog,
+    pub pool_changes: PoolLog,
+    pub pools: HashMap<(Principal, Principal), PoolState>,
+    pub fees: TradingFees,
+    pub limits: OrderLimits,
+    // The smallest amount of each token that an order may offer.
+    pub min_order_sizes: HashMap<Principal, u128>,
+    pub conditional: ConditionalOrders,
+    pub auctions: BatchAuctions,
+    // Conditional orders that were triggered, to be placed.
+    triggered: VecDeque<ConditionalOrderState>,
+    pub env: Box<dyn Environment>,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct SavedExchange {
+    pub next_id: OrderId,
+    pub trades: SavedTradeLog,
+    pub pools: HashMap<(Principal, Principal), PoolState>,
+    pub fees: TradingFees,
+    pub limits: OrderLimits,
+    pub min_order_sizes: HashMap<Principal, u128>,
+    pub conditional: ConditionalOrders,
+    pub auctions: BatchAuctions,
+}
+
+// Upper bound on the number of price levels returned per side of the book.
+const MAX_DEPTH: usize = 100;
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// The most legs a swap may be routed through.
+const MAX_SWAP_HOPS: usize = 3;
+
+// The shortest epoch of batch auctions, so that clearing doesn't run on every heartbeat.
+const MIN_AUCTION_EPOCH: u64 = 10_000_000_000;
+
+// The order limits until the owner sets others.
+const DEFAULT_MAX_ORDERS_PER_USER: u32 = 100;
+const DEFAULT_MAX_ORDERS_PER_PAIR: u32 = 10_000;
+
+impl Default for OrderLimits {
+    fn default() -> Self {
+        OrderLimits {
+            maxOrdersPerUser: DEFAULT_MAX_ORDERS_PER_USER,
+            maxOrdersPerPair: DEFAULT_MAX_ORDERS_PER_PAIR,
+        }
+    }
+}
+
+// Upper bound for the maker and taker fees, in basis points.
+const MAX_FEE_BPS: u32 = 1_000;
+const BPS: u128 = 10_000;
+
+impl TradingFees {
+    fn is_valid(&self) -> bool {
+        self.makerFeeBps <= MAX_FEE_BPS && self.takerFeeBps <= MAX_FEE_BPS
+    }
+
+    fn maker_fee(&self, amount: u128) -> u128 {
+        amount * self.makerFeeBps as u128 / BPS
+    }
+
+    fn taker_fee(&self, amount: u128) -> u128 {
+        amount * self.takerFeeBps as u128 / BPS
+    }
 }
 
 impl BalancesState {
-    pub fn add_balance(&mut self, owner: &Principal, token_canister_id: &Principal, delta: u128) {
-        let balances = self.0.entry(*owner).or_insert_with(HashMap::new);
+    pub fn init(memory: Memory) -> Self {
+        BalancesState(StableBTreeMap::init(memory))
+    }
 
-        if let Some(x) = balances.get_mut(token_canister_id) {
-            *x += delta;
-        } else {
-            balances.insert(*token_canister_id, delta);
+    pub fn balance_of(&self, owner: &Principal, token_canister_id: &Principal) -> u128 {
+        self.0
+            .get(&(principal_key(owner), principal_key(token_canister_id)))
+            .unwrap_or(0)
+    }
+
+    pub fn add_balance(&mut self, owner: &Principal, token_canister_id: &Principal, delta: u128) {
+        if delta == 0 {
+            return;
         }
+        let key = (principal_key(owner), principal_key(token_canister_id));
+        let balance = self.0.get(&key).unwrap_or(0);
+        self.0.insert(key, balance + delta);
     }
 
     // Tries to substract balance from user account. Checks for overflows
@@ -62,70 +210,247 @@ impl BalancesState {
         token_canister_id: &Principal,
         delta: u128,
     ) -> bool {
-        if let Some(balances) = self.0.get_mut(owner) {
-            if let Some(x) = balances.get_mut(token_canister_id) {
-                match (*x).checked_sub(delta) {
-                    Some(num) => {
-                        *x = num;
-                    }
-                    None => return false,
-                }
-                // no need to keep an empty token record
-                if *x == 0 {
-                    balances.remove(token_canister_id);
-                }
-                return true;
-            }
+        let key = (principal_key(owner), principal_key(token_canister_id));
+        let balance = match self.0.get(&key).and_then(|x| x.checked_sub(delta)) {
+            Some(balance) => balance,
+            None => return false,
+        };
+        // no need to keep an empty token record
+        if balance == 0 {
+            self.0.remove(&key);
+        } else {
+            self.0.insert(key, balance);
+        }
+
+        true
+    }
+
+    // The tokens `owner` has a balance of, with the amounts.
+    pub fn balances_of(&self, owner: &Principal) -> Vec<(Principal, u128)> {
+        let owner = principal_key(owner);
+        self.0
+            .range((owner, PrincipalKey::default())..)
+            .take_while(|((o, _), _)| *o == owner)
+            .map(|((_, token), amount)| (key_principal(&token), amount))
+            .collect()
+    }
+
+    pub fn owners(&self) -> BTreeSet<Principal> {
+        self.0
+            .iter()
+            .map(|((owner, _), _)| key_principal(&owner))
+            .collect()
+    }
+
+    #[cfg(feature = "test")]
+    pub fn clear(&mut self) {
+        let keys: Vec<_> = self.0.iter().map(|(k, _)| k).collect();
+        for key in keys {
+            self.0.remove(&key);
+        }
+    }
+
+    // Moves balance of a user to another set of balances, if there is enough of it.
+    pub fn move_balance(
+        &mut self,
+        to: &mut BalancesState,
+        owner: &Principal,
+        token_canister_id: &Principal,
+        delta: u128,
+    ) -> bool {
+        if !self.subtract_balance(owner, token_canister_id, delta) {
+            return false;
+        }
+        to.add_balance(owner, token_canister_id, delta);
+        true
+    }
+}
+
+impl OrdersState {
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>) -> Self {
+        OrdersState {
+            orders: StableBTreeMap::init(memory_manager.get(ORDERS)),
+            counts: OrderCounts {
+                by_owner: StableBTreeMap::init(memory_manager.get(ORDERS_BY_OWNER)),
+                by_pair: StableBTreeMap::init(memory_manager.get(ORDERS_BY_PAIR)),
+            },
+        }
+    }
+
+    pub fn get(&self, id: &OrderId) -> Option<OrderState> {
+        self.orders.get(id)
+    }
+
+    pub fn insert(&mut self, id: OrderId, order: OrderState) {
+        if let Some(replaced) = self.orders.insert(id, order) {
+            self.counts.remove(&replaced);
+        }
+        self.counts.add(&order);
+    }
+
+    pub fn remove(&mut self, id: &OrderId) -> Option<OrderState> {
+        let order = self.orders.remove(id)?;
+        self.counts.remove(&order);
+        Some(order)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = OrderState> + '_ {
+        self.orders.iter().map(|(_, o)| o)
+    }
+
+    #[cfg(feature = "test")]
+    pub fn clear(&mut self) {
+        let ids: Vec<OrderId> = self.orders.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            self.remove(&id);
         }
+    }
+}
+
+fn count_pair_key(order: &OrderState) -> (PrincipalKey, PrincipalKey) {
+    let (a, b) = pair_key(order.from_token_canister_id, order.to_token_canister_id);
+    (principal_key(&a), principal_key(&b))
+}
+
+impl OrderCounts {
+    // The open orders of `owner`, and those in the pair of two tokens.
+    pub fn of(&self, owner: &Principal, token_a: Principal, token_b: Principal) -> (u32, u32) {
+        let (a, b) = pair_key(token_a, token_b);
+        (
+            self.by_owner.get(&principal_key(owner)).unwrap_or(0),
+            self.by_pair
+                .get(&(principal_key(&a), principal_key(&b)))
+                .unwrap_or(0),
+        )
+    }
+
+    pub fn add(&mut self, order: &OrderState) {
+        let owner = principal_key(&order.owner);
+        let count = self.by_owner.get(&owner).unwrap_or(0);
+        self.by_owner.insert(owner, count + 1);
+        let pair = count_pair_key(order);
+        let count = self.by_pair.get(&pair).unwrap_or(0);
+        self.by_pair.insert(pair, count + 1);
+    }
+
+    pub fn remove(&mut self, order: &OrderState) {
+        let owner = principal_key(&order.owner);
+        match self.by_owner.get(&owner).unwrap_or(0) {
+            0 | 1 => self.by_owner.remove(&owner),
+            count => self.by_owner.insert(owner, count - 1),
+        };
+        let pair = count_pair_key(order);
+        match self.by_pair.get(&pair).unwrap_or(0) {
+            0 | 1 => self.by_pair.remove(&pair),
+            count => self.by_pair.insert(pair, count - 1),
+        };
+    }
 
-        false
+    fn is_empty(&self) -> bool {
+        self.by_owner.is_empty()
     }
 }
 
 impl Exchange {
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>, saved: SavedExchange) -> Self {
+        let mut exchange = Exchange {
+            next_id: saved.next_id,
+            balances: BalancesState::init(memory_manager.get(BALANCES)),
+            reserved: BalancesState::init(memory_manager.get(RESERVED)),
+            orders: OrdersState::init(memory_manager),
+            trades: TradeLog::init(memory_manager, saved.trades),
+            pool_changes: PoolLog::init(memory_manager),
+            pools: saved.pools,
+            fees: saved.fees,
+            limits: saved.limits,
+            min_order_sizes: saved.min_order_sizes,
+            conditional: saved.conditional,
+            auctions: saved.auctions,
+            triggered: VecDeque::new(),
+            env: Box::new(CanisterEnvironment {}),
+        };
+        // Earlier versions didn't count orders.
+        if exchange.orders.counts.is_empty() {
+            let open: Vec<OrderState> = exchange
+                .orders
+                .values()
+                .chain(exchange.conditional.values().map(|o| o.order))
+                .collect();
+            for o in open {
+                exchange.orders.counts.add(&o);
+            }
+        }
+        exchange
+    }
+
+    // Takes out what is saved on upgrades.
+    pub fn take_saved(&mut self) -> SavedExchange {
+        SavedExchange {
+            next_id: self.next_id,
+            trades: SavedTradeLog::default(),
+            pools: std::mem::take(&mut self.pools),
+            fees: self.fees,
+            limits: self.limits,
+            min_order_sizes: std::mem::take(&mut self.min_order_sizes),
+            conditional: std::mem::take(&mut self.conditional),
+            auctions: std::mem::take(&mut self.auctions),
+        }
+    }
+
     pub fn get_balance(&self, token_canister_id: Principal) -> Nat {
         self.balances
-            .0
-            .get(&caller())
-            .and_then(|v| v.get(&token_canister_id))
-            .map_or(0, |v| *v)
+            .balance_of(&self.env.caller(), &token_canister_id)
             .into()
     }
 
     pub fn get_balances(&self) -> Vec<Balance> {
-        match self.balances.0.get(&caller()) {
-            None => Vec::new(),
-            Some(v) => v
-                .iter()
-                .map(|(token_canister_id, amount)| Balance {
-                    owner: caller(),
-                    token: *token_canister_id,
-                    amount: (*amount).into(),
-                })
-                .collect(),
-        }
+        self.balances_of(&self.env.caller())
     }
 
     pub fn get_all_balances(&self) -> Vec<Balance> {
-        self.balances
-            .0
+        let mut owners = self.balances.owners();
+        owners.extend(self.reserved.owners());
+        owners
             .iter()
-            .flat_map(|(owner, balances)| {
-                balances.iter().map(move |(token, amount)| Balance {
-                    owner: *owner,
-                    token: *token,
-                    amount: (*amount).into(),
-                })
+            .flat_map(|owner| self.balances_of(owner))
+            .collect()
+    }
+
+    // The free and reserved balance of every token `owner` has either of.
+    fn balances_of(&self, owner: &Principal) -> Vec<Balance> {
+        let tokens: BTreeSet<Principal> = self
+            .balances
+            .balances_of(owner)
+            .into_iter()
+            .chain(self.reserved.balances_of(owner))
+            .map(|(token, _)| token)
+            .collect();
+        tokens
+            .iter()
+            .map(|token| Balance {
+                owner: *owner,
+                token: *token,
+                amount: self.balances.balance_of(owner, token).into(),
+                reserved: self.reserved.balance_of(owner, token).into(),
             })
             .collect()
     }
 
     pub fn get_order(&self, order: OrderId) -> Option<Order> {
-        self.orders.get(&order).map(|o| (*o).into())
+        let now = self.env.now();
+        self.orders
+            .get(&order)
+            .filter(|o| !o.is_expired(now))
+            .map(|o| o.into())
     }
 
     pub fn get_all_orders(&self) -> Vec<Order> {
-        self.orders.iter().map(|(_, o)| (*o).into()).collect()
+        let now = self.env.now();
+        self.orders
+            .values()
+            .filter(|o| !o.is_expired(now))
+            .map(|o| o.into())
+            .collect()
     }
 
     pub fn place_order(
@@ -134,39 +459,254 @@ impl Exchange {
         from_amount: Nat,
         to_token_canister_id: Principal,
         to_amount: Nat,
+        order_type: OrderType,
     ) -> OrderPlacementReceipt {
         ic_cdk::println!("place order");
-        let balance = self.get_balance(from_token_canister_id);
-        if balance < from_amount {
-            return OrderPlacementReceipt::Err(OrderPlacementErr::InvalidOrder);
+        let now = self.env.now();
+        self.remove_expired_orders(now);
+
+        let resting = matches!(order_type, OrderType::Limit | OrderType::GoodTillTime(_));
+        self.check_order_limits(
+            self.env.caller(),
+            from_token_canister_id,
+            &from_amount,
+            to_token_canister_id,
+            resting,
+        )?;
+        let order = self.new_order(
+            self.env.caller(),
+            from_token_canister_id,
+            from_amount,
+            to_token_canister_id,
+            to_amount,
+            order_type,
+            now,
+        )?;
+        let placement = self.execute_order(order, order_type, now);
+        self.place_triggered_orders(now);
+
+        placement
+    }
+
+    // Rejects orders below the minimum size of their token, and orders that would
+    // wait on the book, or as conditional orders, beyond the limits.
+    fn check_order_limits(
+        &self,
+        owner: Principal,
+        from_token_canister_id: Principal,
+        from_amount: &Nat,
+        to_token_canister_id: Principal,
+        waits: bool,
+    ) -> Result<(), OrderPlacementErr> {
+        let min_order_size = self.min_order_size(from_token_canister_id);
+        if nat_to_u128(from_amount.clone()) < min_order_size {
+            return Err(OrderPlacementErr::OrderTooSmall);
         }
-        let id = self.next_id();
+        if !waits {
+            return Ok(());
+        }
+
+        let (of_owner, in_pair) =
+            self.orders
+                .counts
+                .of(&owner, from_token_canister_id, to_token_canister_id);
+        let full = |count: u32, max: u32| max != 0 && count >= max;
+        if full(of_owner, self.limits.maxOrdersPerUser)
+            || full(in_pair, self.limits.maxOrdersPerPair)
+        {
+            return Err(OrderPlacementErr::OrderBookFull);
+        }
+        Ok(())
+    }
+
+    // Validates an order and assigns its id.
+    #[allow(clippy::too_many_arguments)]
+    fn new_order(
+        &mut self,
+        owner: Principal,
+        from_token_canister_id: Principal,
+        from_amount: Nat,
+        to_token_canister_id: Principal,
+        to_amount: Nat,
+        order_type: OrderType,
+        now: u64,
+    ) -> Result<OrderState, OrderPlacementErr> {
         let from_amount = nat_to_u128(from_amount);
         let to_amount = nat_to_u128(to_amount);
-        self.orders.insert(
-            id,
-            OrderState {
-                id,
-                owner: caller(),
-                from_token_canister_id,
-                from_amount,
-                to_token_canister_id,
-                to_amount,
-            },
-        );
-        self.resolve_order(id);
+        let market = order_type == OrderType::Market;
+        // Only market orders may leave the price open.
+        if from_amount == 0 || (to_amount == 0 && !market) {
+            return Err(OrderPlacementErr::InvalidOrder);
+        }
+        let expires_at = match order_type {
+            OrderType::GoodTillTime(t) if t <= now => return Err(OrderPlacementErr::InvalidOrder),
+            OrderType::GoodTillTime(t) => Some(t),
+            _ => None,
+        };
 
-        if let Some(o) = self.orders.get(&id).cloned() {
-            OrderPlacementReceipt::Ok(Some(o.into()))
+        Ok(OrderState {
+            id: self.next_id(),
+            owner,
+            from_token_canister_id,
+            from_amount,
+            to_token_canister_id,
+            to_amount,
+            expires_at,
+        })
+    }
+
+    // Matches the order against the book, and puts what is left of it on the
+    // book if its type lets it rest.
+    fn execute_order(
+        &mut self,
+        order: OrderState,
+        order_type: OrderType,
+        now: u64,
+    ) -> OrderPlacementReceipt {
+        let id = order.id;
+        let resting = matches!(order_type, OrderType::Limit | OrderType::GoodTillTime(_));
+        let batched = self
+            .auctions
+            .is_batched(order.from_token_canister_id, order.to_token_canister_id);
+        // Orders in batch auctions wait on the book for the clearing.
+        if order.is_expired(now) || (batched && !resting) {
+            return OrderPlacementReceipt::Err(OrderPlacementErr::InvalidOrder);
+        }
+        // The order pays its fills out of the reservation, so all open orders
+        // together never offer more than their owner has.
+        if !self.reserve(&order) {
+            return OrderPlacementReceipt::Err(OrderPlacementErr::InsufficientFreeBalance);
+        }
+        let (matches, remaining) = if batched {
+            (Vec::new(), order)
         } else {
-            OrderPlacementReceipt::Ok(None)
+            self.resolve_order(&order)
+        };
+        if order_type == OrderType::FillOrKill && !remaining.is_filled() {
+            ic_cdk::println!("kill order {}", id);
+            self.release(&order);
+            return OrderPlacementReceipt::Ok(OrderPlacement {
+                id,
+                status: OrderStatus::Cancelled,
+                fills: Vec::new(),
+                order: None,
+            });
+        }
+
+        self.orders.insert(id, order);
+        let fills = matches
+            .into_iter()
+            .map(|(b, a_to_amount, b_to_amount)| {
+                self.process_trade(id, b, a_to_amount, b_to_amount, now)
+            })
+            .collect();
+
+        let (status, order) = match self.orders.get(&id) {
+            None => (OrderStatus::Filled, None),
+            Some(o) if resting => (OrderStatus::Open, Some(o.into())),
+            Some(_) => {
+                // Whatever could not be filled immediately is cancelled.
+                if let Some(o) = self.orders.remove(&id) {
+                    self.release(&o);
+                }
+                (OrderStatus::Cancelled, None)
+            }
+        };
+
+        OrderPlacementReceipt::Ok(OrderPlacement {
+            id,
+            status,
+            fills,
+            order,
+        })
+    }
+
+    // Places conditional orders that trades triggered, and records what became
+    // of them. Their trades may trigger more, which are placed in turn.
+    fn place_triggered_orders(&mut self, now: u64) {
+        while let Some(triggered) = self.triggered.pop_front() {
+            ic_cdk::println!("trigger order {}", triggered.order.id);
+            self.release(&triggered.order);
+            self.orders.counts.remove(&triggered.order);
+            let status = match self.execute_order(triggered.order, triggered.order_type, now) {
+                OrderPlacementReceipt::Ok(placement) => placement.status,
+                OrderPlacementReceipt::Err(_) => OrderStatus::Rejected,
+            };
+            self.conditional.record_triggered(triggered, status, now);
+        }
+    }
+
+    pub fn place_conditional_order(
+        &mut self,
+        from_token_canister_id: Principal,
+        from_amount: Nat,
+        to_token_canister_id: Principal,
+        to_amount: Nat,
+        order_type: OrderType,
+        trigger: Trigger,
+    ) -> ConditionalOrderReceipt {
+        let now = self.env.now();
+        self.remove_expired_orders(now);
+
+        if !trigger.price.is_finite() || trigger.price <= 0.0 {
+            return Err(OrderPlacementErr::InvalidOrder);
+        }
+        self.check_order_limits(
+            self.env.caller(),
+            from_token_canister_id,
+            &from_amount,
+            to_token_canister_id,
+            true,
+        )?;
+        let order = self.new_order(
+            self.env.caller(),
+            from_token_canister_id,
+            from_amount,
+            to_token_canister_id,
+            to_amount,
+            order_type,
+            now,
+        )?;
+        if !self.reserve(&order) {
+            return Err(OrderPlacementErr::InsufficientFreeBalance);
+        }
+        let order = ConditionalOrderState {
+            order,
+            order_type,
+            trigger,
+        };
+        self.conditional.insert(order);
+        self.orders.counts.add(&order.order);
+
+        Ok(order.into())
+    }
+
+    pub fn get_conditional_orders(&self) -> Vec<ConditionalOrder> {
+        self.conditional.of(&self.env.caller())
+    }
+
+    pub fn get_triggered_orders(&self) -> Vec<TriggeredOrder> {
+        self.conditional.triggered_of(&self.env.caller())
+    }
+
+    pub fn cancel_conditional_order(&mut self, order: OrderId) -> CancelOrderReceipt {
+        match self.conditional.get(&order) {
+            Some(o) if o.order.owner == self.env.caller() => {
+                let o = self.conditional.remove(&order).unwrap();
+                self.release(&o.order);
+                self.orders.counts.remove(&o.order);
+                CancelOrderReceipt::Ok(order)
+            }
+            Some(_) => CancelOrderReceipt::Err(CancelOrderErr::NotAllowed),
+            None => CancelOrderReceipt::Err(CancelOrderErr::NotExistingOrder),
         }
     }
 
     pub fn cancel_order(&mut self, order: OrderId) -> CancelOrderReceipt {
         if let Some(o) = self.orders.get(&order) {
-            if o.owner == caller() {
+            if o.owner == self.env.caller() {
                 self.orders.remove(&order);
+                self.release(&o);
                 CancelOrderReceipt::Ok(order)
             } else {
                 CancelOrderReceipt::Err(CancelOrderErr::NotAllowed)
@@ -176,150 +716,2095 @@ impl Exchange {
         }
     }
 
-    fn resolve_order(&mut self, id: OrderId) {
-        ic_cdk::println!("resolve order");
-        let mut matches = Vec::new();
-        {
-            let a = self.orders.get(&id).unwrap();
-            for (order, b) in self.orders.iter() {
-                if *order == id {
-                    continue;
-                }
+    // Removes the expired orders and releases what they reserved.
+    pub fn remove_expired_orders(&mut self, now: u64) {
+        let expired: Vec<OrderState> = self.orders.values().filter(|o| o.is_expired(now)).collect();
+        for o in expired {
+            self.orders.remove(&o.id);
+            self.release(&o);
+        }
+        for o in self.conditional.take_expired(now) {
+            self.release(&o.order);
+            self.orders.counts.remove(&o.order);
+        }
+    }
+
+    // Moves what an order offers from the free to the reserved balance of its owner.
+    // Returns false if the free balance is too low.
+    fn reserve(&mut self, order: &OrderState) -> bool {
+        self.balances.move_balance(
+            &mut self.reserved,
+            &order.owner,
+            &order.from_token_canister_id,
+            order.from_amount,
+        )
+    }
+
+    // Gives what is left of a removed order back to the free balance of its owner.
+    fn release(&mut self, order: &OrderState) {
+        self.reserved.move_balance(
+            &mut self.balances,
+            &order.owner,
+            &order.from_token_canister_id,
+            order.from_amount,
+        );
+    }
 
-                if a.from_token_canister_id == b.to_token_canister_id
+    // Plans the trades of an incoming order against the book without executing them.
+    // Counter orders are taken best price first, and oldest first on equal prices.
+    // Returns the planned matches and what would be left of the incoming order.
+    fn resolve_order(&self, order: &OrderState) -> (Vec<(OrderId, u128, u128)>, OrderState) {
+        ic_cdk::println!("resolve order");
+        let mut a = *order;
+        let mut candidates: Vec<OrderState> = self
+            .orders
+            .values()
+            .filter(|b| {
+                b.id != a.id
+                    && a.from_token_canister_id == b.to_token_canister_id
                     && a.to_token_canister_id == b.from_token_canister_id
-                {
                     // Simplified to use multiplication from
                     // (a.from_amount / a.to_amount) * (b.from_amount / b.to_amount) >= 1
                     // which checks that this pair of trades is profitable.
-                    if BigUint::from(a.from_amount) * BigUint::from(b.from_amount)
+                    && BigUint::from(a.from_amount) * BigUint::from(b.from_amount)
                         >= BigUint::from(a.to_amount) * BigUint::from(b.to_amount)
-                    {
-                        ic_cdk::println!(
-                            "match {}: {} -> {}, {}: {} -> {}",
-                            id,
-                            a.from_amount,
-                            a.to_amount,
-                            *order,
-                            b.from_amount,
-                            b.to_amount
-                        );
-                        matches.push((id, *order));
-                    }
-                }
-            }
-        }
-        for m in matches {
-            let mut a_to_amount: u128 = 0;
-            let mut b_to_amount: u128 = 0;
-            {
-                if let Some(a) = self.orders.get(&m.0) {
-                    if let Some(b) = self.orders.get(&m.1) {
-                        // Check if some orders can be completed in their entirety.
-                        if b.from_amount >= a.to_amount {
-                            a_to_amount = a.to_amount;
-                        }
-                        if a.from_amount >= b.to_amount {
-                            b_to_amount = b.to_amount;
-                        }
-                        // Check if some orders can be completed partially.
-                        if a_to_amount == 0 && b_to_amount > 0 {
-                            a_to_amount = b.from_amount;
-                            // Verify that we can complete the partial order with natural number tokens remaining.
-                            if ((BigUint::from(a_to_amount) * BigUint::from(a.from_amount))
-                                % BigUint::from(a.to_amount))
-                                != BigUint::from(0u32)
-                            {
-                                continue;
-                            }
-                        }
-                        if b_to_amount == 0 && a_to_amount > 0 {
-                            b_to_amount = a.from_amount;
-                            // Verify that we can complete the partial order with natural number tokens remaining.
-                            if ((BigUint::from(b_to_amount) * BigUint::from(b.from_amount))
-                                % BigUint::from(b.to_amount))
-                                != BigUint::from(0u32)
-                            {
-                                continue;
-                            }
-                        }
-                    }
-                }
+            })
+            .collect();
+        // The best counter order offers the most tokens per token asked.
+        candidates.sort_by(|x, y| {
+            (BigUint::from(y.from_amount) * BigUint::from(x.to_amount))
+                .cmp(&(BigUint::from(x.from_amount) * BigUint::from(y.to_amount)))
+                .then(x.id.cmp(&y.id))
+        });
+
+        let mut matches = Vec::new();
+        for b in candidates {
+            if a.is_filled() {
+                break;
             }
-            if a_to_amount > 0 && b_to_amount > 0 {
-                self.process_trade(m.0, m.1, a_to_amount, b_to_amount);
+            if let Some((a_to_amount, b_to_amount)) = fill_amounts(&a, &b) {
+                ic_cdk::println!(
+                    "match {}: {} -> {}, {}: {} -> {}",
+                    a.id,
+                    a.from_amount,
+                    a.to_amount,
+                    b.id,
+                    b.from_amount,
+                    b.to_amount
+                );
+                a.fill(b_to_amount);
+                matches.push((b.id, a_to_amount, b_to_amount));
             }
         }
+
+        (matches, a)
     }
 
-    fn process_trade(&mut self, a: OrderId, b: OrderId, a_to_amount: u128, b_to_amount: u128) {
+    // Executes a match at the price of the resting order `b`. Each party pays
+    // exactly what the other receives before fees, and the fees are taken from
+    // the received tokens into the fee account. When both sides paid their own
+    // price, the canister kept the difference between them; with that gone, the
+    // difference goes to the taker, as it always did for market orders.
+    fn process_trade(
+        &mut self,
+        a: OrderId,
+        b: OrderId,
+        a_to_amount: u128,
+        b_to_amount: u128,
+        now: u64,
+    ) -> Fill {
         ic_cdk::println!("process trade {} {} {} {}", a, b, a_to_amount, b_to_amount);
 
-        let Exchange {
-            orders, balances, ..
-        } = self;
-
-        let mut order_a = orders.remove(&a).unwrap();
-        let mut order_b = orders.remove(&b).unwrap();
-
-        // Calculate "cost" to each
-        let a_from_amount: u128 = ((BigUint::from(a_to_amount)
-            * BigUint::from(order_a.from_amount))
-            / BigUint::from(order_a.to_amount))
-        .try_into()
-        .unwrap();
+        let mut order_a = self.orders.remove(&a).unwrap();
+        let mut order_b = self.orders.remove(&b).unwrap();
 
-        let b_from_amount: u128 = ((BigUint::from(b_to_amount)
-            * BigUint::from(order_b.from_amount))
-            / BigUint::from(order_b.to_amount))
-        .try_into()
-        .unwrap();
+        let taker_fee = self.fees.taker_fee(a_to_amount);
+        let maker_fee = self.fees.maker_fee(b_to_amount);
 
         // Update order with remaining tokens
-        order_a.from_amount -= a_from_amount;
-        order_a.to_amount -= a_to_amount;
+        order_a.fill(b_to_amount);
 
-        order_b.from_amount -= b_from_amount;
+        order_b.from_amount -= a_to_amount;
         order_b.to_amount -= b_to_amount;
 
-        // Update DEX balances
-        balances.subtract_balance(
+        self.settle_trade(
+            &order_a,
+            &order_b,
+            a_to_amount,
+            b_to_amount,
+            taker_fee,
+            maker_fee,
+            now,
+        );
+
+        let fill = Fill {
+            orderId: order_b.id,
+            counterparty: order_b.owner,
+            fromAmount: b_to_amount.into(),
+            toAmount: (a_to_amount - taker_fee).into(),
+            fee: taker_fee.into(),
+        };
+
+        self.keep_order(order_a);
+        self.keep_order(order_b);
+
+        fill
+    }
+
+    // Moves the tokens of a trade between `a` and `b`, which were already
+    // updated with what is left of them, and records it. `a` is the taker.
+    #[allow(clippy::too_many_arguments)]
+    fn settle_trade(
+        &mut self,
+        order_a: &OrderState,
+        order_b: &OrderState,
+        a_to_amount: u128,
+        b_to_amount: u128,
+        taker_fee: u128,
+        maker_fee: u128,
+        now: u64,
+    ) {
+        let fee_account = self.env.canister_id();
+        let Exchange {
+            balances,
+            reserved,
+            trades,
+            conditional,
+            triggered,
+            ..
+        } = self;
+
+        // Update DEX balances. What the orders pay comes out of their reservations.
+        reserved.subtract_balance(&order_a.owner, &order_a.from_token_canister_id, b_to_amount);
+        balances.add_balance(
             &order_a.owner,
-            &order_a.from_token_canister_id,
-            a_from_amount,
+            &order_a.to_token_canister_id,
+            a_to_amount - taker_fee,
         );
-        balances.add_balance(&order_a.owner, &order_a.to_token_canister_id, a_to_amount);
 
-        balances.subtract_balance(
+        reserved.subtract_balance(&order_b.owner, &order_b.from_token_canister_id, a_to_amount);
+        balances.add_balance(
             &order_b.owner,
-            &order_b.from_token_canister_id,
-            b_from_amount,
+            &order_b.to_token_canister_id,
+            b_to_amount - maker_fee,
         );
-        balances.add_balance(&order_b.owner, &order_b.to_token_canister_id, b_to_amount);
 
-        // The DEX keeps any tokens not required to satisfy the parties.
-        let dex_amount_a = a_from_amount - b_to_amount;
-        if dex_amount_a > 0 {
-            balances.add_balance(&ic_cdk::id(), &order_a.from_token_canister_id, dex_amount_a);
+        // The canister's own account collects the fees.
+        if taker_fee > 0 {
+            balances.add_balance(&fee_account, &order_a.to_token_canister_id, taker_fee);
         }
+        if maker_fee > 0 {
+            balances.add_balance(&fee_account, &order_b.to_token_canister_id, maker_fee);
+        }
+
+        let trade = TradeState {
+            id: trades.next_id(),
+            timestamp: now,
+            taker_order_id: order_a.id,
+            taker: order_a.owner,
+            taker_token_canister_id: order_a.from_token_canister_id,
+            taker_amount: b_to_amount,
+            taker_fee,
+            maker_order_id: order_b.id,
+            maker: order_b.owner,
+            maker_token_canister_id: order_b.from_token_canister_id,
+            maker_amount: a_to_amount,
+            maker_fee,
+        };
+        trades.record(trade);
+        // Triggered orders are placed once the current order is done.
+        triggered.extend(conditional.take_triggered(&trade));
+    }
 
-        let dex_amount_b = b_from_amount - a_to_amount;
-        if dex_amount_b > 0 {
-            balances.add_balance(&ic_cdk::id(), &order_b.from_token_canister_id, dex_amount_b);
+    // Maintain the order only if not empty
+    fn keep_order(&mut self, order: OrderState) {
+        if order.from_amount != 0 {
+            self.orders.insert(order.id, order);
         }
+    }
 
-        // Maintain the order only if not empty
-        if order_a.from_amount != 0 {
-            orders.insert(order_a.id, order_a);
+    // Clears the batch auctions whose epoch is over. Returns whether any was due.
+    pub fn run_batch_auctions(&mut self, now: u64) -> bool {
+        let due = self.auctions.due(now);
+        if due.is_empty() {
+            return false;
         }
+        self.remove_expired_orders(now);
+        for (base, quote) in due {
+            let clearing = self.clear_batch_auction(base, quote, now);
+            self.auctions.cleared((base, quote), clearing);
+        }
+        self.place_triggered_orders(now);
+        true
+    }
 
-        if order_b.from_amount != 0 {
-            orders.insert(order_b.id, order_b);
+    pub fn set_batch_auction(
+        &mut self,
+        a: Principal,
+        b: Principal,
+        epoch: Option<u64>,
+        now: u64,
+    ) -> AdminReceipt {
+        match epoch {
+            Some(epoch) if epoch < MIN_AUCTION_EPOCH => Err(AdminErr::InvalidArgument),
+            Some(epoch) => {
+                self.auctions.enable(a, b, epoch, now);
+                Ok(())
+            }
+            None => {
+                if !self.auctions.disable(a, b) {
+                    return Err(AdminErr::InvalidArgument);
+                }
+                // Continuous matching expects a book that doesn't cross.
+                self.remove_expired_orders(now);
+                let (base, quote) = pair_key(a, b);
+                self.clear_batch_auction(base, quote, now);
+                self.place_triggered_orders(now);
+                Ok(())
+            }
         }
     }
 
-    fn next_id(&mut self) -> OrderId {
-        self.next_id += 1;
-        self.next_id
+    // Matches the crossing orders of a pair at a single price. Bids and asks are
+    // filled best price first, and oldest first on equal prices, and every fill
+    // is charged the maker fee.
+    fn clear_batch_auction(
+        &mut self,
+        base: Principal,
+        quote: Principal,
+        now: u64,
+    ) -> ClearingState {
+        let mut bids = Vec::new();
+        let mut asks = Vec::new();
+        for o in self.orders.values() {
+            if o.from_amount == 0 || o.to_amount == 0 {
+                continue;
+            }
+            if o.from_token_canister_id == quote && o.to_token_canister_id == base {
+                bids.push((
+                    o.id,
+                    Price {
+                        quote: o.from_amount,
+                        base: o.to_amount,
+                    },
+                    o.from_amount,
+                ));
+            } else if o.from_token_canister_id == base && o.to_token_canister_id == quote {
+                asks.push((
+                    o.id,
+                    Price {
+                        quote: o.to_amount,
+                        base: o.from_amount,
+                    },
+                    o.from_amount,
+                ));
+            }
+        }
+        let mut clearing = ClearingState {
+            timestamp: now,
+            price: None,
+            volume: 0,
+            quote_volume: 0,
+            trades: 0,
+        };
+        let price = match clearing_price(
+            &bids.iter().map(|o| (o.1, o.2)).collect::<Vec<_>>(),
+            &asks.iter().map(|o| (o.1, o.2)).collect::<Vec<_>>(),
+        ) {
+            Some(price) => price,
+            None => return clearing,
+        };
+        clearing.price = Some((price.quote, price.base));
+        bids.retain(|o| o.1 >= price);
+        asks.retain(|o| o.1 <= price);
+        bids.sort_by(|x, y| y.1.cmp(&x.1).then(x.0.cmp(&y.0)));
+        asks.sort_by(|x, y| x.1.cmp(&y.1).then(x.0.cmp(&y.0)));
+
+        let mut bids = bids.into_iter().peekable();
+        let mut asks = asks.into_iter().peekable();
+        while let (Some(bid), Some(ask)) = (bids.peek_mut(), asks.peek_mut()) {
+            let base_amount = ask.2.min(price.base_for(bid.2));
+            let quote_amount = price.quote_for(base_amount);
+            if quote_amount == 0 {
+                // What is left of one side is worth nothing at this price. If
+                // it is the ask, the bid may still buy from the next one.
+                if base_amount == ask.2 {
+                    asks.next();
+                } else {
+                    bids.next();
+                }
+                continue;
+            }
+            let (bid_id, ask_id) = (bid.0, ask.0);
+            bid.2 -= quote_amount;
+            ask.2 -= base_amount;
+            if ask.2 == 0 {
+                asks.next();
+            }
+            self.process_auction_trade(bid_id, ask_id, base_amount, quote_amount, now);
+            clearing.volume += base_amount;
+            clearing.quote_volume += quote_amount;
+            clearing.trades += 1;
+        }
+        clearing
+    }
+
+    // Executes a batch auction fill. Both orders keep their own price for what is
+    // left of them.
+    fn process_auction_trade(
+        &mut self,
+        bid: OrderId,
+        ask: OrderId,
+        base_amount: u128,
+        quote_amount: u128,
+        now: u64,
+    ) {
+        let mut bid = self.orders.remove(&bid).unwrap();
+        let mut ask = self.orders.remove(&ask).unwrap();
+
+        let bid_fee = self.fees.maker_fee(base_amount);
+        let ask_fee = self.fees.maker_fee(quote_amount);
+        bid.fill(quote_amount);
+        ask.fill(base_amount);
+
+        self.settle_trade(&bid, &ask, base_amount, quote_amount, bid_fee, ask_fee, now);
+        self.keep_order(bid);
+        self.keep_order(ask);
+    }
+
+    pub fn set_trading_fees(&mut self, fees: TradingFees) -> AdminReceipt {
+        if !fees.is_valid() {
+            return AdminReceipt::Err(AdminErr::InvalidArgument);
+        }
+        self.fees = fees;
+        AdminReceipt::Ok(())
+    }
+
+    pub fn min_order_size(&self, token_canister_id: Principal) -> u128 {
+        self.min_order_sizes
+            .get(&token_canister_id)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Zero removes the minimum.
+    pub fn set_min_order_size(&mut self, token_canister_id: Principal, amount: Nat) {
+        match nat_to_u128(amount) {
+            0 => self.min_order_sizes.remove(&token_canister_id),
+            amount => self.min_order_sizes.insert(token_canister_id, amount),
+        };
+    }
+
+    pub fn get_collected_fees(&self) -> Vec<Balance> {
+        self.balances_of(&self.env.canister_id())
+    }
+
+    pub fn get_user_trades(
+        &self,
+        user: Principal,
+        before: Option<TradeId>,
+        limit: usize,
+    ) -> Vec<Trade> {
+        self.trades.by_user(&user, before, limit)
+    }
+
+    pub fn get_pair_trades(
+        &self,
+        token_a: Principal,
+        token_b: Principal,
+        before: Option<TradeId>,
+        limit: usize,
+    ) -> Vec<Trade> {
+        self.trades.by_pair(token_a, token_b, before, limit)
+    }
+
+    pub fn get_candles(
+        &self,
+        base: Principal,
+        quote: Principal,
+        interval: u64,
+        start: u64,
+        end: u64,
+    ) -> Vec<Candle> {
+        self.trades.candles(base, quote, interval, start, end)
+    }
+
+    // Aggregates the open orders of a pair by price. Prices are those of `base` in
+    // `quote`, and amounts are in `base`.
+    pub fn get_depth(
+        &self,
+        base: Principal,
+        quote: Principal,
+        levels: usize,
+        now: u64,
+    ) -> OrderBookDepth {
+        self.get_depths(&[(base, quote)], levels, now)
+            .pop()
+            .unwrap()
+    }
+
+    // The depth of each of the (base, quote) `pairs`, in one pass over the orders.
+    pub fn get_depths(
+        &self,
+        pairs: &[(Principal, Principal)],
+        levels: usize,
+        now: u64,
+    ) -> Vec<OrderBookDepth> {
+        // Where the orders from one token to another go: the bids or the asks
+        // of a pair.
+        let mut sides: HashMap<(Principal, Principal), Vec<(usize, bool)>> = HashMap::new();
+        for (i, (base, quote)) in pairs.iter().enumerate() {
+            sides.entry((*quote, *base)).or_default().push((i, true));
+            sides.entry((*base, *quote)).or_default().push((i, false));
+        }
+        let mut bids = vec![Vec::new(); pairs.len()];
+        let mut asks = vec![Vec::new(); pairs.len()];
+        for o in self.orders.values().filter(|o| !o.is_expired(now)) {
+            if o.from_amount == 0 || o.to_amount == 0 {
+                continue;
+            }
+            let key = (o.from_token_canister_id, o.to_token_canister_id);
+            for (i, bid) in sides.get(&key).into_iter().flatten() {
+                if *bid {
+                    bids[*i].push((o.from_amount as f64 / o.to_amount as f64, o.to_amount));
+                } else {
+                    asks[*i].push((o.to_amount as f64 / o.from_amount as f64, o.from_amount));
+                }
+            }
+        }
+
+        let levels = levels.min(MAX_DEPTH);
+        bids.into_iter()
+            .zip(asks)
+            .map(|(mut bids, mut asks): (Vec<(f64, u128)>, _)| {
+                bids.sort_by(|x, y| y.0.total_cmp(&x.0));
+                asks.sort_by(|x, y| x.0.total_cmp(&y.0));
+                OrderBookDepth {
+                    bids: price_levels(bids, levels),
+                    asks: price_levels(asks, levels),
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_top_of_book(&self, base: Principal, quote: Principal, now: u64) -> TopOfBook {
+        let depth = self.get_depth(base, quote, 1, now);
+        let best_bid = depth.bids.into_iter().next();
+        let best_ask = depth.asks.into_iter().next();
+        let spread = match (&best_bid, &best_ask) {
+            (Some(bid), Some(ask)) => Some(ask.price - bid.price),
+            _ => None,
+        };
+        TopOfBook {
+            bestBid: best_bid,
+            bestAsk: best_ask,
+            spread,
+        }
+    }
+
+    pub fn get_ticker(&self, base: Principal, quote: Principal, now: u64) -> Ticker {
+        let last_price = self
+            .trades
+            .last_pair_trade(base, quote)
+            .and_then(|t| t.price(&base));
+        let (volume, quote_volume) = self
+            .trades
+            .pair_trades_between(base, quote, now.saturating_sub(DAY_NANOS), now + 1)
+            .into_iter()
+            .filter_map(|t| t.base_quote_amounts(&base))
+            .fold((0u128, 0u128), |(v, q), (b, a)| (v + b, q + a));
+        Ticker {
+            lastPrice: last_price,
+            volume: volume.into(),
+            quoteVolume: quote_volume.into(),
+        }
+    }
+
+    pub fn get_pools(&self) -> Vec<Pool> {
+        self.pools.values().map(|p| p.into()).collect()
+    }
+
+    pub fn get_liquidity_positions(&self) -> Vec<LiquidityPosition> {
+        let caller = self.env.caller();
+        self.pools
+            .values()
+            .filter_map(|p| {
+                let shares = p.shares_of(&caller);
+                if shares == 0 {
+                    return None;
+                }
+                Some(LiquidityPosition {
+                    tokenA: p.token_a,
+                    tokenB: p.token_b,
+                    shares: shares.into(),
+                    amountA: (BigUint::from(shares) * BigUint::from(p.reserve_a)
+                        / BigUint::from(p.total_shares))
+                    .into(),
+                    amountB: (BigUint::from(shares) * BigUint::from(p.reserve_b)
+                        / BigUint::from(p.total_shares))
+                    .into(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn add_liquidity(
+        &mut self,
+        token_a: Principal,
+        amount_a: Nat,
+        token_b: Principal,
+        amount_b: Nat,
+    ) -> AddLiquidityReceipt {
+        let caller = self.env.caller();
+        if token_a == token_b {
+            return AddLiquidityReceipt::Err(PoolErr::NotExistingPool);
+        }
+        let amount_a = nat_to_u128(amount_a);
+        let amount_b = nat_to_u128(amount_b);
+        if self.balances.balance_of(&caller, &token_a) < amount_a
+            || self.balances.balance_of(&caller, &token_b) < amount_b
+        {
+            return AddLiquidityReceipt::Err(PoolErr::BalanceLow);
+        }
+
+        let key = pair_key(token_a, token_b);
+        let (amount_0, amount_1) = if key.0 == token_a {
+            (amount_a, amount_b)
+        } else {
+            (amount_b, amount_a)
+        };
+        let pool = self
+            .pools
+            .entry(key)
+            .or_insert_with(|| PoolState::new(key.0, key.1));
+        let (used_0, used_1, shares) = match pool.add_liquidity(caller, amount_0, amount_1) {
+            Ok(added) => added,
+            Err(e) => {
+                if pool.total_shares == 0 {
+                    self.pools.remove(&key);
+                }
+                return AddLiquidityReceipt::Err(e);
+            }
+        };
+
+        self.balances.subtract_balance(&caller, &key.0, used_0);
+        self.balances.subtract_balance(&caller, &key.1, used_1);
+        self.pool_changes.record(
+            caller,
+            PoolChangeKind::Liquidity,
+            key.0,
+            -(used_0 as i128),
+            key.1,
+            -(used_1 as i128),
+            self.env.now(),
+        );
+        ic_cdk::println!("add liquidity {} {} -> {} shares", used_0, used_1, shares);
+
+        AddLiquidityReceipt::Ok(shares.into())
+    }
+
+    pub fn remove_liquidity(
+        &mut self,
+        token_a: Principal,
+        token_b: Principal,
+        shares: Nat,
+    ) -> RemoveLiquidityReceipt {
+        let caller = self.env.caller();
+        let key = pair_key(token_a, token_b);
+        let shares = nat_to_u128(shares);
+        let pool = self.pools.get_mut(&key).ok_or(PoolErr::NotExistingPool)?;
+        let (amount_0, amount_1) = pool.remove_liquidity(caller, shares)?;
+        if pool.total_shares == 0 {
+            self.pools.remove(&key);
+        }
+
+        self.balances.add_balance(&caller, &key.0, amount_0);
+        self.balances.add_balance(&caller, &key.1, amount_1);
+        self.pool_changes.record(
+            caller,
+            PoolChangeKind::Liquidity,
+            key.0,
+            amount_0 as i128,
+            key.1,
+            amount_1 as i128,
+            self.env.now(),
+        );
+
+        RemoveLiquidityReceipt::Ok(LiquidityPosition {
+            tokenA: key.0,
+            tokenB: key.1,
+            shares: shares.into(),
+            amountA: amount_0.into(),
+            amountB: amount_1.into(),
+        })
+    }
+
+    pub fn quote_swap(
+        &self,
+        from_token_canister_id: Principal,
+        from_amount: Nat,
+        to_token_canister_id: Principal,
+    ) -> SwapReceipt {
+        let pool = self
+            .pools
+            .get(&pair_key(from_token_canister_id, to_token_canister_id))
+            .ok_or(PoolErr::NotExistingPool)?;
+
+        Ok(pool
+            .quote(&from_token_canister_id, nat_to_u128(from_amount))?
+            .into())
+    }
+
+    pub fn swap_exact_in(
+        &mut self,
+        from_token_canister_id: Principal,
+        from_amount: Nat,
+        to_token_canister_id: Principal,
+        min_to_amount: Nat,
+    ) -> SwapReceipt {
+        let caller = self.env.caller();
+        let from_amount = nat_to_u128(from_amount);
+        if self.balances.balance_of(&caller, &from_token_canister_id) < from_amount {
+            return SwapReceipt::Err(PoolErr::BalanceLow);
+        }
+        let pool = self
+            .pools
+            .get_mut(&pair_key(from_token_canister_id, to_token_canister_id))
+            .ok_or(PoolErr::NotExistingPool)?;
+        if pool.quote(&from_token_canister_id, from_amount)? < nat_to_u128(min_to_amount) {
+            return SwapReceipt::Err(PoolErr::SlippageExceeded);
+        }
+        let to_amount = pool.swap(&from_token_canister_id, from_amount)?;
+
+        self.balances
+            .subtract_balance(&caller, &from_token_canister_id, from_amount);
+        self.balances
+            .add_balance(&caller, &to_token_canister_id, to_amount);
+        self.pool_changes.record(
+            caller,
+            PoolChangeKind::Swap,
+            from_token_canister_id,
+            -(from_amount as i128),
+            to_token_canister_id,
+            to_amount as i128,
+            self.env.now(),
+        );
+        ic_cdk::println!("swap {} -> {}", from_amount, to_amount);
+
+        SwapReceipt::Ok(to_amount.into())
+    }
+
+    pub fn quote_swap_route(
+        &self,
+        from_token_canister_id: Principal,
+        from_amount: Nat,
+        to_token_canister_id: Principal,
+        tokens: &[Principal],
+        tradable: impl Fn(Principal, Principal) -> bool,
+    ) -> SwapRouteReceipt {
+        let from_amount = nat_to_u128(from_amount);
+        if from_amount == 0 {
+            return Err(PoolErr::InvalidAmount);
+        }
+        let legs = self
+            .find_route(
+                from_token_canister_id,
+                from_amount,
+                to_token_canister_id,
+                tokens,
+                &tradable,
+            )
+            .ok_or(PoolErr::NoRoute)?;
+
+        Ok(swap_route(&legs))
+    }
+
+    // Swaps along the route through order books and pools that gives the most of
+    // `to_token_canister_id`. Each leg spends what the previous one received,
+    // and the call traps if the last one fell short so that none of them is
+    // kept. Returns the legs as they were executed.
+    pub fn swap(
+        &mut self,
+        from_token_canister_id: Principal,
+        from_amount: Nat,
+        to_token_canister_id: Principal,
+        min_to_amount: Nat,
+        tokens: &[Principal],
+        tradable: impl Fn(Principal, Principal) -> bool,
+    ) -> SwapRouteReceipt {
+        let caller = self.env.caller();
+        let now = self.env.now();
+        self.remove_expired_orders(now);
+
+        let from_amount = nat_to_u128(from_amount);
+        let min_to_amount = nat_to_u128(min_to_amount);
+        if from_amount == 0 {
+            return Err(PoolErr::InvalidAmount);
+        }
+        if self.balances.balance_of(&caller, &from_token_canister_id) < from_amount {
+            return Err(PoolErr::BalanceLow);
+        }
+        let legs = self
+            .find_route(
+                from_token_canister_id,
+                from_amount,
+                to_token_canister_id,
+                tokens,
+                &tradable,
+            )
+            .ok_or(PoolErr::NoRoute)?;
+        if legs.last().unwrap().amount_out < min_to_amount {
+            return Err(PoolErr::SlippageExceeded);
+        }
+
+        let mut executed = Vec::with_capacity(legs.len());
+        let mut amount = from_amount;
+        for leg in legs {
+            let (amount_in, amount_out) =
+                self.execute_leg(caller, leg.from, amount, leg.to, leg.venue, now);
+            ic_cdk::println!("swap leg {} -> {}", amount_in, amount_out);
+            executed.push(SwapLegState {
+                amount_in,
+                amount_out,
+                ..leg
+            });
+            amount = amount_out;
+        }
+        if amount < min_to_amount {
+            ic_cdk::trap("The swap fell short of its minimum.");
+        }
+        self.place_triggered_orders(now);
+
+        Ok(swap_route(&executed))
+    }
+
+    // The best route of at most MAX_SWAP_HOPS legs, through `tokens`.
+    fn find_route(
+        &self,
+        from: Principal,
+        from_amount: u128,
+        to: Principal,
+        tokens: &[Principal],
+        tradable: &impl Fn(Principal, Principal) -> bool,
+    ) -> Option<Vec<SwapLegState>> {
+        // Paths are extended by one more token that can be swapped to until
+        // they have MAX_SWAP_HOPS legs.
+        let next = self.swap_pairs(tradable);
+        let mut routes = Vec::new();
+        let mut paths = vec![vec![from]];
+        while let Some(path) = paths.pop() {
+            let next = match next.get(path.last().unwrap()) {
+                Some(next) => next,
+                None => continue,
+            };
+            if next.contains(&to) {
+                let mut route = path.clone();
+                route.push(to);
+                routes.push(route);
+            }
+            if path.len() < MAX_SWAP_HOPS {
+                for t in next
+                    .iter()
+                    .filter(|t| **t != to && !path.contains(t) && tokens.contains(t))
+                {
+                    let mut path = path.clone();
+                    path.push(*t);
+                    paths.push(path);
+                }
+            }
+        }
+
+        routes
+            .iter()
+            .filter_map(|path| self.quote_path(path, from_amount, tradable))
+            .max_by_key(|legs| legs.last().unwrap().amount_out)
+    }
+
+    // The tokens each token can be swapped to directly, through a pool or
+    // against the open orders of a book.
+    fn swap_pairs(
+        &self,
+        tradable: &impl Fn(Principal, Principal) -> bool,
+    ) -> HashMap<Principal, BTreeSet<Principal>> {
+        let pools = self
+            .pools
+            .keys()
+            .flat_map(|(a, b)| vec![(*a, *b), (*b, *a)]);
+        let books = self
+            .orders
+            .values()
+            .map(|o| (o.to_token_canister_id, o.from_token_canister_id));
+        let mut next: HashMap<Principal, BTreeSet<Principal>> = HashMap::new();
+        for (from, to) in pools.chain(books) {
+            if tradable(from, to) {
+                next.entry(from).or_default().insert(to);
+            }
+        }
+        next
+    }
+
+    fn quote_path(
+        &self,
+        path: &[Principal],
+        from_amount: u128,
+        tradable: &impl Fn(Principal, Principal) -> bool,
+    ) -> Option<Vec<SwapLegState>> {
+        let mut legs: Vec<SwapLegState> = Vec::new();
+        let mut amount = from_amount;
+        for hop in path.windows(2) {
+            let leg = self.quote_leg(hop[0], amount, hop[1], tradable)?;
+            amount = leg.amount_out;
+            legs.push(leg);
+        }
+        Some(legs)
+    }
+
+    // The better of the pool and the order book of a pair. The book only counts
+    // if it takes all of `amount_in`.
+    fn quote_leg(
+        &self,
+        from: Principal,
+        amount_in: u128,
+        to: Principal,
+        tradable: &impl Fn(Principal, Principal) -> bool,
+    ) -> Option<SwapLegState> {
+        if !tradable(from, to) {
+            return None;
+        }
+        let pool = self
+            .pools
+            .get(&pair_key(from, to))
+            .and_then(|p| p.quote(&from, amount_in).ok())
+            .map(|out| (SwapVenue::Pool, out));
+        let book = if self.auctions.is_batched(from, to) {
+            None
+        } else {
+            self.quote_book(from, amount_in, to)
+                .map(|out| (SwapVenue::OrderBook, out))
+        };
+
+        pool.into_iter()
+            .chain(book)
+            .filter(|(_, out)| *out > 0)
+            .max_by_key(|(_, out)| *out)
+            .map(|(venue, amount_out)| SwapLegState {
+                from,
+                to,
+                venue,
+                amount_in,
+                amount_out,
+            })
+    }
+
+    // What a market order of `amount_in` receives after fees, if the book fills it.
+    fn quote_book(&self, from: Principal, amount_in: u128, to: Principal) -> Option<u128> {
+        let order = OrderState {
+            id: 0,
+            owner: Principal::anonymous(),
+            from_token_canister_id: from,
+            from_amount: amount_in,
+            to_token_canister_id: to,
+            to_amount: 0,
+            expires_at: None,
+        };
+        let (matches, remaining) = self.resolve_order(&order);
+        if !remaining.is_filled() {
+            return None;
+        }
+        Some(
+            matches
+                .iter()
+                .map(|(_, a_to_amount, _)| a_to_amount - self.fees.taker_fee(*a_to_amount))
+                .sum(),
+        )
+    }
+
+    // Swaps `amount_in` through `venue`. Returns what the leg spent and received.
+    // A book may not take all of it, and what is left stays with the owner.
+    fn execute_leg(
+        &mut self,
+        owner: Principal,
+        from: Principal,
+        amount_in: u128,
+        to: Principal,
+        venue: SwapVenue,
+        now: u64,
+    ) -> (u128, u128) {
+        match venue {
+            SwapVenue::Pool => {
+                let pool = self.pools.get_mut(&pair_key(from, to)).unwrap();
+                let amount_out = match pool.swap(&from, amount_in) {
+                    Ok(amount_out) => amount_out,
+                    Err(_) => ic_cdk::trap("The swap could not be executed."),
+                };
+                self.balances.subtract_balance(&owner, &from, amount_in);
+                self.balances.add_balance(&owner, &to, amount_out);
+                self.pool_changes.record(
+                    owner,
+                    PoolChangeKind::Swap,
+                    from,
+                    -(amount_in as i128),
+                    to,
+                    amount_out as i128,
+                    now,
+                );
+                (amount_in, amount_out)
+            }
+            SwapVenue::OrderBook => {
+                let placement = self
+                    .new_order(
+                        owner,
+                        from,
+                        amount_in.into(),
+                        to,
+                        0u32.into(),
+                        OrderType::Market,
+                        now,
+                    )
+                    .and_then(|order| self.execute_order(order, OrderType::Market, now));
+                match placement {
+                    Ok(placement) => {
+                        placement
+                            .fills
+                            .into_iter()
+                            .fold((0, 0), |(spent, received), f| {
+                                (
+                                    spent + nat_to_u128(f.fromAmount),
+                                    received + nat_to_u128(f.toAmount),
+                                )
+                            })
+                    }
+                    Err(_) => ic_cdk::trap("The swap could not be executed."),
+                }
+            }
+        }
+    }
+
+    fn next_id(&mut self) -> OrderId {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+// A leg of a swap route, as quoted.
+#[derive(Clone, Copy, Debug)]
+struct SwapLegState {
+    from: Principal,
+    to: Principal,
+    venue: SwapVenue,
+    amount_in: u128,
+    amount_out: u128,
+}
+
+fn swap_route(legs: &[SwapLegState]) -> SwapRoute {
+    SwapRoute {
+        legs: legs
+            .iter()
+            .map(|l| SwapLeg {
+                from: l.from,
+                to: l.to,
+                venue: l.venue,
+                amountIn: l.amount_in.into(),
+                amountOut: l.amount_out.into(),
+            })
+            .collect(),
+        amountOut: legs.last().map_or(0, |l| l.amount_out).into(),
+    }
+}
+
+// Merges sorted (price, amount) entries of equal price into at most `levels` levels.
+fn price_levels(entries: Vec<(f64, u128)>, levels: usize) -> Vec<PriceLevel> {
+    let mut result: Vec<(f64, u128, u32)> = Vec::new();
+    for (price, amount) in entries {
+        if let Some(level) = result.last_mut().filter(|level| level.0 == price) {
+            level.1 += amount;
+            level.2 += 1;
+        } else if result.len() == levels {
+            break;
+        } else {
+            result.push((price, amount, 1));
+        }
+    }
+    result
+        .into_iter()
+        .map(|(price, amount, orders)| PriceLevel {
+            price,
+            amount: amount.into(),
+            orders,
+        })
+        .collect()
+}
+
+// The order `a` spends as much as it can at the price of `b`.
+// Returns what `a` and `b` receive.
+fn fill_amounts(a: &OrderState, b: &OrderState) -> Option<(u128, u128)> {
+    let b_to_amount = a.from_amount.min(b.to_amount);
+    let a_to_amount: u128 = ((BigUint::from(b_to_amount) * BigUint::from(b.from_amount))
+        / BigUint::from(b.to_amount))
+    .try_into()
+    .unwrap();
+
+    if a_to_amount > 0 {
+        Some((a_to_amount, b_to_amount))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::TestEnvironment;
+    use crate::test_utils::principal;
+    use proptest::prelude::*;
+    use proptest::test_runner::{Config, RngAlgorithm, TestRng, TestRunner};
+
+    fn new_exchange() -> Exchange {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut exchange = Exchange::init(&memory_manager, SavedExchange::default());
+        exchange.env = environment(USER);
+        exchange
+    }
+
+    fn environment(caller: u8) -> Box<dyn Environment> {
+        Box::new(TestEnvironment {
+            now: 1,
+            caller: principal(caller),
+            canister_id: principal(EXCHANGE),
+        })
+    }
+
+    const USER: u8 = 1;
+    const TOKEN_A: u8 = 2;
+    const TOKEN_B: u8 = 3;
+    const EXCHANGE: u8 = 9;
+
+    // An order to put on the book. It is of USER, does not expire and reserves
+    // from the free balance unless it is funded.
+    struct TestOrder {
+        owner: u8,
+        from: u8,
+        from_amount: u128,
+        to: u8,
+        to_amount: u128,
+        expires_at: Option<u64>,
+        funded: bool,
+    }
+
+    impl TestOrder {
+        fn new(from: u8, from_amount: u128, to: u8, to_amount: u128) -> Self {
+            TestOrder {
+                owner: USER,
+                from,
+                from_amount,
+                to,
+                to_amount,
+                expires_at: None,
+                funded: false,
+            }
+        }
+
+        fn owner(self, owner: u8) -> Self {
+            TestOrder { owner, ..self }
+        }
+
+        fn expires_at(self, expires_at: u64) -> Self {
+            TestOrder {
+                expires_at: Some(expires_at),
+                ..self
+            }
+        }
+
+        // Credits the owner with what the order sells first.
+        fn funded(self) -> Self {
+            TestOrder {
+                funded: true,
+                ..self
+            }
+        }
+
+        // Puts the order on the book without matching it.
+        fn place(self, exchange: &mut Exchange) -> OrderState {
+            let order = OrderState {
+                id: exchange.next_id(),
+                owner: principal(self.owner),
+                from_token_canister_id: principal(self.from),
+                from_amount: self.from_amount,
+                to_token_canister_id: principal(self.to),
+                to_amount: self.to_amount,
+                expires_at: self.expires_at,
+            };
+            if self.funded {
+                exchange.balances.add_balance(
+                    &order.owner,
+                    &order.from_token_canister_id,
+                    order.from_amount,
+                );
+            }
+            assert!(exchange.reserve(&order));
+            exchange.orders.insert(order.id, order);
+            order
+        }
+    }
+
+    fn free_and_reserved(exchange: &Exchange) -> (u128, u128) {
+        (
+            exchange
+                .balances
+                .balance_of(&principal(USER), &principal(TOKEN_A)),
+            exchange
+                .reserved
+                .balance_of(&principal(USER), &principal(TOKEN_A)),
+        )
+    }
+
+    #[test]
+    fn orders_fit_their_stable_encoding() {
+        let owner = Principal::from_slice(&[0xff; 29]);
+        let order = OrderState {
+            id: OrderId::MAX,
+            owner,
+            from_token_canister_id: owner,
+            from_amount: u128::MAX,
+            to_token_canister_id: owner,
+            to_amount: u128::MAX,
+            expires_at: Some(u64::MAX),
+        };
+        assert!(order.to_bytes().len() <= MAX_ORDER_SIZE as usize);
+    }
+
+    #[test]
+    fn balances_never_go_negative() {
+        let Exchange {
+            mut balances,
+            mut reserved,
+            ..
+        } = new_exchange();
+        balances.add_balance(&principal(USER), &principal(TOKEN_A), 100);
+
+        assert!(!balances.subtract_balance(&principal(USER), &principal(TOKEN_B), 1));
+        assert!(!balances.move_balance(&mut reserved, &principal(USER), &principal(TOKEN_A), 101));
+        assert!(balances.move_balance(&mut reserved, &principal(USER), &principal(TOKEN_A), 60));
+        assert!(!balances.move_balance(&mut reserved, &principal(USER), &principal(TOKEN_A), 41));
+        assert!(balances.move_balance(&mut reserved, &principal(USER), &principal(TOKEN_A), 40));
+        assert_eq!(
+            balances.balance_of(&principal(USER), &principal(TOKEN_A)),
+            0
+        );
+        assert_eq!(
+            reserved.balance_of(&principal(USER), &principal(TOKEN_A)),
+            100
+        );
+        assert!(balances.balances_of(&principal(USER)).is_empty());
+    }
+
+    #[test]
+    fn orders_can_not_reserve_more_than_the_free_balance() {
+        let mut exchange = new_exchange();
+        exchange
+            .balances
+            .add_balance(&principal(USER), &principal(TOKEN_A), 500);
+        TestOrder::new(TOKEN_A, 300, TOKEN_B, 100).place(&mut exchange);
+        let over = OrderState {
+            id: exchange.next_id(),
+            owner: principal(USER),
+            from_token_canister_id: principal(TOKEN_A),
+            from_amount: 201,
+            to_token_canister_id: principal(TOKEN_B),
+            to_amount: 100,
+            expires_at: None,
+        };
+        assert!(!exchange.reserve(&over));
+        assert_eq!(free_and_reserved(&exchange), (200, 300));
+
+        let reserved: u128 = exchange.orders.values().map(|o| o.from_amount).sum();
+        assert_eq!(reserved, 300);
+    }
+
+    #[test]
+    fn orders_reserve_from_the_free_balance() {
+        let mut exchange = new_exchange();
+        exchange
+            .balances
+            .add_balance(&principal(USER), &principal(TOKEN_A), 1_000);
+        let first = TestOrder::new(TOKEN_A, 300, TOKEN_B, 100).place(&mut exchange);
+        TestOrder::new(TOKEN_A, 200, TOKEN_B, 100).place(&mut exchange);
+        assert_eq!(free_and_reserved(&exchange), (500, 500));
+
+        // A withdrawal only takes from the free balance and leaves the orders alone.
+        assert!(!exchange
+            .balances
+            .subtract_balance(&principal(USER), &principal(TOKEN_A), 600));
+        assert!(exchange
+            .balances
+            .subtract_balance(&principal(USER), &principal(TOKEN_A), 500));
+        assert_eq!(exchange.orders.values().count(), 2);
+
+        exchange.orders.remove(&first.id);
+        exchange.release(&first);
+        assert_eq!(free_and_reserved(&exchange), (300, 200));
+    }
+
+    fn level(level: &PriceLevel) -> (f64, u128, u32) {
+        (level.price, nat_to_u128(level.amount.clone()), level.orders)
+    }
+
+    #[test]
+    fn depth_aggregates_orders_by_price() {
+        let mut exchange = new_exchange();
+        // Bids for A in B at 2, 2 and 1, asks at 3 and 4.
+        for (from, from_amount, to, to_amount) in [
+            (TOKEN_B, 200, TOKEN_A, 100),
+            (TOKEN_B, 100, TOKEN_A, 100),
+            (TOKEN_B, 20, TOKEN_A, 10),
+            (TOKEN_A, 50, TOKEN_B, 200),
+            (TOKEN_A, 10, TOKEN_B, 30),
+        ] {
+            TestOrder::new(from, from_amount, to, to_amount)
+                .funded()
+                .expires_at(10)
+                .place(&mut exchange);
+        }
+
+        let depth = exchange.get_depth(principal(TOKEN_A), principal(TOKEN_B), 10, 0);
+        let bids: Vec<_> = depth.bids.iter().map(level).collect();
+        let asks: Vec<_> = depth.asks.iter().map(level).collect();
+        assert_eq!(bids, vec![(2.0, 110, 2), (1.0, 100, 1)]);
+        assert_eq!(asks, vec![(3.0, 10, 1), (4.0, 50, 1)]);
+
+        let depth = exchange.get_depth(principal(TOKEN_A), principal(TOKEN_B), 1, 0);
+        assert_eq!((depth.bids.len(), depth.asks.len()), (1, 1));
+
+        // Each pair of a batch sees the book from its own base.
+        let (a, b, c) = (principal(TOKEN_A), principal(TOKEN_B), principal(TOKEN_C));
+        let depths = exchange.get_depths(&[(a, b), (b, a), (a, c)], 10, 0);
+        let sizes: Vec<_> = depths
+            .iter()
+            .map(|d| (d.bids.len(), d.asks.len()))
+            .collect();
+        assert_eq!(sizes, vec![(2, 2), (2, 2), (0, 0)]);
+        assert_eq!(level(&depths[1].asks[0]), (0.5, 220, 2));
+
+        let top = exchange.get_top_of_book(principal(TOKEN_A), principal(TOKEN_B), 0);
+        assert_eq!(top.spread, Some(1.0));
+        assert_eq!(level(&top.bestBid.unwrap()), (2.0, 110, 2));
+
+        // Expired orders are not on the book.
+        let top = exchange.get_top_of_book(principal(TOKEN_A), principal(TOKEN_B), 10);
+        assert!(top.bestBid.is_none() && top.bestAsk.is_none() && top.spread.is_none());
+    }
+
+    #[test]
+    fn ticker_covers_the_last_day() {
+        let mut exchange = new_exchange();
+        for (timestamp, taker_amount, maker_amount) in [(0, 10, 20), (DAY_NANOS, 5, 15)] {
+            exchange.trades.record(TradeState {
+                id: exchange.trades.next_id(),
+                timestamp,
+                taker_order_id: 1,
+                taker: principal(USER),
+                taker_token_canister_id: principal(TOKEN_A),
+                taker_amount,
+                taker_fee: 0,
+                maker_order_id: 2,
+                maker: principal(USER),
+                maker_token_canister_id: principal(TOKEN_B),
+                maker_amount,
+                maker_fee: 0,
+            });
+        }
+
+        let ticker = exchange.get_ticker(principal(TOKEN_A), principal(TOKEN_B), DAY_NANOS + 1);
+        assert_eq!(ticker.lastPrice, Some(3.0));
+        assert_eq!(
+            (ticker.volume, ticker.quoteVolume),
+            (Nat::from(5u32), Nat::from(15u32))
+        );
+        let ticker = exchange.get_ticker(principal(TOKEN_B), principal(TOKEN_A), DAY_NANOS);
+        assert_eq!(
+            (ticker.volume, ticker.quoteVolume),
+            (Nat::from(35u32), Nat::from(15u32))
+        );
+    }
+
+    #[test]
+    fn expired_orders_release_their_reservation() {
+        let mut exchange = new_exchange();
+        exchange
+            .balances
+            .add_balance(&principal(USER), &principal(TOKEN_A), 1_000);
+        TestOrder::new(TOKEN_A, 300, TOKEN_B, 100)
+            .expires_at(10)
+            .place(&mut exchange);
+        let live = TestOrder::new(TOKEN_A, 200, TOKEN_B, 100)
+            .expires_at(20)
+            .place(&mut exchange);
+
+        exchange.remove_expired_orders(10);
+        assert_eq!(free_and_reserved(&exchange), (800, 200));
+        assert_eq!(
+            exchange.orders.values().map(|o| o.id).collect::<Vec<_>>(),
+            vec![live.id]
+        );
+    }
+
+    #[test]
+    fn batch_auctions_clear_at_one_price() {
+        let mut exchange = new_exchange();
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        assert!(exchange.set_batch_auction(a, b, Some(1), 0).is_err());
+        assert!(exchange
+            .set_batch_auction(a, b, Some(MIN_AUCTION_EPOCH), 0)
+            .is_ok());
+
+        // Bids for A at 3 and 2 B, asks at 1 and 4 B, which clear at 2 B.
+        let best_bid = TestOrder::new(TOKEN_B, 300, TOKEN_A, 100)
+            .owner(4)
+            .funded()
+            .place(&mut exchange)
+            .id;
+        let bid = TestOrder::new(TOKEN_B, 200, TOKEN_A, 100)
+            .owner(5)
+            .funded()
+            .place(&mut exchange)
+            .id;
+        let ask = TestOrder::new(TOKEN_A, 150, TOKEN_B, 150)
+            .owner(6)
+            .funded()
+            .place(&mut exchange)
+            .id;
+        let high_ask = TestOrder::new(TOKEN_A, 50, TOKEN_B, 200)
+            .owner(7)
+            .funded()
+            .place(&mut exchange)
+            .id;
+
+        exchange.run_batch_auctions(MIN_AUCTION_EPOCH - 1);
+        assert!(exchange.auctions.get(a, b).unwrap().lastClearing.is_none());
+
+        exchange.run_batch_auctions(MIN_AUCTION_EPOCH);
+        let auction = exchange.auctions.get(a, b).unwrap();
+        assert_eq!(auction.nextClearing, 2 * MIN_AUCTION_EPOCH);
+        let clearing = auction.lastClearing.unwrap();
+        assert_eq!(clearing.price, Some(2.0));
+        assert_eq!(
+            (
+                nat_to_u128(clearing.volume),
+                nat_to_u128(clearing.quoteVolume)
+            ),
+            (150, 300)
+        );
+
+        // The best bid buys all of the crossing ask at the clearing price.
+        assert_eq!(exchange.balances.balance_of(&principal(4), &a), 150);
+        assert_eq!(exchange.balances.balance_of(&principal(6), &b), 300);
+        assert!(exchange.orders.get(&best_bid).is_none() && exchange.orders.get(&ask).is_none());
+        assert!(exchange.orders.get(&bid).is_some() && exchange.orders.get(&high_ask).is_some());
+        assert_eq!(exchange.reserved.balance_of(&principal(4), &b), 0);
+        assert_eq!(exchange.reserved.balance_of(&principal(5), &b), 200);
+    }
+
+    #[test]
+    fn batch_auctions_skip_what_is_left_of_an_ask() {
+        let mut exchange = new_exchange();
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        assert!(exchange
+            .set_batch_auction(a, b, Some(MIN_AUCTION_EPOCH), 0)
+            .is_ok());
+
+        // The first bid leaves 1 A of the first ask, which is worth no B.
+        TestOrder::new(TOKEN_B, 1, TOKEN_A, 3)
+            .owner(4)
+            .funded()
+            .place(&mut exchange);
+        let bid = TestOrder::new(TOKEN_B, 100, TOKEN_A, 300)
+            .owner(5)
+            .funded()
+            .place(&mut exchange)
+            .id;
+        let dust = TestOrder::new(TOKEN_A, 4, TOKEN_B, 1)
+            .owner(6)
+            .funded()
+            .place(&mut exchange)
+            .id;
+        let ask = TestOrder::new(TOKEN_A, 300, TOKEN_B, 100)
+            .owner(7)
+            .funded()
+            .place(&mut exchange)
+            .id;
+
+        exchange.run_batch_auctions(MIN_AUCTION_EPOCH);
+        let clearing = exchange.auctions.get(a, b).unwrap().lastClearing.unwrap();
+        assert_eq!(
+            (
+                nat_to_u128(clearing.volume),
+                nat_to_u128(clearing.quoteVolume),
+                clearing.trades
+            ),
+            (303, 101, 2)
+        );
+        assert!(exchange.orders.get(&bid).is_none() && exchange.orders.get(&ask).is_none());
+        assert_eq!(exchange.orders.get(&dust).unwrap().from_amount, 1);
+        assert_eq!(exchange.balances.balance_of(&principal(5), &a), 300);
+        assert_eq!(exchange.balances.balance_of(&principal(7), &b), 100);
+    }
+
+    #[test]
+    fn order_limits_are_enforced() {
+        let mut exchange = new_exchange();
+        exchange.limits = OrderLimits {
+            maxOrdersPerUser: 2,
+            maxOrdersPerPair: 3,
+        };
+        exchange.set_min_order_size(principal(TOKEN_A), 10u32.into());
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        exchange.balances.add_balance(&principal(USER), &a, 1_000);
+        exchange.balances.add_balance(&principal(4), &a, 1_000);
+        let sell = |exchange: &mut Exchange, owner: u8, amount: u32, order_type| {
+            exchange.env = environment(owner);
+            exchange.place_order(a, amount.into(), b, 100u32.into(), order_type)
+        };
+
+        assert!(matches!(
+            sell(&mut exchange, USER, 9, OrderType::Limit),
+            Err(OrderPlacementErr::OrderTooSmall)
+        ));
+        assert!(sell(&mut exchange, USER, 10, OrderType::Limit).is_ok());
+        assert!(sell(&mut exchange, USER, 10, OrderType::Limit).is_ok());
+        assert!(matches!(
+            sell(&mut exchange, USER, 10, OrderType::Limit),
+            Err(OrderPlacementErr::OrderBookFull)
+        ));
+        // Orders that don't rest don't count.
+        assert!(sell(&mut exchange, USER, 10, OrderType::ImmediateOrCancel).is_ok());
+
+        assert!(sell(&mut exchange, 4, 10, OrderType::Limit).is_ok());
+        assert!(matches!(
+            sell(&mut exchange, 4, 10, OrderType::Limit),
+            Err(OrderPlacementErr::OrderBookFull)
+        ));
+        let conditional = exchange.place_conditional_order(
+            a,
+            10u32.into(),
+            b,
+            100u32.into(),
+            OrderType::Limit,
+            Trigger {
+                condition: TriggerCondition::StopLoss,
+                price: 1.0,
+            },
+        );
+        assert!(matches!(conditional, Err(OrderPlacementErr::OrderBookFull)));
+
+        // Cancelled orders free their place.
+        exchange.env = environment(USER);
+        let ids: Vec<OrderId> = exchange.orders.values().map(|o| o.id).collect();
+        assert!(exchange.cancel_order(ids[0]).is_ok());
+        assert_eq!(exchange.orders.counts.of(&principal(USER), a, b), (1, 2));
+        assert!(sell(&mut exchange, USER, 10, OrderType::Limit).is_ok());
+        assert_eq!(exchange.orders.counts.of(&principal(USER), a, b), (2, 3));
+
+        exchange.limits.maxOrdersPerPair = 0;
+        let placement = sell(&mut exchange, 4, 10, OrderType::Limit).ok().unwrap();
+        assert!(exchange.cancel_order(placement.id).is_ok());
+        assert_eq!(exchange.orders.counts.of(&principal(4), a, b), (1, 3));
+    }
+
+    // USER sells A for B against makers who sell 100 B for 100 and 200 A.
+    fn book_of_makers() -> Exchange {
+        let mut exchange = new_exchange();
+        TestOrder::new(TOKEN_B, 100, TOKEN_A, 100)
+            .owner(6)
+            .funded()
+            .place(&mut exchange);
+        TestOrder::new(TOKEN_B, 100, TOKEN_A, 200)
+            .owner(7)
+            .funded()
+            .place(&mut exchange);
+        exchange
+            .balances
+            .add_balance(&principal(USER), &principal(TOKEN_A), 1_000);
+        exchange
+    }
+
+    fn sell_a(
+        exchange: &mut Exchange,
+        amount: u32,
+        min: u32,
+        order_type: OrderType,
+    ) -> OrderPlacement {
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        exchange
+            .place_order(a, amount.into(), b, min.into(), order_type)
+            .ok()
+            .unwrap()
+    }
+
+    fn received_b(exchange: &Exchange) -> u128 {
+        exchange
+            .balances
+            .balance_of(&principal(USER), &principal(TOKEN_B))
+    }
+
+    fn orders_of_user(exchange: &Exchange) -> usize {
+        exchange
+            .orders
+            .values()
+            .filter(|o| o.owner == principal(USER))
+            .count()
+    }
+
+    #[test]
+    fn immediate_or_cancel_orders_never_rest() {
+        let mut exchange = book_of_makers();
+        let placement = sell_a(&mut exchange, 300, 300, OrderType::ImmediateOrCancel);
+        assert!(placement.status == OrderStatus::Cancelled && placement.order.is_none());
+        assert_eq!(placement.fills.len(), 1);
+        assert_eq!(free_and_reserved(&exchange), (900, 0));
+        assert_eq!(received_b(&exchange), 100);
+        assert_eq!(orders_of_user(&exchange), 0);
+    }
+
+    #[test]
+    fn fill_or_kill_orders_fill_completely_or_not_at_all() {
+        let mut exchange = book_of_makers();
+        let placement = sell_a(&mut exchange, 300, 200, OrderType::FillOrKill);
+        assert!(placement.status == OrderStatus::Cancelled && placement.fills.is_empty());
+        assert_eq!(free_and_reserved(&exchange), (1_000, 0));
+        assert_eq!(received_b(&exchange), 0);
+        assert_eq!(orders_of_user(&exchange), 0);
+        assert_eq!(exchange.orders.values().count(), 2);
+        assert_eq!(exchange.trades.next_id(), 0);
+
+        let placement = sell_a(&mut exchange, 300, 150, OrderType::FillOrKill);
+        assert!(placement.status == OrderStatus::Filled);
+        assert_eq!(free_and_reserved(&exchange), (700, 0));
+        assert_eq!(received_b(&exchange), 200);
+    }
+
+    #[test]
+    fn good_till_time_orders_expire() {
+        let mut exchange = book_of_makers();
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        assert!(matches!(
+            exchange.place_order(
+                a,
+                10u32.into(),
+                b,
+                100u32.into(),
+                OrderType::GoodTillTime(1)
+            ),
+            Err(OrderPlacementErr::InvalidOrder)
+        ));
+        let placement = sell_a(&mut exchange, 300, 600, OrderType::GoodTillTime(10));
+        assert!(placement.status == OrderStatus::Open && placement.fills.is_empty());
+        assert_eq!(free_and_reserved(&exchange), (700, 300));
+
+        exchange.remove_expired_orders(9);
+        assert_eq!(orders_of_user(&exchange), 1);
+        exchange.remove_expired_orders(10);
+        assert_eq!(orders_of_user(&exchange), 0);
+        assert_eq!(free_and_reserved(&exchange), (1_000, 0));
+        assert_eq!(exchange.orders.counts.of(&principal(USER), a, b), (0, 2));
+    }
+
+    #[test]
+    fn market_orders_leave_nothing_behind() {
+        let mut exchange = book_of_makers();
+        // Takes both makers at their prices, and the rest is released.
+        let placement = sell_a(&mut exchange, 500, 0, OrderType::Market);
+        assert!(placement.status == OrderStatus::Cancelled && placement.order.is_none());
+        assert_eq!(placement.fills.len(), 2);
+        assert_eq!(free_and_reserved(&exchange), (700, 0));
+        assert_eq!(received_b(&exchange), 200);
+        assert_eq!(exchange.orders.values().count(), 0);
+
+        // A bound on the price stops it at the first maker.
+        let mut exchange = book_of_makers();
+        let placement = sell_a(&mut exchange, 300, 300, OrderType::Market);
+        assert_eq!(placement.fills.len(), 1);
+        assert_eq!(free_and_reserved(&exchange), (900, 0));
+        assert_eq!(orders_of_user(&exchange), 0);
+    }
+
+    #[test]
+    fn fees_are_taken_from_what_each_side_receives() {
+        let mut exchange = new_exchange();
+        exchange.fees = TradingFees {
+            makerFeeBps: 25,
+            takerFeeBps: 30,
+        };
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        let maker = principal(6);
+        let fee_account = principal(EXCHANGE);
+        // The maker sells B at 1 A, the taker would pay up to 2 A.
+        TestOrder::new(TOKEN_B, 10_000, TOKEN_A, 10_000)
+            .owner(6)
+            .funded()
+            .place(&mut exchange);
+        exchange.balances.add_balance(&principal(USER), &a, 10_000);
+
+        let placement = sell_a(&mut exchange, 1_000, 500, OrderType::Limit);
+        assert!(placement.status == OrderStatus::Filled);
+        let fill = &placement.fills[0];
+        assert_eq!(
+            (
+                nat_to_u128(fill.fromAmount.clone()),
+                nat_to_u128(fill.toAmount.clone()),
+                nat_to_u128(fill.fee.clone())
+            ),
+            (1_000, 997, 3)
+        );
+        // 25 bps of 1_000 is 2.5, rounded down.
+        assert_eq!(received_b(&exchange), 997);
+        assert_eq!(exchange.balances.balance_of(&maker, &a), 998);
+        assert_eq!(exchange.balances.balance_of(&fee_account, &b), 3);
+        assert_eq!(exchange.balances.balance_of(&fee_account, &a), 2);
+        let trade = exchange.trades.iter().last().unwrap();
+        assert_eq!((trade.taker_fee, trade.maker_fee), (3, 2));
+
+        // Fills too small to owe a whole unit pay no fee.
+        let placement = sell_a(&mut exchange, 33, 33, OrderType::Limit);
+        assert_eq!(nat_to_u128(placement.fills[0].fee.clone()), 0);
+        assert_eq!(received_b(&exchange), 997 + 33);
+        assert_eq!(exchange.balances.balance_of(&maker, &a), 998 + 33);
+        assert_eq!(exchange.balances.balance_of(&fee_account, &b), 3);
+        assert_eq!(exchange.balances.balance_of(&fee_account, &a), 2);
+
+        // No tokens are made or lost.
+        for token in [a, b].iter() {
+            let total: u128 = [principal(USER), maker, fee_account]
+                .iter()
+                .map(|o| {
+                    exchange.balances.balance_of(o, token) + exchange.reserved.balance_of(o, token)
+                })
+                .sum();
+            assert_eq!(total, 10_000);
+        }
+    }
+
+    const TOKEN_C: u8 = 8;
+    const PROVIDER: u8 = 5;
+
+    // USER sells A for B once the price of A falls to 1 B.
+    fn stop_loss(exchange: &mut Exchange, amount: u32, min: u32, order_type: OrderType) -> OrderId {
+        let trigger = Trigger {
+            condition: TriggerCondition::StopLoss,
+            price: 1.0,
+        };
+        exchange.env = environment(USER);
+        exchange
+            .place_conditional_order(
+                principal(TOKEN_A),
+                amount.into(),
+                principal(TOKEN_B),
+                min.into(),
+                order_type,
+                trigger,
+            )
+            .ok()
+            .unwrap()
+            .order
+            .id
+    }
+
+    #[test]
+    fn conditional_orders_are_placed_once_triggered() {
+        let mut exchange = book_of_makers();
+        let filled = stop_loss(&mut exchange, 100, 50, OrderType::Market);
+        let cancelled = stop_loss(&mut exchange, 200, 100, OrderType::Limit);
+        assert_eq!(free_and_reserved(&exchange), (700, 300));
+        assert!(exchange.cancel_conditional_order(cancelled).is_ok());
+        assert_eq!(free_and_reserved(&exchange), (900, 100));
+
+        // Another user sells A at 1 B, and the stop loss sells after it.
+        exchange
+            .balances
+            .add_balance(&principal(8), &principal(TOKEN_A), 10);
+        exchange.env = environment(8);
+        sell_a(&mut exchange, 10, 10, OrderType::Limit);
+        assert_eq!(received_b(&exchange), 95);
+        assert_eq!(free_and_reserved(&exchange), (900, 0));
+        assert!(exchange.conditional.get(&filled).is_none());
+
+        exchange.env = environment(USER);
+        assert!(exchange.get_conditional_orders().is_empty());
+        let triggered = exchange.get_triggered_orders();
+        assert_eq!(triggered.len(), 1);
+        assert!(triggered[0].order.order.id == filled);
+        assert!(triggered[0].status == OrderStatus::Filled);
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        assert_eq!(exchange.orders.counts.of(&principal(USER), a, b).0, 0);
+    }
+
+    #[test]
+    fn triggered_orders_that_can_not_be_placed_are_rejected() {
+        let mut exchange = new_exchange();
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        exchange.balances.add_balance(&principal(USER), &a, 1_000);
+        let rejected = stop_loss(&mut exchange, 100, 50, OrderType::Market);
+        let open = stop_loss(&mut exchange, 200, 100, OrderType::Limit);
+
+        // A batch auction clears at 1 B. Market orders can't wait for the next one.
+        assert!(exchange
+            .set_batch_auction(a, b, Some(MIN_AUCTION_EPOCH), 0)
+            .is_ok());
+        TestOrder::new(TOKEN_B, 10, TOKEN_A, 10)
+            .owner(4)
+            .funded()
+            .place(&mut exchange);
+        TestOrder::new(TOKEN_A, 10, TOKEN_B, 10)
+            .owner(5)
+            .funded()
+            .place(&mut exchange);
+        exchange.run_batch_auctions(MIN_AUCTION_EPOCH);
+
+        assert_eq!(free_and_reserved(&exchange), (800, 200));
+        assert!(exchange.orders.get(&open).is_some());
+        exchange.env = environment(USER);
+        let statuses: Vec<_> = exchange
+            .get_triggered_orders()
+            .iter()
+            .map(|o| (o.order.order.id, o.status))
+            .collect();
+        assert!(statuses == vec![(open, OrderStatus::Open), (rejected, OrderStatus::Rejected)]);
+    }
+
+    // Funds and adds liquidity of PROVIDER to the pool of two tokens.
+    fn pool(exchange: &mut Exchange, token_a: u8, amount_a: u128, token_b: u8, amount_b: u128) {
+        exchange.env = environment(PROVIDER);
+        let provider = principal(PROVIDER);
+        for (token, amount) in [(token_a, amount_a), (token_b, amount_b)].iter() {
+            exchange
+                .balances
+                .add_balance(&provider, &principal(*token), *amount);
+        }
+        let added = exchange.add_liquidity(
+            principal(token_a),
+            amount_a.into(),
+            principal(token_b),
+            amount_b.into(),
+        );
+        assert!(added.is_ok());
+        exchange.env = environment(USER);
+    }
+
+    fn swap(exchange: &mut Exchange, from_amount: u128, min_to_amount: u128) -> SwapRouteReceipt {
+        let tokens = [principal(TOKEN_A), principal(TOKEN_B), principal(TOKEN_C)];
+        exchange.swap(
+            principal(TOKEN_A),
+            from_amount.into(),
+            principal(TOKEN_B),
+            min_to_amount.into(),
+            &tokens,
+            |_, _| true,
+        )
+    }
+
+    fn swap_quote(exchange: &Exchange, from_amount: u128) -> SwapRoute {
+        let tokens = [principal(TOKEN_A), principal(TOKEN_B), principal(TOKEN_C)];
+        exchange
+            .quote_swap_route(
+                principal(TOKEN_A),
+                from_amount.into(),
+                principal(TOKEN_B),
+                &tokens,
+                |_, _| true,
+            )
+            .unwrap()
+    }
+
+    fn venues(route: &SwapRoute) -> Vec<(u8, u8, SwapVenue)> {
+        route
+            .legs
+            .iter()
+            .map(|l| (l.from.as_slice()[0], l.to.as_slice()[0], l.venue))
+            .collect()
+    }
+
+    #[test]
+    fn swaps_are_routed_through_other_tokens() {
+        let mut exchange = new_exchange();
+        pool(&mut exchange, TOKEN_A, 100_000, TOKEN_C, 100_000);
+        pool(&mut exchange, TOKEN_C, 100_000, TOKEN_B, 200_000);
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        exchange.balances.add_balance(&principal(USER), &a, 1_000);
+
+        let route = swap(&mut exchange, 1_000, 0).unwrap();
+        assert_eq!(
+            venues(&route),
+            vec![
+                (TOKEN_A, TOKEN_C, SwapVenue::Pool),
+                (TOKEN_C, TOKEN_B, SwapVenue::Pool)
+            ]
+        );
+        // Each leg spent what the previous one received.
+        let legs: Vec<_> = route
+            .legs
+            .iter()
+            .map(|l| {
+                (
+                    nat_to_u128(l.amountIn.clone()),
+                    nat_to_u128(l.amountOut.clone()),
+                )
+            })
+            .collect();
+        assert_eq!(legs[0].0, 1_000);
+        assert_eq!(legs[0].1, legs[1].0);
+        let received = nat_to_u128(route.amountOut);
+        assert_eq!(received, legs[1].1);
+        assert!(received > 1_900 && received < 2_000);
+        assert_eq!(exchange.balances.balance_of(&principal(USER), &a), 0);
+        assert_eq!(exchange.balances.balance_of(&principal(USER), &b), received);
+        assert_eq!(
+            exchange
+                .balances
+                .balance_of(&principal(USER), &principal(TOKEN_C)),
+            0
+        );
+        assert_eq!(
+            exchange.pool_changes.changes_of(&principal(USER)).count(),
+            2
+        );
+    }
+
+    #[test]
+    fn swaps_take_the_route_that_gives_the_most() {
+        let mut exchange = new_exchange();
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        // The direct pool is shallow, so two hops through deep pools give more.
+        pool(&mut exchange, TOKEN_A, 10_000, TOKEN_B, 10_000);
+        pool(&mut exchange, TOKEN_A, 1_000_000, TOKEN_C, 1_000_000);
+        pool(&mut exchange, TOKEN_C, 1_000_000, TOKEN_B, 1_000_000);
+        assert_eq!(
+            venues(&swap_quote(&exchange, 1_000)),
+            vec![
+                (TOKEN_A, TOKEN_C, SwapVenue::Pool),
+                (TOKEN_C, TOKEN_B, SwapVenue::Pool)
+            ]
+        );
+
+        // An order that sells B at a better price than any pool takes it all.
+        let maker = TestOrder::new(TOKEN_B, 1_500, TOKEN_A, 1_000)
+            .owner(6)
+            .funded()
+            .place(&mut exchange)
+            .id;
+        exchange.balances.add_balance(&principal(USER), &a, 1_000);
+        let route = swap(&mut exchange, 1_000, 0).unwrap();
+        assert_eq!(
+            venues(&route),
+            vec![(TOKEN_A, TOKEN_B, SwapVenue::OrderBook)]
+        );
+        assert_eq!(nat_to_u128(route.amountOut), 1_500);
+        assert_eq!(exchange.balances.balance_of(&principal(USER), &b), 1_500);
+        assert!(exchange.orders.get(&maker).is_none());
+    }
+
+    #[test]
+    fn swaps_below_their_minimum_are_refused() {
+        let mut exchange = new_exchange();
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        assert!(matches!(
+            swap(&mut exchange, 1_000, 0),
+            Err(PoolErr::BalanceLow)
+        ));
+        exchange.balances.add_balance(&principal(USER), &a, 1_000);
+        assert!(matches!(
+            swap(&mut exchange, 1_000, 0),
+            Err(PoolErr::NoRoute)
+        ));
+
+        pool(&mut exchange, TOKEN_A, 100_000, TOKEN_B, 100_000);
+        let quoted = nat_to_u128(swap_quote(&exchange, 1_000).amountOut);
+        assert!(matches!(
+            swap(&mut exchange, 1_000, quoted + 1),
+            Err(PoolErr::SlippageExceeded)
+        ));
+        assert_eq!(exchange.balances.balance_of(&principal(USER), &a), 1_000);
+        assert_eq!(exchange.balances.balance_of(&principal(USER), &b), 0);
+        let pool = &exchange.pools[&pair_key(a, b)];
+        assert_eq!((pool.reserve_a, pool.reserve_b), (100_000, 100_000));
+
+        let route = swap(&mut exchange, 1_000, quoted).unwrap();
+        assert_eq!(nat_to_u128(route.amountOut), quoted);
+    }
+
+    #[derive(Clone, Debug)]
+    enum Action {
+        // Sells `from_amount` of A, or of B if not `sell_a`, for at least `to_amount`
+        // of the other token.
+        Place {
+            user: u8,
+            sell_a: bool,
+            from_amount: u128,
+            to_amount: u128,
+            order_type: u8,
+        },
+        // Cancels one of the open orders of the user.
+        Cancel {
+            user: u8,
+            pick: usize,
+        },
+    }
+
+    const TRADERS: [u8; 3] = [5, 6, 7];
+    const FUNDING: u128 = 1_000_000;
+    const ORDER_TYPES: [OrderType; 4] = [
+        OrderType::Limit,
+        OrderType::ImmediateOrCancel,
+        OrderType::FillOrKill,
+        OrderType::Market,
+    ];
+
+    fn action() -> impl Strategy<Value = Action> {
+        let user = prop::sample::select(&TRADERS[..]);
+        prop_oneof![
+            4 => (user.clone(), any::<bool>(), 1..5_000u128, 1..5_000u128, 0..4u8).prop_map(
+                |(user, sell_a, from_amount, to_amount, order_type)| Action::Place {
+                    user,
+                    sell_a,
+                    from_amount,
+                    to_amount,
+                    order_type,
+                }
+            ),
+            1 => (user, any::<usize>()).prop_map(|(user, pick)| Action::Cancel { user, pick }),
+        ]
+    }
+
+    // What every order paid and received across its trades, before fees.
+    fn paid_and_received(exchange: &Exchange) -> HashMap<OrderId, (u128, u128)> {
+        let mut totals: HashMap<OrderId, (u128, u128)> = HashMap::new();
+        for t in exchange.trades.iter() {
+            let taker = totals.entry(t.taker_order_id).or_default();
+            taker.0 += t.taker_amount;
+            taker.1 += t.maker_amount;
+            let maker = totals.entry(t.maker_order_id).or_default();
+            maker.0 += t.maker_amount;
+            maker.1 += t.taker_amount;
+        }
+        totals
+    }
+
+    // Random sequences of orders and cancellations between a few traders, with
+    // random fees. Runs are seeded, so they are the same every time.
+    #[test]
+    fn matching_keeps_balances_and_prices() {
+        let config = Config {
+            cases: 64,
+            failure_persistence: None,
+            ..Config::default()
+        };
+        let mut runner =
+            TestRunner::new_with_rng(config, TestRng::deterministic_rng(RngAlgorithm::ChaCha));
+        let strategy = (
+            0..=100u32,
+            0..=100u32,
+            prop::collection::vec(action(), 1..60),
+        );
+        runner
+            .run(&strategy, |(maker_fee, taker_fee, actions)| {
+                let mut exchange = new_exchange();
+                exchange.fees = TradingFees {
+                    makerFeeBps: maker_fee,
+                    takerFeeBps: taker_fee,
+                };
+                let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+                for user in TRADERS.iter() {
+                    exchange
+                        .balances
+                        .add_balance(&principal(*user), &a, FUNDING);
+                    exchange
+                        .balances
+                        .add_balance(&principal(*user), &b, FUNDING);
+                }
+                // The price each order was placed at.
+                let mut limits: HashMap<OrderId, (u128, u128)> = HashMap::new();
+
+                for action in actions {
+                    match action {
+                        Action::Place {
+                            user,
+                            sell_a,
+                            from_amount,
+                            to_amount,
+                            order_type,
+                        } => {
+                            exchange.env = environment(user);
+                            let (from, to) = if sell_a { (a, b) } else { (b, a) };
+                            let placement = exchange.place_order(
+                                from,
+                                from_amount.into(),
+                                to,
+                                to_amount.into(),
+                                ORDER_TYPES[order_type as usize],
+                            );
+                            if let Ok(placement) = placement {
+                                limits.insert(placement.id, (from_amount, to_amount));
+                            }
+                        }
+                        Action::Cancel { user, pick } => {
+                            exchange.env = environment(user);
+                            let ids: Vec<OrderId> = exchange
+                                .orders
+                                .values()
+                                .filter(|o| o.owner == principal(user))
+                                .map(|o| o.id)
+                                .collect();
+                            if !ids.is_empty() {
+                                prop_assert!(exchange.cancel_order(ids[pick % ids.len()]).is_ok());
+                            }
+                        }
+                    }
+
+                    // No tokens are made or lost, and open orders hold exactly
+                    // what is reserved for them, so no balance has to go negative.
+                    let mut owners = exchange.balances.owners();
+                    owners.extend(exchange.reserved.owners());
+                    for token in [a, b].iter() {
+                        let total: u128 = owners
+                            .iter()
+                            .map(|o| {
+                                exchange.balances.balance_of(o, token)
+                                    + exchange.reserved.balance_of(o, token)
+                            })
+                            .sum();
+                        prop_assert_eq!(total, FUNDING * TRADERS.len() as u128);
+                        for owner in owners.iter() {
+                            let offered: u128 = exchange
+                                .orders
+                                .values()
+                                .filter(|o| o.owner == *owner && o.from_token_canister_id == *token)
+                                .map(|o| o.from_amount)
+                                .sum();
+                            prop_assert_eq!(exchange.reserved.balance_of(owner, token), offered);
+                        }
+                    }
+                    // The order counts follow the book.
+                    let open: Vec<OrderState> = exchange.orders.values().collect();
+                    for user in TRADERS.iter() {
+                        let of_user = open.iter().filter(|o| o.owner == principal(*user)).count();
+                        prop_assert_eq!(
+                            exchange.orders.counts.of(&principal(*user), a, b),
+                            (of_user as u32, open.len() as u32)
+                        );
+                    }
+                }
+
+                // Takers trade at the price of the maker, which is at least their
+                // own, and no order ever gets less than the price it was placed at.
+                for t in exchange.trades.iter() {
+                    let (from_amount, to_amount) = limits[&t.taker_order_id];
+                    prop_assert!(t.maker_amount * from_amount >= t.taker_amount * to_amount);
+                }
+                for (id, (paid, received)) in paid_and_received(&exchange) {
+                    let (from_amount, to_amount) = limits[&id];
+                    prop_assert!(paid <= from_amount);
+                    prop_assert!(received * from_amount >= paid * to_amount);
+                }
+                Ok(())
+            })
+            .unwrap();
     }
 }
@@ -0,0 +1,54 @@
+use ic_cdk_macros::heartbeat;
+
+use crate::http::refresh_market_data;
+use crate::types::TokenStandard;
+use crate::{deposit_from_account, STATE};
+
+// How often deposit accounts are swept, in nanoseconds, and how many at a time.
+const SWEEP_INTERVAL: u64 = 60_000_000_000;
+const SWEEP_BATCH: usize = 10;
+
+#[heartbeat]
+async fn heartbeat() {
+    let now = ic_cdk::api::time();
+    let cleared = STATE.with(|s| s.borrow_mut().exchange.run_batch_auctions(now));
+    // Clearing auctions and building the market data both go through the whole
+    // book, so they are left to different heartbeats.
+    if !cleared {
+        STATE.with(|s| refresh_market_data(&s.borrow(), now));
+    }
+    sweep_deposit_accounts().await;
+}
+
+// Credits what was sent to the next batch of deposit accounts, in every listed
+// token that has accounts. Accounts that hold no more than the transfer fee are
+// left as they are.
+async fn sweep_deposit_accounts() {
+    let now = ic_cdk::api::time();
+    let sweep = STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if state.admin.withdraw_only || now < state.deposits.last_sweep + SWEEP_INTERVAL {
+            return None;
+        }
+        state.deposits.last_sweep = now;
+        let tokens: Vec<_> = state
+            .tokens
+            .0
+            .iter()
+            .filter(|(_, t)| t.listed && t.standard != TokenStandard::DIP20)
+            .map(|(k, t)| (*k, t.clone()))
+            .collect();
+        Some((state.deposits.next_batch(SWEEP_BATCH), tokens))
+    });
+    let (batch, tokens) = match sweep {
+        Some(sweep) => sweep,
+        None => return,
+    };
+
+    let exchange = ic_cdk::api::id();
+    for (owner, index) in batch {
+        for (token_canister_id, token) in &tokens {
+            let _ = deposit_from_account(owner, *token_canister_id, token, index, exchange).await;
+        }
+    }
+}
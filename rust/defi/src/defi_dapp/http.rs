@@ -0,0 +1,462 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use candid::Principal;
+use ic_certified_map::{AsHashTree, Hash, RbTree};
+use serde::Serialize;
+use serde_cbor::Serializer;
+use sha2::{Digest, Sha256};
+
+use crate::statement::{page, statement, to_csv, MAX_PAGE_SIZE};
+use crate::types::*;
+use crate::utils::nat_to_u128;
+use crate::State;
+
+// How many price levels and trades of each pair are served, and how often
+// they are certified again, in nanoseconds.
+const DEPTH_LEVELS: usize = 20;
+const RECENT_TRADES: usize = 50;
+const MARKET_DATA_INTERVAL: u64 = 5_000_000_000;
+// How long a statement token can be used, in nanoseconds.
+const STATEMENT_TOKEN_LIFETIME: u64 = 5 * 60 * 1_000_000_000;
+
+thread_local! {
+    // The market data served over HTTP, by path. Only updates can certify data,
+    // so it is built by the heartbeat rather than on each request.
+    static MARKET_DATA: RefCell<MarketData> = RefCell::new(MarketData {
+        bodies: HashMap::new(),
+        hashes: RbTree::new(),
+        certified_at: None,
+    });
+    // The owner of each statement token, and when the token expires. Tokens
+    // are not kept across upgrades.
+    static STATEMENT_TOKENS: RefCell<HashMap<String, (Principal, u64)>> =
+        RefCell::new(HashMap::new());
+}
+
+struct MarketData {
+    bodies: HashMap<String, Vec<u8>>,
+    hashes: RbTree<String, Hash>,
+    certified_at: Option<u64>,
+}
+
+// Serves the certified market data as JSON:
+// - `GET /pairs`: the pairs of listed tokens with their tickers,
+// - `GET /depth/<base>/<quote>`: the order book of a pair,
+// - `GET /trades/<base>/<quote>`: the latest trades of a pair, newest first,
+// with the pairs in the order `/pairs` lists them. It also serves
+// `GET /statement?token=<token>&start=<ns>&end=<ns>&skip=<n>&limit=<n>` as a
+// CSV download, paged like `getStatement`. All but the token are optional. If
+// there are more lines, the `X-Statement-Next` header holds the `start` and
+// `skip` of the next page. Gateways call anonymously, so the statement is of
+// the principal that was issued the token by `issueStatementToken`.
+pub fn handle(state: &State, request: &HttpRequest, now: u64) -> HttpResponse {
+    if request.method != "GET" {
+        return response(405, "text/plain", b"Method not allowed".to_vec());
+    }
+    let (path, query) = match request.url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.url.as_str(), ""),
+    };
+    if path == "/statement" {
+        return statement_csv(state, query, now);
+    }
+    MARKET_DATA.with(|m| {
+        let market_data = m.borrow();
+        let body = match market_data.bodies.get(path) {
+            Some(body) => body.clone(),
+            None => return response(404, "text/plain", b"Not found".to_vec()),
+        };
+        let mut response = response(200, "application/json", body);
+        if let Some(certificate) = ic_cdk::api::data_certificate() {
+            response.headers.push((
+                "IC-Certificate".to_string(),
+                format!(
+                    "certificate=:{}:, tree=:{}:",
+                    base64::encode(certificate),
+                    witness(&market_data.hashes, path)
+                ),
+            ));
+        }
+        response
+    })
+}
+
+// Builds the market data again and certifies it, unless that was done lately.
+pub fn refresh_market_data(state: &State, now: u64) {
+    let due = MARKET_DATA.with(|m| {
+        m.borrow()
+            .certified_at
+            .is_none_or(|t| now >= t + MARKET_DATA_INTERVAL)
+    });
+    if !due {
+        return;
+    }
+    let mut hashes = RbTree::new();
+    let mut bodies = HashMap::new();
+    for (path, body) in market_data(state, now) {
+        hashes.insert(path.clone(), Sha256::digest(&body).into());
+        bodies.insert(path, body);
+    }
+    ic_cdk::api::set_certified_data(&ic_certified_map::labeled_hash(
+        b"http_assets",
+        &hashes.root_hash(),
+    ));
+    MARKET_DATA.with(|m| {
+        *m.borrow_mut() = MarketData {
+            bodies,
+            hashes,
+            certified_at: Some(now),
+        }
+    });
+}
+
+fn witness(hashes: &RbTree<String, Hash>, path: &str) -> String {
+    let tree = ic_certified_map::labeled(b"http_assets", hashes.witness(path.as_bytes()));
+    let mut data = vec![];
+    let mut serializer = Serializer::new(&mut data);
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    base64::encode(data)
+}
+
+// Amounts are strings, as they may not fit into JSON numbers.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PairData {
+    base: String,
+    quote: String,
+    trading: bool,
+    last_price: Option<f64>,
+    volume: String,
+    quote_volume: String,
+}
+
+#[derive(Serialize)]
+struct LevelData {
+    price: f64,
+    amount: String,
+    orders: u32,
+}
+
+#[derive(Serialize)]
+struct DepthData {
+    bids: Vec<LevelData>,
+    asks: Vec<LevelData>,
+}
+
+// `side` is that of the taker, who buys the base token or sells it.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TradeData {
+    id: TradeId,
+    timestamp: u64,
+    side: &'static str,
+    price: Option<f64>,
+    amount: String,
+    quote_amount: String,
+}
+
+fn levels(levels: &[PriceLevel]) -> Vec<LevelData> {
+    levels
+        .iter()
+        .map(|l| LevelData {
+            price: l.price,
+            amount: nat_to_u128(l.amount.clone()).to_string(),
+            orders: l.orders,
+        })
+        .collect()
+}
+
+fn json<T: Serialize>(value: &T) -> Vec<u8> {
+    serde_json::to_vec(value).unwrap()
+}
+
+fn market_data(state: &State, now: u64) -> Vec<(String, Vec<u8>)> {
+    let exchange = &state.exchange;
+    let pairs: Vec<(Principal, Principal)> = state
+        .tokens
+        .tradable_pairs()
+        .iter()
+        .map(|p| (p.tokenA, p.tokenB))
+        .collect();
+
+    let mut data = Vec::new();
+    let pair_data: Vec<PairData> = pairs
+        .iter()
+        .map(|(base, quote)| {
+            let ticker = exchange.get_ticker(*base, *quote, now);
+            PairData {
+                base: base.to_string(),
+                quote: quote.to_string(),
+                trading: state.admin.is_trading(*base, *quote),
+                last_price: ticker.lastPrice,
+                volume: nat_to_u128(ticker.volume).to_string(),
+                quote_volume: nat_to_u128(ticker.quoteVolume).to_string(),
+            }
+        })
+        .collect();
+    data.push(("/pairs".to_string(), json(&pair_data)));
+
+    let depths = exchange.get_depths(&pairs, DEPTH_LEVELS, now);
+    for ((base, quote), depth) in pairs.into_iter().zip(depths) {
+        data.push((
+            format!("/depth/{}/{}", base, quote),
+            json(&DepthData {
+                bids: levels(&depth.bids),
+                asks: levels(&depth.asks),
+            }),
+        ));
+
+        let trades: Vec<TradeData> = exchange
+            .trades
+            .recent_pair_trades(base, quote, RECENT_TRADES)
+            .filter_map(|t| {
+                let (amount, quote_amount) = t.base_quote_amounts(&base)?;
+                Some(TradeData {
+                    id: t.id,
+                    timestamp: t.timestamp,
+                    side: if t.taker_token_canister_id == quote {
+                        "buy"
+                    } else {
+                        "sell"
+                    },
+                    price: t.price(&base),
+                    amount: amount.to_string(),
+                    quote_amount: quote_amount.to_string(),
+                })
+            })
+            .collect();
+        data.push((format!("/trades/{}/{}", base, quote), json(&trades)));
+    }
+    data
+}
+
+// Issues a token that lets anyone who has it download the statement of `owner`
+// for a short while. `random` makes it unguessable.
+pub fn issue_statement_token(owner: Principal, random: &[u8], now: u64) -> String {
+    let token: String = random.iter().map(|b| format!("{:02x}", b)).collect();
+    STATEMENT_TOKENS.with(|t| {
+        let mut tokens = t.borrow_mut();
+        tokens.retain(|_, (_, expires_at)| *expires_at > now);
+        tokens.insert(token.clone(), (owner, now + STATEMENT_TOKEN_LIFETIME));
+    });
+    token
+}
+
+fn statement_owner(token: &str, now: u64) -> Option<Principal> {
+    STATEMENT_TOKENS.with(|t| {
+        t.borrow()
+            .get(token)
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(owner, _)| *owner)
+    })
+}
+
+fn statement_csv(state: &State, query: &str, now: u64) -> HttpResponse {
+    let owner = match param(query, "token").and_then(|t| statement_owner(t, now)) {
+        Some(owner) => owner,
+        None => return response(403, "text/plain", b"Invalid statement token".to_vec()),
+    };
+    let start = param(query, "start").map_or(Ok(0), str::parse);
+    let end = param(query, "end").map_or(Ok(u64::MAX), str::parse);
+    let skip = param(query, "skip").map_or(Ok(0), str::parse);
+    let limit = param(query, "limit").map_or(Ok(MAX_PAGE_SIZE), str::parse);
+    let (start, end, skip, limit) = match (start, end, skip, limit) {
+        (Ok(start), Ok(end), Ok(skip), Ok(limit)) => (start, end, skip, limit),
+        _ => return response(400, "text/plain", b"Invalid statement query".to_vec()),
+    };
+
+    let lines = statement(
+        &state.journal,
+        &state.exchange.trades,
+        &state.exchange.pool_changes,
+        &owner,
+        start,
+        end,
+    );
+    let (lines, next) = page(&lines, start, skip, limit);
+    let mut response = response(200, "text/csv", to_csv(lines).into_bytes());
+    response.headers.push((
+        "Content-Disposition".to_string(),
+        format!("attachment; filename=\"statement-{}.csv\"", owner),
+    ));
+    if let Some(next) = next {
+        response.headers.push((
+            "X-Statement-Next".to_string(),
+            format!("start={}&skip={}", next.start, next.skip),
+        ));
+    }
+    response
+}
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|p| p.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn response(status_code: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), content_type.to_string())],
+        body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exchange::OrderState;
+    use crate::test_utils::principal;
+    use crate::tokens::TokenState;
+    use crate::trades::TradeState;
+    use crate::SavedState;
+    use ic_stable_structures::memory_manager::MemoryManager;
+    use ic_stable_structures::DefaultMemoryImpl;
+    use serde_json::{json, Value};
+
+    const USER: u8 = 1;
+    const BASE: u8 = 2;
+    const QUOTE: u8 = 3;
+
+    fn body(data: &[(String, Vec<u8>)], path: &str) -> Value {
+        let (_, body) = data.iter().find(|(p, _)| p == path).unwrap();
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[test]
+    fn market_data_is_served_per_pair() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut state = State::init(&memory_manager, SavedState::default());
+        for token in [BASE, QUOTE].iter() {
+            state.tokens.list(
+                principal(*token),
+                TokenState {
+                    standard: TokenStandard::ICRC2,
+                    symbol: "T".to_string(),
+                    decimals: 8,
+                    fee: 10,
+                    listed: true,
+                },
+            );
+        }
+        state.exchange.orders.insert(
+            1,
+            OrderState {
+                id: 1,
+                owner: principal(USER),
+                from_token_canister_id: principal(BASE),
+                from_amount: 100,
+                to_token_canister_id: principal(QUOTE),
+                to_amount: 250,
+                expires_at: None,
+            },
+        );
+        state.exchange.trades.record(TradeState {
+            id: 0,
+            timestamp: 5,
+            taker_order_id: 2,
+            taker: principal(USER),
+            taker_token_canister_id: principal(QUOTE),
+            taker_amount: 200,
+            taker_fee: 0,
+            maker_order_id: 3,
+            maker: principal(USER),
+            maker_token_canister_id: principal(BASE),
+            maker_amount: 100,
+            maker_fee: 0,
+        });
+
+        let data = market_data(&state, 10);
+        let (base, quote) = (principal(BASE).to_string(), principal(QUOTE).to_string());
+        assert_eq!(
+            body(&data, "/pairs"),
+            json!([{
+                "base": base,
+                "quote": quote,
+                "trading": true,
+                "lastPrice": 2.0,
+                "volume": "100",
+                "quoteVolume": "200",
+            }])
+        );
+        assert_eq!(
+            body(&data, &format!("/depth/{}/{}", base, quote)),
+            json!({"bids": [], "asks": [{"price": 2.5, "amount": "100", "orders": 1}]})
+        );
+        assert_eq!(
+            body(&data, &format!("/trades/{}/{}", base, quote)),
+            json!([{
+                "id": 0,
+                "timestamp": 5,
+                "side": "buy",
+                "price": 2.0,
+                "amount": "100",
+                "quoteAmount": "200",
+            }])
+        );
+    }
+
+    #[test]
+    fn statements_need_a_token_of_the_owner() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut state = State::init(&memory_manager, SavedState::default());
+        let deposit = state.journal.begin(
+            OperationKind::Deposit,
+            principal(USER),
+            principal(BASE),
+            1_000,
+            10,
+            None,
+            1,
+        );
+        state.journal.settle(deposit.id, true, 2);
+        let withdrawal = state.journal.begin(
+            OperationKind::Withdraw,
+            principal(USER),
+            principal(BASE),
+            100,
+            10,
+            None,
+            3,
+        );
+        state.journal.settle(withdrawal.id, true, 4);
+        let get = |url: String, now| {
+            let request = HttpRequest {
+                method: "GET".to_string(),
+                url,
+                headers: vec![],
+                body: vec![],
+            };
+            handle(&state, &request, now)
+        };
+
+        let token = issue_statement_token(principal(USER), &[0xab, 0x01], 10);
+        assert_eq!(token, "ab01");
+        let response = get(format!("/statement?token={}&start=0&limit=1", token), 11);
+        assert_eq!(response.status_code, 200);
+        assert!(response
+            .headers
+            .contains(&("X-Statement-Next".to_string(), "start=4&skip=0".to_string())));
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            format!(
+                "timestamp,kind,id,token,amount,balance\n2,deposit,{},{},1000,1000\n",
+                deposit.id,
+                principal(BASE)
+            )
+        );
+
+        assert_eq!(get("/statement".to_string(), 11).status_code, 403);
+        assert_eq!(
+            get("/statement?token=ab02".to_string(), 11).status_code,
+            403
+        );
+        let expired = 10 + STATEMENT_TOKEN_LIFETIME;
+        let url = format!("/statement?token={}", token);
+        assert_eq!(get(url, expired).status_code, 403);
+    }
+}
@@ -0,0 +1,73 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::call::CallResult;
+use ic_ledger_types::{AccountIdentifier, Tokens};
+
+use crate::utils;
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct GetBlocksArgs {
+    pub start: u64,
+    pub length: u64,
+}
+
+// The fields of the ledger's reply that the exchange reads. Archived blocks are
+// not looked up.
+#[derive(CandidType, Debug, Deserialize)]
+pub struct QueryBlocksResponse {
+    pub first_block_index: u64,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct Block {
+    pub transaction: Transaction,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct Transaction {
+    pub operation: Option<Operation>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub enum Operation {
+    Mint {
+        to: AccountIdentifier,
+        amount: Tokens,
+    },
+    Burn {
+        from: AccountIdentifier,
+        amount: Tokens,
+    },
+    Transfer {
+        from: AccountIdentifier,
+        to: AccountIdentifier,
+        amount: Tokens,
+        fee: Tokens,
+    },
+}
+
+// The destination of the transfer in block `index` of the ledger, or None if the
+// block is not a transfer or the ledger does not hold it.
+pub async fn transfer_destination(
+    ledger_canister_id: Principal,
+    index: u64,
+) -> CallResult<Option<AccountIdentifier>> {
+    let args = GetBlocksArgs {
+        start: index,
+        length: 1,
+    };
+    let (response,): (QueryBlocksResponse,) =
+        utils::call(ledger_canister_id, "query_blocks", (args,)).await?;
+
+    if response.first_block_index != index {
+        return Ok(None);
+    }
+    Ok(response
+        .blocks
+        .into_iter()
+        .next()
+        .and_then(|block| match block.transaction.operation {
+            Some(Operation::Transfer { to, .. }) => Some(to),
+            _ => None,
+        }))
+}
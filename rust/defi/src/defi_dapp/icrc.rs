@@ -0,0 +1,448 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::call::RejectionCode;
+use serde::Serialize;
+
+use crate::journal::TransferOutcome;
+use crate::types::{DepositErr, WithdrawErr};
+use crate::utils;
+
+pub struct Icrc {
+    principal: Principal,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+impl Account {
+    pub fn new(owner: Principal, subaccount: Option<Vec<u8>>) -> Self {
+        Account { owner, subaccount }
+    }
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize, PartialEq)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct GetTransactionsRequest {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+// The fields of the ledger's reply that the exchange reads. Archived
+// transactions are not looked up.
+#[derive(CandidType, Debug, Deserialize)]
+pub struct GetTransactionsResponse {
+    pub first_index: Nat,
+    pub transactions: Vec<Transaction>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct Transaction {
+    pub kind: String,
+    pub transfer: Option<Transfer>,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct Transfer {
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+}
+
+#[derive(Debug)]
+pub enum IcrcError {
+    // The ledger could not be called, or its reply could not be decoded.
+    Call(RejectionCode, String),
+    Transfer(TransferError),
+    TransferFrom(TransferFromError),
+}
+pub type IcrcResult<T> = Result<T, IcrcError>;
+
+impl IcrcError {
+    // Transfers are sent with a creation time, so the ledger reports a transfer
+    // that is sent again as a duplicate until it is too old to be deduplicated.
+    pub fn outcome(&self) -> TransferOutcome {
+        match self {
+            IcrcError::Call(RejectionCode::Unknown, _)
+            | IcrcError::Transfer(TransferError::TooOld)
+            | IcrcError::TransferFrom(TransferFromError::TooOld) => TransferOutcome::Unknown,
+            IcrcError::Transfer(TransferError::Duplicate { .. })
+            | IcrcError::TransferFrom(TransferFromError::Duplicate { .. }) => {
+                TransferOutcome::Executed
+            }
+            _ => TransferOutcome::Failed,
+        }
+    }
+}
+
+impl From<(RejectionCode, String)> for IcrcError {
+    fn from((code, msg): (RejectionCode, String)) -> IcrcError {
+        IcrcError::Call(code, msg)
+    }
+}
+
+impl From<IcrcError> for DepositErr {
+    fn from(e: IcrcError) -> DepositErr {
+        match e {
+            IcrcError::Call(..) => DepositErr::CallFailure,
+            IcrcError::Transfer(TransferError::InsufficientFunds { .. })
+            | IcrcError::TransferFrom(TransferFromError::InsufficientFunds { .. })
+            | IcrcError::TransferFrom(TransferFromError::InsufficientAllowance { .. }) => {
+                DepositErr::BalanceLow
+            }
+            IcrcError::Transfer(_) | IcrcError::TransferFrom(_) => DepositErr::TransferFailure,
+        }
+    }
+}
+
+impl From<IcrcError> for WithdrawErr {
+    fn from(e: IcrcError) -> WithdrawErr {
+        match e {
+            IcrcError::Call(..) => WithdrawErr::CallFailure,
+            IcrcError::Transfer(_) | IcrcError::TransferFrom(_) => WithdrawErr::TransferFailure,
+        }
+    }
+}
+
+// The ICRC-1 and ICRC-2 ledger methods used by the exchange. Transfers are made
+// from the calling canister's accounts.
+pub trait IcrcClient {
+    async fn balance_of(&self, account: Account) -> IcrcResult<Nat>;
+    async fn decimals(&self) -> IcrcResult<u8>;
+    async fn fee(&self) -> IcrcResult<Nat>;
+    async fn symbol(&self) -> IcrcResult<String>;
+    async fn transfer(&self, args: TransferArg) -> IcrcResult<Nat>;
+    async fn transfer_from(&self, args: TransferFromArgs) -> IcrcResult<Nat>;
+    async fn allowance(&self, account: Account, spender: Account) -> IcrcResult<Nat>;
+    // The transaction at `index`, or None if the ledger does not hold it.
+    async fn transaction(&self, index: Nat) -> IcrcResult<Option<Transaction>>;
+}
+
+impl Icrc {
+    pub fn new(principal: Principal) -> Self {
+        Icrc { principal }
+    }
+}
+
+impl IcrcClient for Icrc {
+    async fn balance_of(&self, account: Account) -> IcrcResult<Nat> {
+        let (balance,): (Nat,) =
+            utils::call(self.principal, "icrc1_balance_of", (account,)).await?;
+
+        Ok(balance)
+    }
+
+    async fn decimals(&self) -> IcrcResult<u8> {
+        let (decimals,): (u8,) = utils::call(self.principal, "icrc1_decimals", ()).await?;
+
+        Ok(decimals)
+    }
+
+    async fn fee(&self) -> IcrcResult<Nat> {
+        let (fee,): (Nat,) = utils::call(self.principal, "icrc1_fee", ()).await?;
+
+        Ok(fee)
+    }
+
+    async fn symbol(&self) -> IcrcResult<String> {
+        let (symbol,): (String,) = utils::call(self.principal, "icrc1_symbol", ()).await?;
+
+        Ok(symbol)
+    }
+
+    async fn transfer(&self, args: TransferArg) -> IcrcResult<Nat> {
+        let (receipt,): (Result<Nat, TransferError>,) =
+            utils::call(self.principal, "icrc1_transfer", (args,)).await?;
+
+        receipt.map_err(IcrcError::Transfer)
+    }
+
+    async fn transfer_from(&self, args: TransferFromArgs) -> IcrcResult<Nat> {
+        let (receipt,): (Result<Nat, TransferFromError>,) =
+            utils::call(self.principal, "icrc2_transfer_from", (args,)).await?;
+
+        receipt.map_err(IcrcError::TransferFrom)
+    }
+
+    async fn allowance(&self, account: Account, spender: Account) -> IcrcResult<Nat> {
+        let args = AllowanceArgs { account, spender };
+        let (allowance,): (Allowance,) =
+            utils::call(self.principal, "icrc2_allowance", (args,)).await?;
+
+        Ok(allowance.allowance)
+    }
+
+    async fn transaction(&self, index: Nat) -> IcrcResult<Option<Transaction>> {
+        let args = GetTransactionsRequest {
+            start: index.clone(),
+            length: 1u32.into(),
+        };
+        let (response,): (GetTransactionsResponse,) =
+            utils::call(self.principal, "get_transactions", (args,)).await?;
+
+        if response.first_index != index {
+            return Ok(None);
+        }
+        Ok(response.transactions.into_iter().next())
+    }
+}
+
+// An in-memory ICRC-2 ledger that deduplicates transfers by memo and creation
+// time. Every call can be made to be rejected, like a ledger that is stopped or traps.
+#[cfg(test)]
+pub struct MockIcrc {
+    pub fee: u128,
+    pub transactions: std::cell::RefCell<std::collections::HashSet<(Option<Vec<u8>>, u64)>>,
+    pub balances: std::cell::RefCell<std::collections::HashMap<Account, u128>>,
+    pub allowances: std::cell::RefCell<std::collections::HashMap<(Account, Account), u128>>,
+    pub log: std::cell::RefCell<Vec<Transaction>>,
+    pub reject_calls: std::cell::Cell<bool>,
+    // The principal that transfers are made from, i.e. the exchange canister.
+    pub caller: Principal,
+}
+
+#[cfg(test)]
+impl MockIcrc {
+    pub fn new(caller: Principal, fee: u128) -> Self {
+        MockIcrc {
+            fee,
+            transactions: Default::default(),
+            balances: Default::default(),
+            allowances: Default::default(),
+            log: Default::default(),
+            reject_calls: std::cell::Cell::new(false),
+            caller,
+        }
+    }
+
+    fn is_duplicate(&self, memo: &Option<Vec<u8>>, created_at_time: Option<u64>) -> bool {
+        created_at_time
+            .is_some_and(|time| self.transactions.borrow().contains(&(memo.clone(), time)))
+    }
+
+    fn record(&self, memo: Option<Vec<u8>>, created_at_time: Option<u64>) {
+        if let Some(time) = created_at_time {
+            self.transactions.borrow_mut().insert((memo, time));
+        }
+    }
+
+    pub fn balance(&self, account: &Account) -> u128 {
+        self.balances.borrow().get(account).copied().unwrap_or(0)
+    }
+
+    pub fn approve(&self, account: Account, spender: Account, amount: u128) {
+        self.allowances
+            .borrow_mut()
+            .insert((account, spender), amount);
+    }
+
+    pub fn mint(&self, account: Account, amount: u128) {
+        self.credit(account, amount);
+        self.log.borrow_mut().push(Transaction {
+            kind: "mint".to_string(),
+            transfer: None,
+            timestamp: 0,
+        });
+    }
+
+    // A transfer made by another principal than the exchange. Returns its index.
+    pub fn send(&self, from: Account, to: Account, amount: u128) -> u64 {
+        self.move_tokens(from, to, amount).unwrap();
+        self.log.borrow().len() as u64 - 1
+    }
+
+    fn credit(&self, account: Account, amount: u128) {
+        *self.balances.borrow_mut().entry(account).or_default() += amount;
+    }
+
+    fn check_rejected(&self) -> IcrcResult<()> {
+        if self.reject_calls.get() {
+            Err(IcrcError::Call(
+                RejectionCode::CanisterError,
+                "canister trapped".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Moves `amount` and burns the fee on top of it. Returns the source balance if
+    // it is too low.
+    fn move_tokens(&self, from: Account, to: Account, amount: u128) -> Result<(), u128> {
+        let balance = self.balance(&from);
+        if balance < amount + self.fee {
+            return Err(balance);
+        }
+        self.balances
+            .borrow_mut()
+            .insert(from.clone(), balance - amount - self.fee);
+        self.credit(to.clone(), amount);
+        self.log.borrow_mut().push(Transaction {
+            kind: "transfer".to_string(),
+            transfer: Some(Transfer {
+                from,
+                to,
+                amount: amount.into(),
+            }),
+            timestamp: 0,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl IcrcClient for MockIcrc {
+    async fn balance_of(&self, account: Account) -> IcrcResult<Nat> {
+        self.check_rejected()?;
+        Ok(self.balance(&account).into())
+    }
+
+    async fn decimals(&self) -> IcrcResult<u8> {
+        self.check_rejected()?;
+        Ok(8)
+    }
+
+    async fn fee(&self) -> IcrcResult<Nat> {
+        self.check_rejected()?;
+        Ok(self.fee.into())
+    }
+
+    async fn symbol(&self) -> IcrcResult<String> {
+        self.check_rejected()?;
+        Ok("MOCK".to_string())
+    }
+
+    async fn transfer(&self, args: TransferArg) -> IcrcResult<Nat> {
+        self.check_rejected()?;
+        if args.fee.is_some_and(|fee| fee != self.fee) {
+            return Err(IcrcError::Transfer(TransferError::BadFee {
+                expected_fee: self.fee.into(),
+            }));
+        }
+        if self.is_duplicate(&args.memo, args.created_at_time) {
+            return Err(IcrcError::Transfer(TransferError::Duplicate {
+                duplicate_of: 0u32.into(),
+            }));
+        }
+        let from = Account::new(self.caller, args.from_subaccount);
+        let amount = utils::nat_to_u128(args.amount);
+        self.move_tokens(from, args.to, amount).map_err(|balance| {
+            IcrcError::Transfer(TransferError::InsufficientFunds {
+                balance: balance.into(),
+            })
+        })?;
+        self.record(args.memo, args.created_at_time);
+        Ok(0u32.into())
+    }
+
+    async fn transfer_from(&self, args: TransferFromArgs) -> IcrcResult<Nat> {
+        self.check_rejected()?;
+        if args.fee.is_some_and(|fee| fee != self.fee) {
+            return Err(IcrcError::TransferFrom(TransferFromError::BadFee {
+                expected_fee: self.fee.into(),
+            }));
+        }
+        if self.is_duplicate(&args.memo, args.created_at_time) {
+            return Err(IcrcError::TransferFrom(TransferFromError::Duplicate {
+                duplicate_of: 0u32.into(),
+            }));
+        }
+        let (from, to) = (args.from, args.to);
+        let amount = utils::nat_to_u128(args.amount);
+        let key = (from.clone(), Account::new(self.caller, None));
+        let allowance = self.allowances.borrow().get(&key).copied().unwrap_or(0);
+        if allowance < amount + self.fee {
+            return Err(IcrcError::TransferFrom(
+                TransferFromError::InsufficientAllowance {
+                    allowance: allowance.into(),
+                },
+            ));
+        }
+        self.move_tokens(from, to, amount).map_err(|balance| {
+            IcrcError::TransferFrom(TransferFromError::InsufficientFunds {
+                balance: balance.into(),
+            })
+        })?;
+        self.allowances
+            .borrow_mut()
+            .insert(key, allowance - amount - self.fee);
+        self.record(args.memo, args.created_at_time);
+        Ok(0u32.into())
+    }
+
+    async fn allowance(&self, account: Account, spender: Account) -> IcrcResult<Nat> {
+        self.check_rejected()?;
+        Ok(self
+            .allowances
+            .borrow()
+            .get(&(account, spender))
+            .copied()
+            .unwrap_or(0)
+            .into())
+    }
+
+    async fn transaction(&self, index: Nat) -> IcrcResult<Option<Transaction>> {
+        self.check_rejected()?;
+        let index = utils::nat_to_u128(index) as usize;
+        Ok(self.log.borrow().get(index).cloned())
+    }
+}
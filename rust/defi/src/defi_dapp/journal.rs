@@ -0,0 +1,345 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::call::RejectionCode;
+use ic_stable_structures::memory_manager::MemoryManager;
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::dip20::DIP20Error;
+use crate::icrc::{Account, IcrcError};
+use crate::stable::{principal_key, Memory, PrincipalKey, SETTLED, SETTLED_BY_OWNER};
+use crate::types::*;
+
+// What is known about a transfer sent to a token canister.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransferOutcome {
+    Executed,
+    // The token canister did not execute the transfer.
+    Failed,
+    // The transfer may or may not have been executed.
+    Unknown,
+}
+
+// The error of a transfer through any token standard.
+#[derive(Debug)]
+pub enum TransferErr {
+    IcpCall(RejectionCode, String),
+    Icp(ic_ledger_types::TransferError),
+    DIP20(DIP20Error),
+    Icrc(IcrcError),
+}
+
+impl TransferErr {
+    pub fn outcome(&self) -> TransferOutcome {
+        match self {
+            TransferErr::IcpCall(RejectionCode::Unknown, _)
+            | TransferErr::Icp(ic_ledger_types::TransferError::TxTooOld { .. }) => {
+                TransferOutcome::Unknown
+            }
+            TransferErr::Icp(ic_ledger_types::TransferError::TxDuplicate { .. }) => {
+                TransferOutcome::Executed
+            }
+            TransferErr::IcpCall(..) | TransferErr::Icp(_) => TransferOutcome::Failed,
+            TransferErr::DIP20(e) => e.outcome(),
+            TransferErr::Icrc(e) => e.outcome(),
+        }
+    }
+}
+
+impl From<TransferErr> for DepositErr {
+    fn from(e: TransferErr) -> DepositErr {
+        match e {
+            TransferErr::IcpCall(..) => DepositErr::CallFailure,
+            TransferErr::Icp(ic_ledger_types::TransferError::InsufficientFunds { .. }) => {
+                DepositErr::BalanceLow
+            }
+            TransferErr::Icp(_) => DepositErr::TransferFailure,
+            TransferErr::DIP20(e) => e.into(),
+            TransferErr::Icrc(e) => e.into(),
+        }
+    }
+}
+
+impl From<TransferErr> for WithdrawErr {
+    fn from(e: TransferErr) -> WithdrawErr {
+        match e {
+            TransferErr::IcpCall(..) => WithdrawErr::CallFailure,
+            TransferErr::Icp(_) => WithdrawErr::TransferFailure,
+            TransferErr::DIP20(e) => e.into(),
+            TransferErr::Icrc(e) => e.into(),
+        }
+    }
+}
+
+// A deposit or withdrawal that is recorded before its transfer is sent. The
+// transfer is sent with the operation id as memo and `created_at` as creation
+// time, so that sending it again can not execute it twice on ledgers that
+// deduplicate transactions.
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct OperationState {
+    pub id: OperationId,
+    pub kind: OperationKind,
+    pub owner: Principal,
+    pub token: Principal,
+    // The exchange balance credited by a deposit, or the amount paid out by a withdrawal.
+    pub amount: u128,
+    pub fee: u128,
+    // The destination of a withdrawal, or the deposit account a deposit is swept from.
+    pub account: Option<Account>,
+    pub created_at: u64,
+    pub status: OperationStatus,
+    pub settled_at: Option<u64>,
+}
+
+impl From<&OperationState> for Operation {
+    fn from(o: &OperationState) -> Operation {
+        Operation {
+            id: o.id,
+            kind: o.kind,
+            owner: o.owner,
+            token: o.token,
+            amount: o.amount.into(),
+            fee: o.fee.into(),
+            account: o.account.clone(),
+            createdAt: o.created_at,
+            status: o.status,
+            settledAt: o.settled_at,
+        }
+    }
+}
+
+// Operations are encoded with candid like orders. This bounds their encoding
+// with room to spare.
+const MAX_OPERATION_SIZE: u32 = 1024;
+
+impl Storable for OperationState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), OperationState).unwrap()
+    }
+}
+
+impl BoundedStorable for OperationState {
+    const MAX_SIZE: u32 = MAX_OPERATION_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Pending operations, and settled ones. Settled operations only grow, so they
+// are kept in stable memory with an index per owner.
+pub struct Journal {
+    next_id: OperationId,
+    pending: BTreeMap<OperationId, OperationState>,
+    settled: StableBTreeMap<OperationId, OperationState, Memory>,
+    settled_by_owner: StableBTreeMap<(PrincipalKey, OperationId), (), Memory>,
+}
+
+// What is saved on upgrades.
+#[derive(CandidType, Deserialize, Default)]
+pub struct SavedJournal {
+    pub next_id: OperationId,
+    pub pending: BTreeMap<OperationId, OperationState>,
+    // Earlier versions saved settled operations on upgrades. They are moved
+    // into the stable map when restored.
+    pub settled: Vec<OperationState>,
+}
+
+impl Journal {
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>, saved: SavedJournal) -> Self {
+        let mut journal = Journal {
+            next_id: saved.next_id,
+            pending: saved.pending,
+            settled: StableBTreeMap::init(memory_manager.get(SETTLED)),
+            settled_by_owner: StableBTreeMap::init(memory_manager.get(SETTLED_BY_OWNER)),
+        };
+        for op in saved.settled {
+            journal.insert_settled(op);
+        }
+        journal
+    }
+
+    // Takes out what is saved on upgrades.
+    pub fn take_saved(&mut self) -> SavedJournal {
+        SavedJournal {
+            next_id: self.next_id,
+            pending: std::mem::take(&mut self.pending),
+            settled: Vec::new(),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn begin(
+        &mut self,
+        kind: OperationKind,
+        owner: Principal,
+        token: Principal,
+        amount: u128,
+        fee: u128,
+        account: Option<Account>,
+        now: u64,
+    ) -> OperationState {
+        let op = OperationState {
+            id: self.next_id,
+            kind,
+            owner,
+            token,
+            amount,
+            fee,
+            account,
+            created_at: now,
+            status: OperationStatus::Pending,
+            settled_at: None,
+        };
+        self.next_id += 1;
+        self.pending.insert(op.id, op.clone());
+        op
+    }
+
+    pub fn get(&self, id: OperationId) -> Option<OperationState> {
+        self.pending
+            .get(&id)
+            .cloned()
+            .or_else(|| self.settled.get(&id))
+    }
+
+    // The settled operations of `owner`, oldest first.
+    pub fn settled_of(&self, owner: &Principal) -> impl Iterator<Item = OperationState> + '_ {
+        let owner = principal_key(owner);
+        self.settled_by_owner
+            .range((owner, 0)..)
+            .take_while(move |((o, _), _)| *o == owner)
+            .filter_map(move |((_, id), _)| self.settled.get(&id))
+    }
+
+    pub fn has_pending(&self, owner: &Principal) -> bool {
+        self.pending.values().any(|o| o.owner == *owner)
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &OperationState> {
+        self.pending.values()
+    }
+
+    // Moves a pending operation to the settled ones. Returns None if it was not pending.
+    pub fn settle(&mut self, id: OperationId, executed: bool, now: u64) -> Option<OperationState> {
+        let mut op = self.pending.remove(&id)?;
+        op.status = if executed {
+            OperationStatus::Completed
+        } else {
+            OperationStatus::Failed
+        };
+        op.settled_at = Some(now);
+        self.insert_settled(op.clone());
+        Some(op)
+    }
+
+    fn insert_settled(&mut self, op: OperationState) {
+        self.settled_by_owner
+            .insert((principal_key(&op.owner), op.id), ());
+        self.settled.insert(op.id, op);
+    }
+}
+
+thread_local! {
+    static IN_FLIGHT: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+}
+
+// Held while a deposit or withdrawal of `owner` awaits token canisters, so that
+// calls of the same principal don't interleave. A trap after an await skips the
+// release, but then the operation stays pending and blocks the principal until
+// the owner resolves it, which releases the principal. The set is not kept
+// across upgrades.
+pub struct OperationGuard {
+    owner: Principal,
+}
+
+impl OperationGuard {
+    pub fn acquire(owner: Principal) -> Option<Self> {
+        IN_FLIGHT.with(|f| {
+            f.borrow_mut()
+                .insert(owner)
+                .then(|| OperationGuard { owner })
+        })
+    }
+
+    // Releases `owner` without the guard that was left behind.
+    pub fn release(owner: Principal) {
+        IN_FLIGHT.with(|f| f.borrow_mut().remove(&owner));
+    }
+}
+
+impl Drop for OperationGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|f| f.borrow_mut().remove(&self.owner));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+
+    fn journal() -> Journal {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        Journal::init(&memory_manager, SavedJournal::default())
+    }
+
+    fn begin(journal: &mut Journal, owner: u8) -> OperationId {
+        journal
+            .begin(
+                OperationKind::Withdraw,
+                principal(owner),
+                principal(9),
+                100,
+                10,
+                None,
+                1,
+            )
+            .id
+    }
+
+    #[test]
+    fn operations_are_settled_once() {
+        let mut journal = journal();
+        let first = begin(&mut journal, 1);
+        let second = begin(&mut journal, 2);
+        assert_ne!(first, second);
+        assert!(journal.has_pending(&principal(1)));
+
+        let settled = journal.settle(first, true, 5).unwrap();
+        assert!(settled.status == OperationStatus::Completed);
+        assert_eq!(settled.settled_at, Some(5));
+        assert!(journal.settle(first, false, 6).is_none());
+        assert!(!journal.has_pending(&principal(1)));
+
+        journal.settle(second, false, 7).unwrap();
+        assert_eq!(journal.settled_of(&principal(2)).count(), 1);
+        assert!(journal.get(second).unwrap().status == OperationStatus::Failed);
+        let settled: Vec<_> = journal.settled_of(&principal(1)).map(|o| o.id).collect();
+        assert_eq!(settled, vec![first]);
+        assert_eq!(journal.pending().count(), 0);
+
+        let mut op = journal.get(first).unwrap();
+        op.owner = Principal::from_slice(&[u8::MAX; 29]);
+        op.token = op.owner;
+        op.amount = u128::MAX;
+        op.account = Some(Account {
+            owner: op.owner,
+            subaccount: Some(vec![u8::MAX; 32]),
+        });
+        assert!(op.to_bytes().len() <= MAX_OPERATION_SIZE as usize);
+    }
+
+    #[test]
+    fn guard_excludes_concurrent_operations_of_a_principal() {
+        let guard = OperationGuard::acquire(principal(1)).unwrap();
+        assert!(OperationGuard::acquire(principal(1)).is_none());
+        assert!(OperationGuard::acquire(principal(2)).is_some());
+        drop(guard);
+        assert!(OperationGuard::acquire(principal(1)).is_some());
+    }
+}
@@ -5,107 +5,472 @@ use candid::{candid_method, export_service, CandidType, Nat, Principal};
 use ic_cdk::caller;
 use ic_cdk_macros::*;
 use ic_ledger_types::{
-    AccountIdentifier, Memo, Tokens, DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID,
+    AccountIdentifier, Memo, Subaccount, Timestamp, Tokens, DEFAULT_SUBACCOUNT,
+    MAINNET_LEDGER_CANISTER_ID,
 };
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 
+mod admin;
+mod auction;
+mod conditional;
+mod deposits;
 mod dip20;
+mod env;
 mod exchange;
+mod heartbeat;
+mod http;
+mod icp;
+mod icrc;
+mod journal;
+mod pool;
+mod stable;
+mod statement;
+#[cfg(test)]
+mod test_utils;
+mod tokens;
+mod trades;
 mod types;
 mod utils;
-use dip20::DIP20;
-use exchange::Exchange;
+use admin::AdminState;
+use deposits::DepositAccounts;
+use dip20::{DIP20Client, DIP20Error, DIP20};
+use exchange::{Exchange, SavedExchange};
+use ic_stable_structures::memory_manager::MemoryManager;
+use ic_stable_structures::DefaultMemoryImpl;
+use icrc::{Account, Icrc, IcrcClient, IcrcError, TransferArg, TransferFromArgs};
+use journal::{
+    Journal, OperationGuard, OperationState, SavedJournal, TransferErr, TransferOutcome,
+};
+use stable::MEMORY_MANAGER;
+use tokens::{TokenRegistry, TokenState};
 use types::*;
-use utils::{nat_to_u128, principal_to_subaccount};
+use utils::{deposit_subaccount, nat_to_u128};
 
 const ICP_FEE: u64 = 10_000;
 
 thread_local! {
-    static STATE: RefCell<State> = RefCell::new(State::default());
+    static STATE: RefCell<State> =
+        RefCell::new(MEMORY_MANAGER.with(|m| State::init(m, SavedState::default())));
 }
 
-#[derive(CandidType, Clone, Deserialize, Serialize, Default)]
 pub struct State {
     owner: Option<Principal>,
-    ledger: Option<Principal>,
+    admin: AdminState,
+    tokens: TokenRegistry,
+    deposits: DepositAccounts,
+    journal: Journal,
     exchange: Exchange,
 }
 
+// What is saved on upgrades. Balances, orders, trades and settled operations
+// stay in stable memory.
+#[derive(CandidType, Deserialize, Default)]
+pub struct SavedState {
+    owner: Option<Principal>,
+    admin: AdminState,
+    tokens: TokenRegistry,
+    deposits: DepositAccounts,
+    journal: SavedJournal,
+    exchange: SavedExchange,
+}
+
+impl State {
+    fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>, saved: SavedState) -> Self {
+        State {
+            owner: saved.owner,
+            admin: saved.admin,
+            tokens: saved.tokens,
+            deposits: saved.deposits,
+            journal: Journal::init(memory_manager, saved.journal),
+            exchange: Exchange::init(memory_manager, saved.exchange),
+        }
+    }
+
+    fn take_saved(&mut self) -> SavedState {
+        SavedState {
+            owner: self.owner,
+            admin: std::mem::take(&mut self.admin),
+            tokens: std::mem::take(&mut self.tokens),
+            deposits: std::mem::take(&mut self.deposits),
+            journal: self.journal.take_saved(),
+            exchange: self.exchange.take_saved(),
+        }
+    }
+}
+
+// The cached metadata of a token that was ever listed.
+fn get_token(token_canister_id: &Principal) -> Option<TokenState> {
+    STATE.with(|s| s.borrow().tokens.get(token_canister_id).cloned())
+}
+
+fn is_listed(token_canister_id: &Principal) -> bool {
+    STATE.with(|s| s.borrow().tokens.is_listed(token_canister_id))
+}
+
+// Trading is stopped in paused pairs, and everywhere in withdraw-only mode.
+fn is_trading(a: Principal, b: Principal) -> bool {
+    STATE.with(|s| s.borrow().admin.is_trading(a, b))
+}
+
+fn is_withdraw_only() -> bool {
+    STATE.with(|s| s.borrow().admin.withdraw_only)
+}
+
+// Admits one deposit or withdrawal per principal at a time, and none while the
+// principal has an operation that is not settled.
+fn begin_operation(owner: Principal) -> Option<OperationGuard> {
+    let guard = OperationGuard::acquire(owner)?;
+    if STATE.with(|s| s.borrow().journal.has_pending(&owner)) {
+        return None;
+    }
+    Some(guard)
+}
+
+// Settles a journaled operation with the outcome of its transfer: a deposit is
+// credited once it is executed, a withdrawal refunded once it failed. An
+// operation with an unknown outcome stays pending. Returns None if the
+// operation was not pending.
+fn settle_operation(id: OperationId, outcome: TransferOutcome, now: u64) -> Option<OperationState> {
+    let executed = match outcome {
+        TransferOutcome::Executed => true,
+        TransferOutcome::Failed => false,
+        TransferOutcome::Unknown => return None,
+    };
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let op = state.journal.settle(id, executed, now)?;
+        match (op.kind, executed) {
+            (OperationKind::Deposit, true) => state
+                .exchange
+                .balances
+                .add_balance(&op.owner, &op.token, op.amount),
+            (OperationKind::Withdraw, false) => {
+                state
+                    .exchange
+                    .balances
+                    .add_balance(&op.owner, &op.token, op.amount + op.fee)
+            }
+            _ => {}
+        }
+        Some(op)
+    })
+}
+
+fn transfer_outcome(result: &Result<(), TransferErr>) -> TransferOutcome {
+    match result {
+        Ok(()) => TransferOutcome::Executed,
+        Err(e) => e.outcome(),
+    }
+}
+
+// Sends the transfer of a journaled operation. Sending it again sends the same
+// transfer.
+async fn send_transfer(
+    op: &OperationState,
+    standard: TokenStandard,
+    exchange: Principal,
+) -> Result<(), TransferErr> {
+    match standard {
+        TokenStandard::ICP => send_icp_transfer(op, exchange).await,
+        TokenStandard::DIP20 => send_dip20_transfer(&DIP20::new(op.token), op, exchange)
+            .await
+            .map_err(TransferErr::DIP20),
+        TokenStandard::ICRC1 | TokenStandard::ICRC2 => {
+            send_icrc_transfer(&Icrc::new(op.token), op, standard, exchange)
+                .await
+                .map_err(TransferErr::Icrc)
+        }
+    }
+}
+
 #[update]
 #[candid_method(update)]
 pub async fn deposit(token_canister_id: Principal) -> DepositReceipt {
+    if is_withdraw_only() {
+        return Err(DepositErr::WithdrawOnly);
+    }
     let caller = caller();
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
+    let token = match get_token(&token_canister_id) {
+        Some(token) if token.listed => token,
+        _ => return Err(DepositErr::UnlistedToken),
+    };
+    let exchange = ic_cdk::api::id();
+    if matches!(token.standard, TokenStandard::ICP | TokenStandard::ICRC1) {
+        return deposit_from_account(caller, token_canister_id, &token, 0, exchange).await;
+    }
+    let _guard = begin_operation(caller).ok_or(DepositErr::OperationInProgress)?;
 
-    let amount = if token_canister_id == ledger_canister_id {
-        deposit_icp(caller).await?
-    } else {
-        deposit_token(caller, token_canister_id).await?
+    let fee = Nat::from(token.fee);
+    let amount = match token.standard {
+        TokenStandard::DIP20 => {
+            dip20_deposit_amount(&DIP20::new(token_canister_id), fee, caller, exchange).await?
+        }
+        _ => icrc2_deposit_amount(&Icrc::new(token_canister_id), fee, caller, exchange).await?,
     };
-    STATE.with(|s| {
-        s.borrow_mut()
-            .exchange
-            .balances
-            .add_balance(&caller, &token_canister_id, amount)
-    });
-    DepositReceipt::Ok(amount.into())
+
+    journal_deposit(caller, token_canister_id, &token, amount, None, exchange).await
 }
 
-async fn deposit_icp(caller: Principal) -> Result<u128, DepositErr> {
-    let canister_id = ic_cdk::api::id();
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
+// Sweeps a deposit account of `owner` into the exchange's main account. Tokens of
+// the ICRC2 standard can be deposited this way too.
+async fn deposit_from_account(
+    owner: Principal,
+    token_canister_id: Principal,
+    token: &TokenState,
+    index: DepositAccountIndex,
+    exchange: Principal,
+) -> DepositReceipt {
+    let _guard = begin_operation(owner).ok_or(DepositErr::OperationInProgress)?;
+
+    let account = deposit_account(exchange, owner, index);
+    let fee = Nat::from(token.fee);
+    let amount = match token.standard {
+        TokenStandard::ICP => icp_deposit_amount(token_canister_id, fee, &account).await?,
+        TokenStandard::ICRC1 | TokenStandard::ICRC2 => {
+            icrc1_deposit_amount(&Icrc::new(token_canister_id), fee, &account).await?
+        }
+        TokenStandard::DIP20 => return Err(DepositErr::TransferFailure),
+    };
+
+    journal_deposit(
+        owner,
+        token_canister_id,
+        token,
+        amount,
+        Some(account),
+        exchange,
+    )
+    .await
+}
+
+// Journals a deposit of `amount` and sends its transfer. `account` is the deposit
+// account it is swept from, or None for a deposit by approval.
+async fn journal_deposit(
+    owner: Principal,
+    token_canister_id: Principal,
+    token: &TokenState,
+    amount: u128,
+    account: Option<Account>,
+    exchange: Principal,
+) -> DepositReceipt {
+    let op = STATE.with(|s| {
+        s.borrow_mut().journal.begin(
+            OperationKind::Deposit,
+            owner,
+            token_canister_id,
+            amount,
+            token.fee,
+            account,
+            ic_cdk::api::time(),
+        )
+    });
+    let result = send_transfer(&op, token.standard, exchange).await;
+    let outcome = transfer_outcome(&result);
+    settle_operation(op.id, outcome, ic_cdk::api::time());
 
-    let account = AccountIdentifier::new(&canister_id, &principal_to_subaccount(&caller));
+    match result {
+        Err(e) if outcome != TransferOutcome::Executed => Err(e.into()),
+        _ => Ok(amount.into()),
+    }
+}
+
+// What was sent to a deposit account, less the ledger fee.
+async fn icp_deposit_amount(
+    ledger_canister_id: Principal,
+    fee: Nat,
+    account: &Account,
+) -> Result<u128, DepositErr> {
+    let account = icp_account_identifier(account);
 
     let balance_args = ic_ledger_types::AccountBalanceArgs { account };
     let balance = ic_ledger_types::account_balance(ledger_canister_id, balance_args)
         .await
-        .map_err(|_| DepositErr::TransferFailure)?;
+        .map_err(|_| DepositErr::CallFailure)?;
 
-    if balance.e8s() < ICP_FEE {
+    let balance = Nat::from(balance.e8s());
+    if balance <= fee {
         return Err(DepositErr::BalanceLow);
     }
 
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: Memo(0),
-        amount: balance - Tokens::from_e8s(ICP_FEE),
-        fee: Tokens::from_e8s(ICP_FEE),
-        from_subaccount: Some(principal_to_subaccount(&caller)),
-        to: AccountIdentifier::new(&canister_id, &DEFAULT_SUBACCOUNT),
-        created_at_time: None,
-    };
-    ic_ledger_types::transfer(ledger_canister_id, transfer_args)
-        .await
-        .map_err(|_| DepositErr::TransferFailure)?
-        .map_err(|_| DepositErr::TransferFailure)?;
+    Ok(nat_to_u128(balance - fee))
+}
+
+// Everything `caller` approved to the exchange, less the token's transfer fee.
+async fn dip20_deposit_amount(
+    token: &impl DIP20Client,
+    dip_fee: Nat,
+    caller: Principal,
+    exchange: Principal,
+) -> Result<u128, DepositErr> {
+    let allowance = token.allowance(caller, exchange).await?;
+    if allowance <= dip_fee {
+        return Err(DepositErr::BalanceLow);
+    }
+
+    Ok(nat_to_u128(allowance - dip_fee))
+}
+
+// What was sent to a deposit account, less the ledger fee.
+async fn icrc1_deposit_amount(
+    token: &impl IcrcClient,
+    fee: Nat,
+    account: &Account,
+) -> Result<u128, DepositErr> {
+    let balance = token.balance_of(account.clone()).await?;
+    if balance <= fee {
+        return Err(DepositErr::BalanceLow);
+    }
+
+    Ok(nat_to_u128(balance - fee))
+}
+
+// Everything `caller` approved to the exchange, less the ledger fee.
+async fn icrc2_deposit_amount(
+    token: &impl IcrcClient,
+    fee: Nat,
+    caller: Principal,
+    exchange: Principal,
+) -> Result<u128, DepositErr> {
+    let allowance = token
+        .allowance(Account::new(caller, None), Account::new(exchange, None))
+        .await?;
+    if allowance <= fee {
+        return Err(DepositErr::BalanceLow);
+    }
 
-    ic_cdk::println!(
-        "Deposit of {} ICP in account {:?}",
-        balance - Tokens::from_e8s(2 * ICP_FEE),
-        &account
-    );
+    Ok(nat_to_u128(allowance - fee))
+}
 
-    Ok((balance.e8s() - ICP_FEE).into())
+fn icp_subaccount(account: &Account) -> Subaccount {
+    match &account.subaccount {
+        Some(subaccount) => Subaccount(
+            subaccount
+                .as_slice()
+                .try_into()
+                .expect("Subaccounts are checked before they are used."),
+        ),
+        None => DEFAULT_SUBACCOUNT,
+    }
 }
 
-async fn deposit_token(caller: Principal, token: Principal) -> Result<u128, DepositErr> {
-    let token = DIP20::new(token);
-    let dip_fee = token.get_metadata().await.fee;
+fn icp_account_identifier(account: &Account) -> AccountIdentifier {
+    AccountIdentifier::new(&account.owner, &icp_subaccount(account))
+}
+
+fn deposit_account(exchange: Principal, owner: Principal, index: DepositAccountIndex) -> Account {
+    let subaccount = deposit_subaccount(&owner, index);
 
-    let allowance = token.allowance(caller, ic_cdk::api::id()).await;
+    Account::new(exchange, Some(subaccount.0.to_vec()))
+}
 
-    let available = allowance - dip_fee;
+async fn send_icp_transfer(op: &OperationState, exchange: Principal) -> Result<(), TransferErr> {
+    let (from_subaccount, to, amount) = match op.kind {
+        OperationKind::Deposit => (
+            icp_subaccount(op.account.as_ref().unwrap()),
+            AccountIdentifier::new(&exchange, &DEFAULT_SUBACCOUNT),
+            op.amount,
+        ),
+        OperationKind::Withdraw => (
+            DEFAULT_SUBACCOUNT,
+            icp_account_identifier(op.account.as_ref().unwrap()),
+            op.amount + op.fee,
+        ),
+    };
 
-    token
-        .transfer_from(caller, ic_cdk::api::id(), available.to_owned())
+    let transfer_args = ic_ledger_types::TransferArgs {
+        memo: Memo(op.id),
+        amount: Tokens::from_e8s(amount.try_into().unwrap()),
+        fee: Tokens::from_e8s(op.fee.try_into().unwrap()),
+        from_subaccount: Some(from_subaccount),
+        to,
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: op.created_at,
+        }),
+    };
+    ic_ledger_types::transfer(op.token, transfer_args)
         .await
-        .map_err(|_| DepositErr::TransferFailure)?;
+        .map_err(|(code, msg)| TransferErr::IcpCall(code, msg))?
+        .map_err(TransferErr::Icp)?;
+
+    ic_cdk::println!("Transfer of {} ICP to account {:?}", amount, &to);
 
-    Ok(nat_to_u128(available))
+    Ok(())
+}
+
+// DIP20 withdrawals transfer the fee on top of the amount.
+async fn send_dip20_transfer(
+    token: &impl DIP20Client,
+    op: &OperationState,
+    exchange: Principal,
+) -> Result<(), DIP20Error> {
+    match op.kind {
+        OperationKind::Deposit => {
+            token
+                .transfer_from(op.owner, exchange, op.amount.into())
+                .await?
+        }
+        OperationKind::Withdraw => {
+            token
+                .transfer(
+                    op.account.as_ref().unwrap().owner,
+                    (op.amount + op.fee).into(),
+                )
+                .await?
+        }
+    };
+    Ok(())
+}
+
+async fn send_icrc_transfer(
+    ledger: &impl IcrcClient,
+    op: &OperationState,
+    standard: TokenStandard,
+    exchange: Principal,
+) -> Result<(), IcrcError> {
+    let memo = Some(op.id.to_be_bytes().to_vec());
+    let created_at_time = Some(op.created_at);
+    let fee = Some(Nat::from(op.fee));
+
+    match (op.kind, &op.account, standard) {
+        (OperationKind::Deposit, None, TokenStandard::ICRC2) => {
+            ledger
+                .transfer_from(TransferFromArgs {
+                    spender_subaccount: None,
+                    from: Account::new(op.owner, None),
+                    to: Account::new(exchange, None),
+                    amount: op.amount.into(),
+                    fee,
+                    memo,
+                    created_at_time,
+                })
+                .await?
+        }
+        (OperationKind::Deposit, account, _) => {
+            ledger
+                .transfer(TransferArg {
+                    from_subaccount: account.as_ref().unwrap().subaccount.clone(),
+                    to: Account::new(exchange, None),
+                    amount: op.amount.into(),
+                    fee,
+                    memo,
+                    created_at_time,
+                })
+                .await?
+        }
+        (OperationKind::Withdraw, ..) => {
+            ledger
+                .transfer(TransferArg {
+                    from_subaccount: None,
+                    to: op.account.clone().unwrap(),
+                    amount: op.amount.into(),
+                    fee,
+                    memo,
+                    created_at_time,
+                })
+                .await?
+        }
+    };
+    Ok(())
 }
 
 #[query(name = "getBalance")]
@@ -126,25 +491,241 @@ pub fn get_all_balances() -> Vec<Balance> {
     STATE.with(|s| s.borrow().exchange.get_all_balances())
 }
 
-#[update(name = "getOrder")]
-#[candid_method(update, rename = "getOrder")]
+#[query(name = "getOrder")]
+#[candid_method(query, rename = "getOrder")]
 pub fn get_order(order: OrderId) -> Option<Order> {
     STATE.with(|s| s.borrow().exchange.get_order(order))
 }
 
-#[update(name = "getOrders")]
-#[candid_method(update, rename = "getOrders")]
+#[query(name = "getOrders")]
+#[candid_method(query, rename = "getOrders")]
 pub fn get_orders() -> Vec<Order> {
     STATE.with(|s| s.borrow().exchange.get_all_orders())
 }
 
+#[query(name = "getUserTrades")]
+#[candid_method(query, rename = "getUserTrades")]
+pub fn get_user_trades(user: Principal, before: Option<TradeId>, limit: u32) -> Vec<Trade> {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .get_user_trades(user, before, limit as usize)
+    })
+}
+
+// The deposits, withdrawals, trades, fees, pool swaps and liquidity changes of
+// the caller in [start, end), with the balance of each token after them. Pages
+// have at most `limit` entries, without the first `skip` entries at `start`,
+// and say where the next page starts.
+#[query(name = "getStatement")]
+#[candid_method(query, rename = "getStatement")]
+pub fn get_statement(start: u64, end: u64, skip: u32, limit: u32) -> StatementPage {
+    let caller = caller();
+    STATE.with(|s| {
+        let state = s.borrow();
+        let lines = statement::statement(
+            &state.journal,
+            &state.exchange.trades,
+            &state.exchange.pool_changes,
+            &caller,
+            start,
+            end,
+        );
+        let (lines, next) = statement::page(&lines, start, skip, limit as usize);
+        StatementPage {
+            entries: lines.iter().map(|l| l.into()).collect(),
+            next,
+        }
+    })
+}
+
+#[query]
+#[candid_method(query)]
+pub fn http_request(request: HttpRequest) -> HttpResponse {
+    let now = ic_cdk::api::time();
+    STATE.with(|s| http::handle(&s.borrow(), &request, now))
+}
+
+// Issues a token for downloading the caller's statement over HTTP at
+// `/statement?token=<token>`. It expires after five minutes.
+#[update(name = "issueStatementToken")]
+#[candid_method(update, rename = "issueStatementToken")]
+pub async fn issue_statement_token() -> String {
+    let caller = caller();
+    let (random,): (Vec<u8>,) = ic_cdk::call(Principal::management_canister(), "raw_rand", ())
+        .await
+        .unwrap_or_else(|(_, msg)| ic_cdk::trap(&msg));
+    http::issue_statement_token(caller, &random, ic_cdk::api::time())
+}
+
+#[query(name = "getPairTrades")]
+#[candid_method(query, rename = "getPairTrades")]
+pub fn get_pair_trades(
+    token_a: Principal,
+    token_b: Principal,
+    before: Option<TradeId>,
+    limit: u32,
+) -> Vec<Trade> {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .get_pair_trades(token_a, token_b, before, limit as usize)
+    })
+}
+
+#[query(name = "getCandles")]
+#[candid_method(query, rename = "getCandles")]
+pub fn get_candles(
+    base: Principal,
+    quote: Principal,
+    interval: u64,
+    start: u64,
+    end: u64,
+) -> Vec<Candle> {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .get_candles(base, quote, interval, start, end)
+    })
+}
+
+#[query(name = "getDepth")]
+#[candid_method(query, rename = "getDepth")]
+pub fn get_depth(base: Principal, quote: Principal, levels: u32) -> OrderBookDepth {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .get_depth(base, quote, levels as usize, ic_cdk::api::time())
+    })
+}
+
+#[query(name = "getTopOfBook")]
+#[candid_method(query, rename = "getTopOfBook")]
+pub fn get_top_of_book(base: Principal, quote: Principal) -> TopOfBook {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .get_top_of_book(base, quote, ic_cdk::api::time())
+    })
+}
+
+#[query(name = "getTicker")]
+#[candid_method(query, rename = "getTicker")]
+pub fn get_ticker(base: Principal, quote: Principal) -> Ticker {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .get_ticker(base, quote, ic_cdk::api::time())
+    })
+}
+
 #[update(name = "getDepositAddress")]
 #[candid_method(update, rename = "getDepositAddress")]
 pub fn get_deposit_address() -> AccountIdentifier {
-    let canister_id = ic_cdk::api::id();
-    let subaccount = principal_to_subaccount(&caller());
+    icp_account_identifier(&get_deposit_account())
+}
+
+// The ICRC-1 account that ICRC1 tokens are sent to before calling `deposit`.
+#[query(name = "getDepositAccount")]
+#[candid_method(query, rename = "getDepositAccount")]
+pub fn get_deposit_account() -> Account {
+    deposit_account(ic_cdk::api::id(), caller(), 0)
+}
+
+fn to_deposit_account(
+    owner: Principal,
+    index: DepositAccountIndex,
+    label: String,
+) -> DepositAccount {
+    let account = deposit_account(ic_cdk::api::id(), owner, index);
+
+    DepositAccount {
+        index,
+        label,
+        accountId: icp_account_identifier(&account),
+        account,
+    }
+}
+
+// The deposit accounts the caller opened. They are swept periodically, so tokens
+// sent to them are credited without calling `deposit`.
+#[query(name = "getDepositAccounts")]
+#[candid_method(query, rename = "getDepositAccounts")]
+pub fn get_deposit_accounts() -> Vec<DepositAccount> {
+    let caller = caller();
+    STATE
+        .with(|s| s.borrow().deposits.of(&caller))
+        .into_iter()
+        .map(|(index, label)| to_deposit_account(caller, index, label))
+        .collect()
+}
+
+#[update(name = "openDepositAccount")]
+#[candid_method(update, rename = "openDepositAccount")]
+pub fn open_deposit_account(label: String) -> DepositAccountReceipt {
+    let caller = caller();
+    let index = STATE.with(|s| s.borrow_mut().deposits.open(caller, label.clone()))?;
+
+    Ok(to_deposit_account(caller, index, label))
+}
+
+// Credits a transfer to a deposit account of the caller without waiting for the
+// next sweep. The block is looked up on the ledger to find the deposit account,
+// which is then swept.
+#[update(name = "depositNotify")]
+#[candid_method(update, rename = "depositNotify")]
+pub async fn deposit_notify(token_canister_id: Principal, block_index: u64) -> DepositReceipt {
+    if is_withdraw_only() {
+        return Err(DepositErr::WithdrawOnly);
+    }
+    let caller = caller();
+    let token = match get_token(&token_canister_id) {
+        Some(token) if token.listed => token,
+        _ => return Err(DepositErr::UnlistedToken),
+    };
+    let exchange = ic_cdk::api::id();
+    let indices = STATE.with(|s| s.borrow().deposits.indices_of(&caller));
+
+    let index = match token.standard {
+        TokenStandard::ICP => {
+            let to = icp::transfer_destination(token_canister_id, block_index)
+                .await
+                .map_err(|_| DepositErr::CallFailure)?
+                .ok_or(DepositErr::InvalidBlock)?;
+            indices
+                .into_iter()
+                .find(|i| icp_account_identifier(&deposit_account(exchange, caller, *i)) == to)
+        }
+        TokenStandard::ICRC1 | TokenStandard::ICRC2 => {
+            let ledger = Icrc::new(token_canister_id);
+            icrc_notified_index(&ledger, block_index, exchange, caller, indices).await?
+        }
+        TokenStandard::DIP20 => None,
+    }
+    .ok_or(DepositErr::InvalidBlock)?;
 
-    AccountIdentifier::new(&canister_id, &subaccount)
+    deposit_from_account(caller, token_canister_id, &token, index, exchange).await
+}
+
+// The deposit account of `owner` that the transfer at `block_index` was sent to.
+async fn icrc_notified_index(
+    ledger: &impl IcrcClient,
+    block_index: u64,
+    exchange: Principal,
+    owner: Principal,
+    indices: Vec<DepositAccountIndex>,
+) -> Result<Option<DepositAccountIndex>, DepositErr> {
+    let to = match ledger.transaction(block_index.into()).await? {
+        Some(icrc::Transaction {
+            transfer: Some(transfer),
+            ..
+        }) => transfer.to,
+        _ => return Ok(None),
+    };
+
+    Ok(indices
+        .into_iter()
+        .find(|i| deposit_account(exchange, owner, *i) == to))
 }
 
 #[update(name = "getWithdrawalAddress")]
@@ -157,15 +738,10 @@ pub fn get_withdrawal_address() -> AccountIdentifier {
 
 #[update(name = "getSymbol")]
 #[candid_method(update, rename = "getSymbol")]
-pub async fn get_symbol(token_canister_id: Principal) -> String {
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
-
-    if token_canister_id == ledger_canister_id {
-        "ICP".to_string()
-    } else {
-        DIP20::new(token_canister_id).get_metadata().await.symbol
+pub fn get_symbol(token_canister_id: Principal) -> String {
+    match get_token(&token_canister_id) {
+        Some(token) => token.symbol,
+        None => ic_cdk::trap("Token is not listed."),
     }
 }
 
@@ -177,22 +753,232 @@ pub fn place_order(
     to_token_canister_id: Principal,
     to_amount: Nat,
 ) -> OrderPlacementReceipt {
+    place_order_with_type(
+        from_token_canister_id,
+        from_amount,
+        to_token_canister_id,
+        to_amount,
+        OrderType::Limit,
+    )
+}
+
+#[update(name = "placeOrderWithType")]
+#[candid_method(update, rename = "placeOrderWithType")]
+pub fn place_order_with_type(
+    from_token_canister_id: Principal,
+    from_amount: Nat,
+    to_token_canister_id: Principal,
+    to_amount: Nat,
+    order_type: OrderType,
+) -> OrderPlacementReceipt {
+    if !is_listed(&from_token_canister_id) || !is_listed(&to_token_canister_id) {
+        return Err(OrderPlacementErr::UnlistedToken);
+    }
+    if !is_trading(from_token_canister_id, to_token_canister_id) {
+        return Err(OrderPlacementErr::TradingPaused);
+    }
     STATE.with(|s| {
         s.borrow_mut().exchange.place_order(
             from_token_canister_id,
             from_amount,
             to_token_canister_id,
             to_amount,
+            order_type,
+        )
+    })
+}
+
+// Places an order once the last price of its pair crosses the trigger. What it
+// offers is reserved until then.
+#[update(name = "placeConditionalOrder")]
+#[candid_method(update, rename = "placeConditionalOrder")]
+pub fn place_conditional_order(
+    from_token_canister_id: Principal,
+    from_amount: Nat,
+    to_token_canister_id: Principal,
+    to_amount: Nat,
+    order_type: OrderType,
+    trigger: Trigger,
+) -> ConditionalOrderReceipt {
+    if !is_listed(&from_token_canister_id) || !is_listed(&to_token_canister_id) {
+        return Err(OrderPlacementErr::UnlistedToken);
+    }
+    if !is_trading(from_token_canister_id, to_token_canister_id) {
+        return Err(OrderPlacementErr::TradingPaused);
+    }
+    STATE.with(|s| {
+        s.borrow_mut().exchange.place_conditional_order(
+            from_token_canister_id,
+            from_amount,
+            to_token_canister_id,
+            to_amount,
+            order_type,
+            trigger,
         )
     })
 }
 
+#[query(name = "getConditionalOrders")]
+#[candid_method(query, rename = "getConditionalOrders")]
+pub fn get_conditional_orders() -> Vec<ConditionalOrder> {
+    STATE.with(|s| s.borrow().exchange.get_conditional_orders())
+}
+
+// What became of the caller's conditional orders once they were triggered,
+// most recent first.
+#[query(name = "getTriggeredOrders")]
+#[candid_method(query, rename = "getTriggeredOrders")]
+pub fn get_triggered_orders() -> Vec<TriggeredOrder> {
+    STATE.with(|s| s.borrow().exchange.get_triggered_orders())
+}
+
+#[update(name = "cancelConditionalOrder")]
+#[candid_method(update, rename = "cancelConditionalOrder")]
+pub fn cancel_conditional_order(order: OrderId) -> CancelOrderReceipt {
+    STATE.with(|s| s.borrow_mut().exchange.cancel_conditional_order(order))
+}
+
 #[update(name = "cancelOrder")]
 #[candid_method(update, rename = "cancelOrder")]
 pub fn cancel_order(order: OrderId) -> CancelOrderReceipt {
     STATE.with(|s| s.borrow_mut().exchange.cancel_order(order))
 }
 
+#[query(name = "getPools")]
+#[candid_method(query, rename = "getPools")]
+pub fn get_pools() -> Vec<Pool> {
+    STATE.with(|s| s.borrow().exchange.get_pools())
+}
+
+#[query(name = "getLiquidityPositions")]
+#[candid_method(query, rename = "getLiquidityPositions")]
+pub fn get_liquidity_positions() -> Vec<LiquidityPosition> {
+    STATE.with(|s| s.borrow().exchange.get_liquidity_positions())
+}
+
+#[update(name = "addLiquidity")]
+#[candid_method(update, rename = "addLiquidity")]
+pub fn add_liquidity(
+    token_a_canister_id: Principal,
+    amount_a: Nat,
+    token_b_canister_id: Principal,
+    amount_b: Nat,
+) -> AddLiquidityReceipt {
+    if !is_listed(&token_a_canister_id) || !is_listed(&token_b_canister_id) {
+        return Err(PoolErr::UnlistedToken);
+    }
+    if is_withdraw_only() {
+        return Err(PoolErr::TradingPaused);
+    }
+    STATE.with(|s| {
+        s.borrow_mut().exchange.add_liquidity(
+            token_a_canister_id,
+            amount_a,
+            token_b_canister_id,
+            amount_b,
+        )
+    })
+}
+
+#[update(name = "removeLiquidity")]
+#[candid_method(update, rename = "removeLiquidity")]
+pub fn remove_liquidity(
+    token_a_canister_id: Principal,
+    token_b_canister_id: Principal,
+    shares: Nat,
+) -> RemoveLiquidityReceipt {
+    STATE.with(|s| {
+        s.borrow_mut()
+            .exchange
+            .remove_liquidity(token_a_canister_id, token_b_canister_id, shares)
+    })
+}
+
+#[query(name = "quoteSwap")]
+#[candid_method(query, rename = "quoteSwap")]
+pub fn quote_swap(
+    from_token_canister_id: Principal,
+    from_amount: Nat,
+    to_token_canister_id: Principal,
+) -> SwapReceipt {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .quote_swap(from_token_canister_id, from_amount, to_token_canister_id)
+    })
+}
+
+#[update(name = "swapExactIn")]
+#[candid_method(update, rename = "swapExactIn")]
+pub fn swap_exact_in(
+    from_token_canister_id: Principal,
+    from_amount: Nat,
+    to_token_canister_id: Principal,
+    min_to_amount: Nat,
+) -> SwapReceipt {
+    if !is_trading(from_token_canister_id, to_token_canister_id) {
+        return Err(PoolErr::TradingPaused);
+    }
+    STATE.with(|s| {
+        s.borrow_mut().exchange.swap_exact_in(
+            from_token_canister_id,
+            from_amount,
+            to_token_canister_id,
+            min_to_amount,
+        )
+    })
+}
+
+#[query(name = "quoteSwapRoute")]
+#[candid_method(query, rename = "quoteSwapRoute")]
+pub fn quote_swap_route(
+    from_token_canister_id: Principal,
+    from_amount: Nat,
+    to_token_canister_id: Principal,
+) -> SwapRouteReceipt {
+    STATE.with(|s| {
+        let state = s.borrow();
+        state.exchange.quote_swap_route(
+            from_token_canister_id,
+            from_amount,
+            to_token_canister_id,
+            &state.tokens.listed(),
+            |a, b| state.admin.is_trading(a, b),
+        )
+    })
+}
+
+// Swaps through the order books and pools of listed tokens, along the route
+// that gives the most.
+#[update]
+#[candid_method(update)]
+pub fn swap(
+    from_token_canister_id: Principal,
+    from_amount: Nat,
+    to_token_canister_id: Principal,
+    min_to_amount: Nat,
+) -> SwapRouteReceipt {
+    if !is_listed(&from_token_canister_id) || !is_listed(&to_token_canister_id) {
+        return Err(PoolErr::UnlistedToken);
+    }
+    if is_withdraw_only() {
+        return Err(PoolErr::TradingPaused);
+    }
+    STATE.with(|s| {
+        let state = &mut *s.borrow_mut();
+        let tokens = state.tokens.listed();
+        let admin = &state.admin;
+        state.exchange.swap(
+            from_token_canister_id,
+            from_amount,
+            to_token_canister_id,
+            min_to_amount,
+            &tokens,
+            |a, b| admin.is_trading(a, b),
+        )
+    })
+}
+
 #[update]
 #[candid_method(update)]
 pub async fn withdraw(
@@ -200,108 +986,468 @@ pub async fn withdraw(
     amount: Nat,
     address: Principal,
 ) -> WithdrawReceipt {
-    let caller = caller();
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
+    withdraw_to(
+        caller(),
+        token_canister_id,
+        &amount,
+        Account::new(address, None),
+    )
+    .await
+}
 
-    STATE.with(|s| {
-        s.borrow_mut().exchange.orders.retain(|_,v| v.owner != caller);
-    });
+// Like `withdraw`, but to an account that may have a subaccount. DIP20 tokens
+// have no subaccounts.
+#[update(name = "withdrawToAccount")]
+#[candid_method(update, rename = "withdrawToAccount")]
+pub async fn withdraw_to_account(
+    token_canister_id: Principal,
+    amount: Nat,
+    account: Account,
+) -> WithdrawReceipt {
+    withdraw_to(caller(), token_canister_id, &amount, account).await
+}
 
-    if token_canister_id == ledger_canister_id {
-        let account_id = AccountIdentifier::new(&address, &DEFAULT_SUBACCOUNT);
-        withdraw_icp(&amount, account_id).await
-    } else {
-        withdraw_token(token_canister_id, &amount, address).await
+// Withdraws from the exchange balance of `owner` through the token's standard.
+// Delisted tokens can still be withdrawn. The amount and the transfer fee are
+// debited from the free balance, so open orders stay on the book, and refunded
+// if the transfer fails.
+async fn withdraw_to(
+    owner: Principal,
+    token_canister_id: Principal,
+    amount: &Nat,
+    account: Account,
+) -> WithdrawReceipt {
+    let token = get_token(&token_canister_id).ok_or(WithdrawErr::UnlistedToken)?;
+    let valid_account = match (&account.subaccount, token.standard) {
+        (None, _) => true,
+        (Some(_), TokenStandard::DIP20) => false,
+        (Some(subaccount), _) => subaccount.len() == 32,
+    };
+    if !valid_account {
+        return Err(WithdrawErr::InvalidAccount);
+    }
+    let _guard = begin_operation(owner).ok_or(WithdrawErr::OperationInProgress)?;
+
+    let amount = nat_to_u128(amount.to_owned());
+    let now = ic_cdk::api::time();
+    let op = STATE
+        .with(|s| {
+            let mut state = s.borrow_mut();
+            state.exchange.remove_expired_orders(now);
+            if !state.exchange.balances.subtract_balance(
+                &owner,
+                &token_canister_id,
+                amount + token.fee,
+            ) {
+                return None;
+            }
+            Some(state.journal.begin(
+                OperationKind::Withdraw,
+                owner,
+                token_canister_id,
+                amount,
+                token.fee,
+                Some(account),
+                now,
+            ))
+        })
+        .ok_or(WithdrawErr::BalanceLow)?;
+
+    let result = send_transfer(&op, token.standard, ic_cdk::api::id()).await;
+    let outcome = transfer_outcome(&result);
+    settle_operation(op.id, outcome, ic_cdk::api::time());
+
+    match result {
+        Err(e) if outcome != TransferOutcome::Executed => Err(e.into()),
+        _ => Ok((amount + token.fee).into()),
     }
 }
 
-async fn withdraw_icp(amount: &Nat, account_id: AccountIdentifier) -> Result<Nat, WithdrawErr> {
+#[query(name = "getPendingOperations")]
+#[candid_method(query, rename = "getPendingOperations")]
+pub fn get_pending_operations() -> Vec<Operation> {
     let caller = caller();
-    let ledger_canister_id = STATE
-        .with(|s| s.borrow().ledger)
-        .unwrap_or(MAINNET_LEDGER_CANISTER_ID);
+    STATE.with(|s| {
+        s.borrow()
+            .journal
+            .pending()
+            .filter(|o| o.owner == caller)
+            .map(|o| o.into())
+            .collect()
+    })
+}
 
-    let sufficient_balance = STATE.with(|s| {
-        s.borrow_mut().exchange.balances.subtract_balance(
-            &caller,
-            &ledger_canister_id,
-            nat_to_u128(amount.to_owned() + ICP_FEE),
-        )
-    });
-    if !sufficient_balance {
-        return Err(WithdrawErr::BalanceLow);
+#[query(name = "getAllPendingOperations")]
+#[candid_method(query, rename = "getAllPendingOperations")]
+pub fn get_all_pending_operations() -> Vec<Operation> {
+    STATE.with(|s| s.borrow().journal.pending().map(|o| o.into()).collect())
+}
+
+// Sends the transfer of a pending operation again. Ledgers that deduplicate
+// transfers report it as a duplicate if the first one was executed.
+#[update(name = "retryOperation")]
+#[candid_method(update, rename = "retryOperation")]
+pub async fn retry_operation(id: OperationId) -> OperationReceipt {
+    let op = STATE
+        .with(|s| s.borrow().journal.get(id))
+        .ok_or(OperationErr::NotExistingOperation)?;
+    if op.owner != caller() && !is_owner() {
+        return Err(OperationErr::NotAllowed);
+    }
+    if op.status != OperationStatus::Pending {
+        return Ok((&op).into());
     }
+    let standard = get_token(&op.token)
+        .ok_or(OperationErr::NotExistingOperation)?
+        .standard;
+    if standard == TokenStandard::DIP20 {
+        return Err(OperationErr::NotRetryable);
+    }
+    let _guard = OperationGuard::acquire(op.owner).ok_or(OperationErr::OperationInProgress)?;
 
-    let transfer_args = ic_ledger_types::TransferArgs {
-        memo: Memo(0),
-        amount: Tokens::from_e8s(nat_to_u128(amount.to_owned() + ICP_FEE).try_into().unwrap()),
-        fee: Tokens::from_e8s(ICP_FEE),
-        from_subaccount: Some(DEFAULT_SUBACCOUNT),
-        to: account_id,
-        created_at_time: None,
+    let result = send_transfer(&op, standard, ic_cdk::api::id()).await;
+    let settled = settle_operation(id, transfer_outcome(&result), ic_cdk::api::time());
+
+    Ok(settled.as_ref().unwrap_or(&op).into())
+}
+
+// Settles a pending operation whose outcome the owner looked up on the token.
+#[update(name = "resolveOperation")]
+#[candid_method(update, rename = "resolveOperation")]
+pub fn resolve_operation(id: OperationId, executed: bool) -> OperationReceipt {
+    if !is_owner() {
+        return Err(OperationErr::NotAllowed);
+    }
+    resolve(id, executed, ic_cdk::api::time())
+}
+
+// Settles an operation without waiting for its guard, which a trap after an
+// await leaves behind. Releases the principal once the operation is settled.
+fn resolve(id: OperationId, executed: bool, now: u64) -> OperationReceipt {
+    let op = STATE
+        .with(|s| s.borrow().journal.get(id))
+        .ok_or(OperationErr::NotExistingOperation)?;
+    let outcome = if executed {
+        TransferOutcome::Executed
+    } else {
+        TransferOutcome::Failed
     };
-    let icp_reciept = ic_ledger_types::transfer(ledger_canister_id, transfer_args)
+    let settled = settle_operation(id, outcome, now);
+    if settled.is_some() {
+        OperationGuard::release(op.owner);
+    }
+
+    Ok(settled.as_ref().unwrap_or(&op).into())
+}
+
+fn icp_token_state() -> TokenState {
+    TokenState {
+        standard: TokenStandard::ICP,
+        symbol: "ICP".to_string(),
+        decimals: 8,
+        fee: ICP_FEE.into(),
+        listed: true,
+    }
+}
+
+async fn fetch_token(
+    token_canister_id: Principal,
+    standard: TokenStandard,
+) -> Result<TokenState, AdminErr> {
+    match standard {
+        TokenStandard::ICP => Ok(icp_token_state()),
+        TokenStandard::DIP20 => fetch_dip20_token(&DIP20::new(token_canister_id)).await,
+        TokenStandard::ICRC1 | TokenStandard::ICRC2 => {
+            fetch_icrc_token(&Icrc::new(token_canister_id), standard).await
+        }
+    }
+}
+
+async fn fetch_dip20_token(token: &impl DIP20Client) -> Result<TokenState, AdminErr> {
+    let metadata = token
+        .get_metadata()
         .await
-        .map_err(|_| WithdrawErr::TransferFailure)
-        .and_then(|v| v.map_err(|_| WithdrawErr::TransferFailure));
+        .map_err(|_| AdminErr::CallFailure)?;
 
-    if let Err(e) = icp_reciept {
-        STATE.with(|s| {
-            s.borrow_mut().exchange.balances.add_balance(
-                &caller,
-                &ledger_canister_id,
-                nat_to_u128(amount.to_owned() + ICP_FEE),
-            )
-        });
+    Ok(TokenState {
+        standard: TokenStandard::DIP20,
+        symbol: metadata.symbol,
+        decimals: metadata.decimals,
+        fee: nat_to_u128(metadata.fee),
+        listed: true,
+    })
+}
+
+async fn fetch_icrc_token(
+    ledger: &impl IcrcClient,
+    standard: TokenStandard,
+) -> Result<TokenState, AdminErr> {
+    let symbol = ledger.symbol().await.map_err(|_| AdminErr::CallFailure)?;
+    let decimals = ledger.decimals().await.map_err(|_| AdminErr::CallFailure)?;
+    let fee = ledger.fee().await.map_err(|_| AdminErr::CallFailure)?;
+
+    Ok(TokenState {
+        standard,
+        symbol,
+        decimals,
+        fee: nat_to_u128(fee),
+        listed: true,
+    })
+}
 
-        return Err(e);
+fn is_owner() -> bool {
+    STATE.with(|s| s.borrow().owner) == Some(caller())
+}
+
+#[query(name = "getTokens")]
+#[candid_method(query, rename = "getTokens")]
+pub fn get_tokens() -> Vec<TokenInfo> {
+    STATE.with(|s| s.borrow().tokens.tokens())
+}
+
+#[query(name = "getTradablePairs")]
+#[candid_method(query, rename = "getTradablePairs")]
+pub fn get_tradable_pairs() -> Vec<Pair> {
+    STATE.with(|s| s.borrow().tokens.tradable_pairs())
+}
+
+// Lists a token, or relists it, with metadata read from the token canister.
+#[update(name = "listToken")]
+#[candid_method(update, rename = "listToken")]
+pub async fn list_token(token_canister_id: Principal, standard: TokenStandard) -> TokenReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
     }
+    let token = fetch_token(token_canister_id, standard).await?;
+
+    STATE.with(|s| s.borrow_mut().tokens.list(token_canister_id, token));
+    Ok(get_token(&token_canister_id)
+        .unwrap()
+        .to_info(token_canister_id))
+}
 
-    ic_cdk::println!("Withdrawal of {} ICP to account {:?}", amount, &account_id);
+// Reads the metadata of a known token again, e.g. after its fee changed.
+#[update(name = "refreshToken")]
+#[candid_method(update, rename = "refreshToken")]
+pub async fn refresh_token(token_canister_id: Principal) -> TokenReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
+    }
+    let standard = get_token(&token_canister_id)
+        .ok_or(AdminErr::InvalidArgument)?
+        .standard;
+    let token = fetch_token(token_canister_id, standard).await?;
 
-    Ok(amount.to_owned() + ICP_FEE)
+    if !STATE.with(|s| s.borrow_mut().tokens.refresh(token_canister_id, token)) {
+        return Err(AdminErr::InvalidArgument);
+    }
+    Ok(get_token(&token_canister_id)
+        .unwrap()
+        .to_info(token_canister_id))
 }
 
-async fn withdraw_token(
-    token: Principal,
-    amount: &Nat,
-    address: Principal,
-) -> Result<Nat, WithdrawErr> {
-    let caller = caller();
-    let dip = DIP20::new(token);
-    let dip_fee = dip.get_metadata().await.fee;
+// Stops deposits and new orders in the token. Open orders and balances are kept.
+#[update(name = "delistToken")]
+#[candid_method(update, rename = "delistToken")]
+pub fn delist_token(token_canister_id: Principal) -> AdminReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
+    }
+    if !STATE.with(|s| s.borrow_mut().tokens.delist(&token_canister_id)) {
+        return Err(AdminErr::InvalidArgument);
+    }
+    Ok(())
+}
 
-    let sufficient_balance = STATE.with(|s| {
-        s.borrow_mut().exchange.balances.subtract_balance(
-            &caller,
-            &token,
-            nat_to_u128(amount.to_owned() + dip_fee.clone()),
+// Switches a pair to batch auctions that clear every `epoch` nanoseconds, or
+// back to continuous matching.
+#[update(name = "setBatchAuction")]
+#[candid_method(update, rename = "setBatchAuction")]
+pub fn set_batch_auction(
+    token_a_canister_id: Principal,
+    token_b_canister_id: Principal,
+    epoch: Option<u64>,
+) -> AdminReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
+    }
+    let now = ic_cdk::api::time();
+    STATE.with(|s| {
+        s.borrow_mut().exchange.set_batch_auction(
+            token_a_canister_id,
+            token_b_canister_id,
+            epoch,
+            now,
         )
-    });
-    if !sufficient_balance {
-        return Err(WithdrawErr::BalanceLow);
+    })
+}
+
+#[query(name = "getBatchAuction")]
+#[candid_method(query, rename = "getBatchAuction")]
+pub fn get_batch_auction(
+    token_a_canister_id: Principal,
+    token_b_canister_id: Principal,
+) -> Option<BatchAuction> {
+    STATE.with(|s| {
+        s.borrow()
+            .exchange
+            .auctions
+            .get(token_a_canister_id, token_b_canister_id)
+    })
+}
+
+// Stops new orders and swaps in a pair. Open orders stay on the book.
+#[update(name = "pausePair")]
+#[candid_method(update, rename = "pausePair")]
+pub fn pause_pair(token_a_canister_id: Principal, token_b_canister_id: Principal) -> AdminReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
+    }
+    if !STATE.with(|s| {
+        s.borrow_mut()
+            .admin
+            .pause(token_a_canister_id, token_b_canister_id)
+    }) {
+        return Err(AdminErr::InvalidArgument);
     }
+    Ok(())
+}
 
-    let tx_receipt = dip
-        .transfer(address, amount.to_owned() + dip_fee.clone())
-        .await
-        .map_err(|_| WithdrawErr::TransferFailure);
+#[update(name = "resumePair")]
+#[candid_method(update, rename = "resumePair")]
+pub fn resume_pair(token_a_canister_id: Principal, token_b_canister_id: Principal) -> AdminReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
+    }
+    if !STATE.with(|s| {
+        s.borrow_mut()
+            .admin
+            .resume(token_a_canister_id, token_b_canister_id)
+    }) {
+        return Err(AdminErr::InvalidArgument);
+    }
+    Ok(())
+}
 
-    if let Err(e) = tx_receipt {
-        STATE.with(|s| {
-            s.borrow_mut().exchange.balances.add_balance(
-                &caller,
-                &token,
-                nat_to_u128(amount.to_owned() + dip_fee.clone()),
-            )
-        });
+// The emergency mode: deposits, orders, swaps and new liquidity are refused,
+// while balances can still be withdrawn.
+#[update(name = "setWithdrawOnly")]
+#[candid_method(update, rename = "setWithdrawOnly")]
+pub fn set_withdraw_only(withdraw_only: bool) -> AdminReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
+    }
+    STATE.with(|s| s.borrow_mut().admin.withdraw_only = withdraw_only);
+    Ok(())
+}
 
-        return Err(e);
+#[update(name = "transferOwnership")]
+#[candid_method(update, rename = "transferOwnership")]
+pub fn transfer_ownership(new_owner: Principal) -> AdminReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
     }
+    if new_owner == Principal::anonymous() {
+        return Err(AdminErr::InvalidArgument);
+    }
+    STATE.with(|s| s.borrow_mut().owner = Some(new_owner));
+    Ok(())
+}
+
+#[query(name = "getExchangeStatus")]
+#[candid_method(query, rename = "getExchangeStatus")]
+pub fn get_exchange_status() -> ExchangeStatus {
+    STATE.with(|s| {
+        let state = s.borrow();
+        ExchangeStatus {
+            owner: state.owner,
+            withdrawOnly: state.admin.withdraw_only,
+            pausedPairs: state.admin.paused_pairs(),
+        }
+    })
+}
+
+#[query(name = "getTradingFees")]
+#[candid_method(query, rename = "getTradingFees")]
+pub fn get_trading_fees() -> TradingFees {
+    STATE.with(|s| s.borrow().exchange.fees)
+}
+
+#[query(name = "getOrderLimits")]
+#[candid_method(query, rename = "getOrderLimits")]
+pub fn get_order_limits() -> OrderLimits {
+    STATE.with(|s| s.borrow().exchange.limits)
+}
 
-    Ok(amount.to_owned() + dip_fee)
+#[update(name = "setOrderLimits")]
+#[candid_method(update, rename = "setOrderLimits")]
+pub fn set_order_limits(limits: OrderLimits) -> AdminReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
+    }
+    STATE.with(|s| s.borrow_mut().exchange.limits = limits);
+    Ok(())
+}
+
+#[query(name = "getMinOrderSize")]
+#[candid_method(query, rename = "getMinOrderSize")]
+pub fn get_min_order_size(token_canister_id: Principal) -> Nat {
+    STATE.with(|s| s.borrow().exchange.min_order_size(token_canister_id).into())
+}
+
+// The smallest amount of the token that orders may offer. Zero removes it.
+#[update(name = "setMinOrderSize")]
+#[candid_method(update, rename = "setMinOrderSize")]
+pub fn set_min_order_size(token_canister_id: Principal, amount: Nat) -> AdminReceipt {
+    if !is_owner() {
+        return Err(AdminErr::NotAllowed);
+    }
+    STATE.with(|s| {
+        s.borrow_mut()
+            .exchange
+            .set_min_order_size(token_canister_id, amount)
+    });
+    Ok(())
+}
+
+#[update(name = "setTradingFees")]
+#[candid_method(update, rename = "setTradingFees")]
+pub fn set_trading_fees(fees: TradingFees) -> AdminReceipt {
+    STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        if state.owner != Some(caller()) {
+            return AdminReceipt::Err(AdminErr::NotAllowed);
+        }
+        state.exchange.set_trading_fees(fees)
+    })
+}
+
+#[query(name = "getCollectedFees")]
+#[candid_method(query, rename = "getCollectedFees")]
+pub fn get_collected_fees() -> Vec<Balance> {
+    STATE.with(|s| s.borrow().exchange.get_collected_fees())
+}
+
+// Fees are collected in the canister's own exchange account.
+#[update(name = "withdrawFees")]
+#[candid_method(update, rename = "withdrawFees")]
+pub async fn withdraw_fees(
+    token_canister_id: Principal,
+    amount: Nat,
+    address: Principal,
+) -> WithdrawReceipt {
+    if STATE.with(|s| s.borrow().owner) != Some(caller()) {
+        return Err(WithdrawErr::NotAllowed);
+    }
+    let fee_account = ic_cdk::api::id();
+
+    withdraw_to(
+        fee_account,
+        token_canister_id,
+        &amount,
+        Account::new(address, None),
+    )
+    .await
 }
 
 #[query]
@@ -311,6 +1457,7 @@ pub fn whoami() -> Principal {
 }
 
 // For testing
+#[cfg(feature = "test")]
 #[update]
 #[candid_method(oneway)]
 pub fn credit(user: Principal, token_canister_id: Principal, amount: Nat) {
@@ -328,6 +1475,7 @@ pub fn credit(user: Principal, token_canister_id: Principal, amount: Nat) {
 }
 
 // For testing.
+#[cfg(feature = "test")]
 #[update]
 #[candid_method(oneway)]
 pub fn clear() {
@@ -336,7 +1484,18 @@ pub fn clear() {
 
         assert!(state.owner.unwrap() == caller());
         state.exchange.orders.clear();
-        state.exchange.balances.0.clear();
+        let conditional: Vec<_> = state
+            .exchange
+            .conditional
+            .values()
+            .map(|o| o.order)
+            .collect();
+        for o in conditional {
+            state.exchange.orders.counts.remove(&o);
+        }
+        state.exchange.conditional.clear();
+        state.exchange.balances.clear();
+        state.exchange.reserved.clear();
     })
 }
 
@@ -344,23 +1503,28 @@ pub fn clear() {
 fn init(ledger: Option<Principal>) {
     ic_cdk::setup();
     STATE.with(|s| {
-        s.borrow_mut().owner = Some(caller());
-        s.borrow_mut().ledger = ledger;
+        let mut state = s.borrow_mut();
+        state.owner = Some(caller());
+        state.tokens.list(
+            ledger.unwrap_or(MAINNET_LEDGER_CANISTER_ID),
+            icp_token_state(),
+        );
     });
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    let stable_state = STATE.with(|s| s.take());
-    ic_cdk::storage::stable_save((stable_state,)).expect("failed to save stable state");
+    let saved = STATE.with(|s| s.borrow_mut().take_saved());
+    MEMORY_MANAGER.with(|m| stable::save(&m.get(stable::UPGRADES), &saved));
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (stable_state,) =
-        ic_cdk::storage::stable_restore().expect("failed to restore stable state");
+    // Before the memory manager is initialized, which would overwrite it.
+    let legacy = stable::take_legacy_state(&DefaultMemoryImpl::default());
+    let state = MEMORY_MANAGER.with(|m| stable::restore(m, legacy));
     STATE.with(|s| {
-        s.replace(stable_state);
+        s.replace(state);
     });
 }
 
@@ -370,3 +1534,329 @@ export_service!();
 fn export_candid() -> String {
     __export_service()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+    use dip20::MockDIP20;
+    use icrc::MockIcrc;
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    // The mock token never suspends, so a single poll runs a call to completion.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        match future
+            .as_mut()
+            .poll(&mut Context::from_waker(Waker::noop()))
+        {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("the mock token suspended"),
+        }
+    }
+
+    fn balance(owner: Principal, token: Principal) -> u128 {
+        STATE.with(|s| s.borrow().exchange.balances.balance_of(&owner, &token))
+    }
+
+    const EXCHANGE: u8 = 1;
+    const TOKEN: u8 = 2;
+    const USER: u8 = 3;
+    const FEE: u128 = 10;
+
+    fn begin_deposit(amount: u128, account: Option<Account>) -> OperationState {
+        STATE.with(|s| {
+            s.borrow_mut().journal.begin(
+                OperationKind::Deposit,
+                principal(USER),
+                principal(TOKEN),
+                amount,
+                FEE,
+                account,
+                1,
+            )
+        })
+    }
+
+    // Debits the user like `withdraw_to` does, from a balance of 1_000.
+    fn begin_withdrawal(amount: u128, account: Account) -> OperationState {
+        STATE.with(|s| {
+            let mut state = s.borrow_mut();
+            state
+                .exchange
+                .balances
+                .add_balance(&principal(USER), &principal(TOKEN), 1_000);
+            assert!(state.exchange.balances.subtract_balance(
+                &principal(USER),
+                &principal(TOKEN),
+                amount + FEE
+            ));
+            state.journal.begin(
+                OperationKind::Withdraw,
+                principal(USER),
+                principal(TOKEN),
+                amount,
+                FEE,
+                Some(account),
+                1,
+            )
+        })
+    }
+
+    fn outcome<E>(result: &Result<(), E>, outcome: fn(&E) -> TransferOutcome) -> TransferOutcome {
+        result
+            .as_ref()
+            .err()
+            .map_or(TransferOutcome::Executed, outcome)
+    }
+
+    #[test]
+    fn dip20_deposit_is_credited_once() {
+        let token = MockDIP20::new(principal(EXCHANGE), FEE);
+        token.mint(principal(USER), 1_000);
+        token.approve(principal(USER), principal(EXCHANGE), 500);
+
+        let amount = block_on(dip20_deposit_amount(
+            &token,
+            FEE.into(),
+            principal(USER),
+            principal(EXCHANGE),
+        ));
+        assert!(matches!(amount, Ok(490)));
+        let op = begin_deposit(490, None);
+        let result = block_on(send_dip20_transfer(&token, &op, principal(EXCHANGE)));
+        let outcome = outcome(&result, DIP20Error::outcome);
+        assert_eq!(outcome, TransferOutcome::Executed);
+        assert_eq!(token.balance_of(&principal(EXCHANGE)), 490u32);
+
+        assert!(settle_operation(op.id, outcome, 2).is_some());
+        assert!(settle_operation(op.id, outcome, 3).is_none());
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 490);
+    }
+
+    #[test]
+    fn dip20_deposit_rejects_an_allowance_below_the_fee() {
+        let token = MockDIP20::new(principal(EXCHANGE), FEE);
+        token.approve(principal(USER), principal(EXCHANGE), FEE);
+        let amount = block_on(dip20_deposit_amount(
+            &token,
+            FEE.into(),
+            principal(USER),
+            principal(EXCHANGE),
+        ));
+        assert!(matches!(amount, Err(DepositErr::BalanceLow)));
+
+        token.reject_calls.set(true);
+        let amount = block_on(dip20_deposit_amount(
+            &token,
+            FEE.into(),
+            principal(USER),
+            principal(EXCHANGE),
+        ));
+        assert!(matches!(amount, Err(DepositErr::CallFailure)));
+    }
+
+    #[test]
+    fn failed_withdrawal_is_refunded() {
+        // The exchange holds none of the token, so the transfer is refused.
+        let token = MockDIP20::new(principal(EXCHANGE), FEE);
+        let op = begin_withdrawal(100, Account::new(principal(USER), None));
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 890);
+
+        let result = block_on(send_dip20_transfer(&token, &op, principal(EXCHANGE)));
+        let settled = settle_operation(op.id, outcome(&result, DIP20Error::outcome), 2);
+        assert!(settled.unwrap().status == OperationStatus::Failed);
+        assert!(matches!(
+            result.map_err(WithdrawErr::from),
+            Err(WithdrawErr::TransferFailure)
+        ));
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 1_000);
+    }
+
+    #[test]
+    fn operation_with_unknown_outcome_stays_pending() {
+        let op = begin_withdrawal(100, Account::new(principal(USER), None));
+
+        assert!(settle_operation(op.id, TransferOutcome::Unknown, 2).is_none());
+        assert!(begin_operation(principal(USER)).is_none());
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 890);
+
+        let settled = settle_operation(op.id, TransferOutcome::Executed, 3).unwrap();
+        assert!(settled.status == OperationStatus::Completed);
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 890);
+        assert!(begin_operation(principal(USER)).is_some());
+    }
+
+    #[test]
+    fn resolving_releases_a_leaked_guard() {
+        let op = begin_withdrawal(100, Account::new(principal(USER), None));
+        // A trap after the transfer was sent skips the release of the guard.
+        std::mem::forget(OperationGuard::acquire(principal(USER)).unwrap());
+        assert!(OperationGuard::acquire(principal(USER)).is_none());
+
+        let resolved = resolve(op.id, false, 2).ok().unwrap();
+        assert!(resolved.status == OperationStatus::Failed);
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 1_000);
+        assert!(begin_operation(principal(USER)).is_some());
+    }
+
+    #[test]
+    fn icrc1_deposit_sweeps_the_deposit_account() {
+        let ledger = MockIcrc::new(principal(EXCHANGE), FEE);
+        let deposit_account = deposit_account(principal(EXCHANGE), principal(USER), 1);
+        ledger.mint(deposit_account.clone(), 500);
+
+        let amount = block_on(icrc1_deposit_amount(&ledger, FEE.into(), &deposit_account));
+        assert!(matches!(amount, Ok(490)));
+        let op = begin_deposit(490, Some(deposit_account.clone()));
+        let result = block_on(send_icrc_transfer(
+            &ledger,
+            &op,
+            TokenStandard::ICRC1,
+            principal(EXCHANGE),
+        ));
+        settle_operation(op.id, outcome(&result, IcrcError::outcome), 2);
+        assert_eq!(ledger.balance(&deposit_account), 0);
+        assert_eq!(
+            ledger.balance(&Account::new(principal(EXCHANGE), None)),
+            490
+        );
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 490);
+    }
+
+    #[test]
+    fn deposit_accounts_are_distinct() {
+        let owner = Principal::from_slice(&[7; 29]);
+        let subaccounts: std::collections::HashSet<[u8; 32]> = [0, 1, 256]
+            .iter()
+            .map(|i| deposit_subaccount(&owner, *i).0)
+            .chain([deposit_subaccount(&principal(USER), 0).0])
+            .collect();
+        assert_eq!(subaccounts.len(), 4);
+        // The first account of a principal is the one it always deposited to.
+        assert_eq!(deposit_subaccount(&principal(USER), 0).0[..3], [1, USER, 0]);
+    }
+
+    #[test]
+    fn notified_transfer_finds_the_deposit_account() {
+        let ledger = MockIcrc::new(principal(EXCHANGE), FEE);
+        let user = Account::new(principal(USER), None);
+        let savings = deposit_account(principal(EXCHANGE), principal(USER), 1);
+        ledger.mint(user.clone(), 1_000);
+        let block = ledger.send(user.clone(), savings, 500);
+        let elsewhere = ledger.send(user, Account::new(principal(EXCHANGE), None), 100);
+
+        let notified = |block: u64, indices: Vec<DepositAccountIndex>| {
+            block_on(icrc_notified_index(
+                &ledger,
+                block,
+                principal(EXCHANGE),
+                principal(USER),
+                indices,
+            ))
+        };
+        assert!(matches!(notified(block, vec![0, 1]), Ok(Some(1))));
+        assert!(matches!(notified(block, vec![0]), Ok(None)));
+        assert!(matches!(notified(elsewhere, vec![0, 1]), Ok(None)));
+        // The mint is not a transfer, and there is no block after the last one.
+        assert!(matches!(notified(0, vec![0, 1]), Ok(None)));
+        assert!(matches!(notified(elsewhere + 1, vec![0, 1]), Ok(None)));
+    }
+
+    #[test]
+    fn icrc2_deposit_takes_the_allowance_less_the_fee() {
+        let ledger = MockIcrc::new(principal(EXCHANGE), FEE);
+        let user = Account::new(principal(USER), None);
+        ledger.mint(user.clone(), 1_000);
+        ledger.approve(user.clone(), Account::new(principal(EXCHANGE), None), 500);
+
+        let amount = block_on(icrc2_deposit_amount(
+            &ledger,
+            FEE.into(),
+            principal(USER),
+            principal(EXCHANGE),
+        ));
+        assert!(matches!(amount, Ok(490)));
+        let op = begin_deposit(490, None);
+        let result = block_on(send_icrc_transfer(
+            &ledger,
+            &op,
+            TokenStandard::ICRC2,
+            principal(EXCHANGE),
+        ));
+        assert!(result.is_ok());
+        assert_eq!(ledger.balance(&user), 500);
+
+        ledger.reject_calls.set(true);
+        let amount = block_on(icrc2_deposit_amount(
+            &ledger,
+            FEE.into(),
+            principal(USER),
+            principal(EXCHANGE),
+        ));
+        assert!(matches!(amount, Err(DepositErr::CallFailure)));
+    }
+
+    #[test]
+    fn retried_icrc_withdrawal_is_executed_once() {
+        let ledger = MockIcrc::new(principal(EXCHANGE), FEE);
+        ledger.mint(Account::new(principal(EXCHANGE), None), 1_000);
+        let account = Account::new(principal(USER), Some(vec![7; 32]));
+        let op = begin_withdrawal(100, account.clone());
+
+        // The reply to the first transfer is lost.
+        let first = block_on(send_icrc_transfer(
+            &ledger,
+            &op,
+            TokenStandard::ICRC2,
+            principal(EXCHANGE),
+        ));
+        assert!(first.is_ok());
+        let retry = block_on(send_icrc_transfer(
+            &ledger,
+            &op,
+            TokenStandard::ICRC2,
+            principal(EXCHANGE),
+        ));
+        let outcome = outcome(&retry, IcrcError::outcome);
+        assert_eq!(outcome, TransferOutcome::Executed);
+
+        settle_operation(op.id, outcome, 2);
+        assert_eq!(ledger.balance(&account), 100);
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 890);
+    }
+
+    #[test]
+    fn icrc_withdrawal_with_a_stale_fee_is_refunded() {
+        let ledger = MockIcrc::new(principal(EXCHANGE), FEE + 1);
+        ledger.mint(Account::new(principal(EXCHANGE), None), 1_000);
+        let op = begin_withdrawal(100, Account::new(principal(USER), None));
+
+        let result = block_on(send_icrc_transfer(
+            &ledger,
+            &op,
+            TokenStandard::ICRC2,
+            principal(EXCHANGE),
+        ));
+        settle_operation(op.id, outcome(&result, IcrcError::outcome), 2);
+        assert!(matches!(
+            result.map_err(WithdrawErr::from),
+            Err(WithdrawErr::TransferFailure)
+        ));
+        assert_eq!(balance(principal(USER), principal(TOKEN)), 1_000);
+    }
+
+    #[test]
+    fn token_metadata_is_read_from_the_token() {
+        let token = block_on(fetch_dip20_token(&MockDIP20::new(principal(EXCHANGE), FEE)));
+        let token = token.ok().unwrap();
+        assert_eq!((token.symbol.as_str(), token.fee), ("MOCK", FEE));
+        assert_eq!(token.standard, TokenStandard::DIP20);
+
+        let ledger = MockIcrc::new(principal(EXCHANGE), FEE);
+        ledger.reject_calls.set(true);
+        let token = block_on(fetch_icrc_token(&ledger, TokenStandard::ICRC2));
+        assert!(matches!(token, Err(AdminErr::CallFailure)));
+    }
+}
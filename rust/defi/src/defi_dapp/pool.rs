@@ -0,0 +1,392 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::memory_manager::MemoryManager;
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+
+use crate::stable::{principal_key, Memory, PrincipalKey, POOL_CHANGES, POOL_CHANGES_BY_OWNER};
+use crate::types::*;
+
+// Swap fee in basis points, left in the pool for the liquidity providers.
+pub const POOL_FEE_BPS: u128 = 30;
+const BPS: u128 = 10_000;
+
+// A constant product pool: swaps keep reserve_a * reserve_b from decreasing.
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct PoolState {
+    pub token_a: Principal,
+    pub token_b: Principal,
+    pub reserve_a: u128,
+    pub reserve_b: u128,
+    pub total_shares: u128,
+    pub shares: HashMap<Principal, u128>, // owner -> LP shares
+}
+
+impl From<&PoolState> for Pool {
+    fn from(p: &PoolState) -> Pool {
+        Pool {
+            tokenA: p.token_a,
+            tokenB: p.token_b,
+            reserveA: p.reserve_a.into(),
+            reserveB: p.reserve_b.into(),
+            totalShares: p.total_shares.into(),
+        }
+    }
+}
+
+fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+    ((BigUint::from(a) * BigUint::from(b)) / BigUint::from(c))
+        .try_into()
+        .unwrap()
+}
+
+impl PoolState {
+    // Tokens are kept in the order of `utils::pair_key`.
+    pub fn new(token_a: Principal, token_b: Principal) -> Self {
+        PoolState {
+            token_a,
+            token_b,
+            reserve_a: 0,
+            reserve_b: 0,
+            total_shares: 0,
+            shares: HashMap::new(),
+        }
+    }
+
+    pub fn shares_of(&self, owner: &Principal) -> u128 {
+        self.shares.get(owner).copied().unwrap_or(0)
+    }
+
+    // Reserves as (in, out) for a swap from `from_token`.
+    fn reserves_from(&self, from_token: &Principal) -> Result<(u128, u128), PoolErr> {
+        if *from_token == self.token_a {
+            Ok((self.reserve_a, self.reserve_b))
+        } else if *from_token == self.token_b {
+            Ok((self.reserve_b, self.reserve_a))
+        } else {
+            Err(PoolErr::NotExistingPool)
+        }
+    }
+
+    pub fn quote(&self, from_token: &Principal, amount_in: u128) -> Result<u128, PoolErr> {
+        let (reserve_in, reserve_out) = self.reserves_from(from_token)?;
+        if reserve_in == 0 || reserve_out == 0 {
+            return Err(PoolErr::InsufficientLiquidity);
+        }
+        let amount_in_with_fee = BigUint::from(amount_in) * BigUint::from(BPS - POOL_FEE_BPS);
+        let amount_out: u128 = ((&amount_in_with_fee * BigUint::from(reserve_out))
+            / (BigUint::from(reserve_in) * BigUint::from(BPS) + amount_in_with_fee))
+            .try_into()
+            .unwrap();
+        if amount_out == 0 {
+            return Err(PoolErr::InvalidAmount);
+        }
+        Ok(amount_out)
+    }
+
+    pub fn swap(&mut self, from_token: &Principal, amount_in: u128) -> Result<u128, PoolErr> {
+        let amount_out = self.quote(from_token, amount_in)?;
+        if *from_token == self.token_a {
+            self.reserve_a += amount_in;
+            self.reserve_b -= amount_out;
+        } else {
+            self.reserve_b += amount_in;
+            self.reserve_a -= amount_out;
+        }
+        Ok(amount_out)
+    }
+
+    // Adds at most the given amounts at the current pool ratio.
+    // Returns the amounts actually taken and the shares minted.
+    pub fn add_liquidity(
+        &mut self,
+        owner: Principal,
+        amount_a: u128,
+        amount_b: u128,
+    ) -> Result<(u128, u128, u128), PoolErr> {
+        let (amount_a, amount_b, shares) = if self.total_shares == 0 {
+            let shares: u128 = (BigUint::from(amount_a) * BigUint::from(amount_b))
+                .sqrt()
+                .try_into()
+                .unwrap();
+            (amount_a, amount_b, shares)
+        } else {
+            let optimal_b = mul_div(amount_a, self.reserve_b, self.reserve_a);
+            let (amount_a, amount_b) = if optimal_b <= amount_b {
+                (amount_a, optimal_b)
+            } else {
+                (mul_div(amount_b, self.reserve_a, self.reserve_b), amount_b)
+            };
+            let shares = mul_div(amount_a, self.total_shares, self.reserve_a).min(mul_div(
+                amount_b,
+                self.total_shares,
+                self.reserve_b,
+            ));
+            (amount_a, amount_b, shares)
+        };
+        if shares == 0 || amount_a == 0 || amount_b == 0 {
+            return Err(PoolErr::InvalidAmount);
+        }
+
+        self.reserve_a += amount_a;
+        self.reserve_b += amount_b;
+        self.total_shares += shares;
+        *self.shares.entry(owner).or_default() += shares;
+
+        Ok((amount_a, amount_b, shares))
+    }
+
+    // Burns shares for their part of both reserves.
+    pub fn remove_liquidity(
+        &mut self,
+        owner: Principal,
+        shares: u128,
+    ) -> Result<(u128, u128), PoolErr> {
+        let owned = self.shares_of(&owner);
+        if shares == 0 || shares > owned {
+            return Err(PoolErr::InvalidAmount);
+        }
+        let amount_a = mul_div(shares, self.reserve_a, self.total_shares);
+        let amount_b = mul_div(shares, self.reserve_b, self.total_shares);
+
+        self.reserve_a -= amount_a;
+        self.reserve_b -= amount_b;
+        self.total_shares -= shares;
+        if owned == shares {
+            self.shares.remove(&owner);
+        } else {
+            self.shares.insert(owner, owned - shares);
+        }
+
+        Ok((amount_a, amount_b))
+    }
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum PoolChangeKind {
+    Swap,
+    Liquidity,
+}
+
+// How a swap through a pool or a change of liquidity moved the balances of
+// `owner`. Amounts are signed: what went into the pool is negative.
+#[derive(CandidType, Clone, Deserialize)]
+pub struct PoolChangeState {
+    pub id: u64,
+    pub timestamp: u64,
+    pub owner: Principal,
+    pub kind: PoolChangeKind,
+    pub token_a: Principal,
+    pub amount_a: i128,
+    pub token_b: Principal,
+    pub amount_b: i128,
+}
+
+// Pool changes are encoded with candid like trades. This bounds their encoding
+// with room to spare.
+const MAX_POOL_CHANGE_SIZE: u32 = 1024;
+
+impl Storable for PoolChangeState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), PoolChangeState).unwrap()
+    }
+}
+
+impl BoundedStorable for PoolChangeState {
+    const MAX_SIZE: u32 = MAX_POOL_CHANGE_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// The pool changes of all users, in the order they happened. Like the trade
+// log, it only grows, so it is kept in stable memory with an index per owner.
+pub struct PoolLog {
+    changes: StableBTreeMap<u64, PoolChangeState, Memory>,
+    by_owner: StableBTreeMap<(PrincipalKey, u64), (), Memory>,
+}
+
+impl PoolLog {
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>) -> Self {
+        PoolLog {
+            changes: StableBTreeMap::init(memory_manager.get(POOL_CHANGES)),
+            by_owner: StableBTreeMap::init(memory_manager.get(POOL_CHANGES_BY_OWNER)),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn record(
+        &mut self,
+        owner: Principal,
+        kind: PoolChangeKind,
+        token_a: Principal,
+        amount_a: i128,
+        token_b: Principal,
+        amount_b: i128,
+        now: u64,
+    ) {
+        let id = self.changes.len();
+        self.by_owner.insert((principal_key(&owner), id), ());
+        self.changes.insert(
+            id,
+            PoolChangeState {
+                id,
+                timestamp: now,
+                owner,
+                kind,
+                token_a,
+                amount_a,
+                token_b,
+                amount_b,
+            },
+        );
+    }
+
+    // The pool changes of `owner`, oldest first.
+    pub fn changes_of(&self, owner: &Principal) -> impl Iterator<Item = PoolChangeState> + '_ {
+        let owner = principal_key(owner);
+        self.by_owner
+            .range((owner, 0)..)
+            .take_while(move |((o, _), _)| *o == owner)
+            .filter_map(move |((_, id), _)| self.changes.get(&id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+
+    fn product(pool: &PoolState) -> BigUint {
+        BigUint::from(pool.reserve_a) * BigUint::from(pool.reserve_b)
+    }
+
+    fn shares_sum(pool: &PoolState) -> u128 {
+        pool.shares.values().sum()
+    }
+
+    fn funded_pool() -> PoolState {
+        let mut pool = PoolState::new(principal(1), principal(2));
+        pool.add_liquidity(principal(10), 1_000_000, 4_000_000)
+            .unwrap();
+        pool
+    }
+
+    #[test]
+    fn first_deposit_mints_geometric_mean() {
+        let pool = funded_pool();
+        assert_eq!(pool.total_shares, 2_000_000);
+        assert_eq!(pool.shares_of(&principal(10)), 2_000_000);
+    }
+
+    #[test]
+    fn swaps_never_decrease_the_product() {
+        let mut pool = funded_pool();
+        for i in 1..50u128 {
+            let before = product(&pool);
+            let from = if i % 2 == 0 {
+                principal(1)
+            } else {
+                principal(2)
+            };
+            pool.swap(&from, i * 1_337).unwrap();
+            assert!(product(&pool) >= before);
+        }
+    }
+
+    #[test]
+    fn swap_matches_quote_and_charges_fee() {
+        let mut pool = funded_pool();
+        let quoted = pool.quote(&principal(1), 10_000).unwrap();
+        // Without the fee 10_000 would buy 39_603.
+        assert!(quoted < 39_603);
+        assert_eq!(pool.swap(&principal(1), 10_000).unwrap(), quoted);
+        assert_eq!(pool.reserve_a, 1_010_000);
+        assert_eq!(pool.reserve_b, 4_000_000 - quoted);
+    }
+
+    #[test]
+    fn swap_rejects_unknown_token_and_empty_pool() {
+        let mut pool = PoolState::new(principal(1), principal(2));
+        assert!(matches!(
+            pool.swap(&principal(1), 10),
+            Err(PoolErr::InsufficientLiquidity)
+        ));
+        let mut pool = funded_pool();
+        assert!(matches!(
+            pool.swap(&principal(3), 10),
+            Err(PoolErr::NotExistingPool)
+        ));
+        assert!(matches!(
+            pool.swap(&principal(1), 0),
+            Err(PoolErr::InvalidAmount)
+        ));
+    }
+
+    #[test]
+    fn liquidity_is_added_at_the_pool_ratio() {
+        let mut pool = funded_pool();
+        let (a, b, shares) = pool.add_liquidity(principal(11), 500, 10_000).unwrap();
+        assert_eq!((a, b, shares), (500, 2_000, 1_000));
+        assert_eq!(pool.total_shares, shares_sum(&pool));
+        assert_eq!((pool.reserve_a, pool.reserve_b), (1_000_500, 4_002_000));
+    }
+
+    #[test]
+    fn removing_all_shares_empties_the_pool() {
+        let mut pool = funded_pool();
+        pool.add_liquidity(principal(11), 1_000, 4_000).unwrap();
+        pool.swap(&principal(2), 50_000).unwrap();
+        let (reserve_a, reserve_b) = (pool.reserve_a, pool.reserve_b);
+
+        let (a1, b1) = pool
+            .remove_liquidity(principal(10), pool.shares_of(&principal(10)))
+            .unwrap();
+        let (a2, b2) = pool
+            .remove_liquidity(principal(11), pool.shares_of(&principal(11)))
+            .unwrap();
+        assert_eq!((a1 + a2, b1 + b2), (reserve_a, reserve_b));
+        assert_eq!(
+            (pool.reserve_a, pool.reserve_b, pool.total_shares),
+            (0, 0, 0)
+        );
+        assert!(pool.shares.is_empty());
+    }
+
+    #[test]
+    fn shares_can_not_be_overdrawn() {
+        let mut pool = funded_pool();
+        assert!(matches!(
+            pool.remove_liquidity(principal(11), 1),
+            Err(PoolErr::InvalidAmount)
+        ));
+        assert!(matches!(
+            pool.remove_liquidity(principal(10), 2_000_001),
+            Err(PoolErr::InvalidAmount)
+        ));
+    }
+
+    #[test]
+    fn value_per_share_never_decreases() {
+        // product / total_shares^2 is the squared value of one share.
+        let mut pool = funded_pool();
+        for i in 1..20u128 {
+            let product_before = product(&pool);
+            let shares_before = BigUint::from(pool.total_shares);
+            pool.swap(&principal(1), i * 10_000).unwrap();
+            pool.add_liquidity(principal(10 + i as u8), i * 7, i * 31)
+                .unwrap();
+            assert_eq!(pool.total_shares, shares_sum(&pool));
+            assert!(
+                product(&pool) * shares_before.pow(2)
+                    >= product_before * BigUint::from(pool.total_shares).pow(2)
+            );
+        }
+    }
+}
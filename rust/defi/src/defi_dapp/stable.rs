@@ -0,0 +1,465 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use candid::de::IDLDeserialize;
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Blob;
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _};
+
+use ic_ledger_types::MAINNET_LEDGER_CANISTER_ID;
+
+use crate::admin::AdminState;
+use crate::deposits::DepositAccounts;
+use crate::exchange::{Exchange, OrderState, SavedExchange};
+use crate::journal::{Journal, SavedJournal};
+use crate::tokens::TokenRegistry;
+use crate::{icp_token_state, OrderId, SavedState, State};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// What is saved on upgrades, next to the stable maps.
+pub const UPGRADES: MemoryId = MemoryId::new(0);
+pub const BALANCES: MemoryId = MemoryId::new(1);
+pub const RESERVED: MemoryId = MemoryId::new(2);
+pub const ORDERS: MemoryId = MemoryId::new(3);
+pub const SETTLED: MemoryId = MemoryId::new(4);
+pub const SETTLED_BY_OWNER: MemoryId = MemoryId::new(5);
+pub const TRADES: MemoryId = MemoryId::new(6);
+pub const TRADES_BY_USER: MemoryId = MemoryId::new(7);
+pub const TRADES_BY_PAIR: MemoryId = MemoryId::new(8);
+pub const POOL_CHANGES: MemoryId = MemoryId::new(9);
+pub const POOL_CHANGES_BY_OWNER: MemoryId = MemoryId::new(10);
+pub const ORDERS_BY_OWNER: MemoryId = MemoryId::new(11);
+pub const ORDERS_BY_PAIR: MemoryId = MemoryId::new(12);
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+thread_local! {
+    pub static MEMORY_MANAGER: MemoryManager<DefaultMemoryImpl> =
+        MemoryManager::init(DefaultMemoryImpl::default());
+}
+
+// Principals are at most 29 bytes long.
+pub type PrincipalKey = Blob<29>;
+
+pub fn principal_key(principal: &Principal) -> PrincipalKey {
+    PrincipalKey::try_from(principal.as_slice()).unwrap()
+}
+
+pub fn key_principal(key: &PrincipalKey) -> Principal {
+    Principal::from_slice(key.as_slice())
+}
+
+// Writes `value` to the start of `memory`, prefixed with its length.
+pub fn save<T: CandidType>(memory: &Memory, value: &T) {
+    let bytes = Encode!(value).expect("failed to encode stable state");
+    let len = bytes.len() as u64 + 8;
+    let pages = len.div_ceil(WASM_PAGE_SIZE);
+    if memory.size() < pages {
+        assert!(
+            memory.grow(pages - memory.size()) != -1,
+            "failed to grow stable memory"
+        );
+    }
+    memory.write(0, &(bytes.len() as u64).to_le_bytes());
+    memory.write(8, &bytes);
+}
+
+// Reads what `save` wrote, or the default if nothing was saved yet.
+pub fn load<T: CandidType + for<'de> Deserialize<'de> + Default>(memory: &Memory) -> T {
+    if memory.size() == 0 {
+        return T::default();
+    }
+    let mut len = [0; 8];
+    memory.read(0, &mut len);
+    let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+    memory.read(8, &mut bytes);
+    Decode!(&bytes, T).expect("failed to decode stable state")
+}
+
+// The state of the first release, which `ic_cdk::storage::stable_save` wrote
+// as a one element tuple at the start of stable memory.
+#[derive(CandidType, Deserialize, Default)]
+pub struct LegacyState {
+    pub owner: Option<Principal>,
+    pub ledger: Option<Principal>,
+    pub exchange: LegacyExchange,
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct LegacyExchange {
+    pub next_id: OrderId,
+    pub balances: LegacyBalances,
+    pub orders: HashMap<OrderId, LegacyOrder>,
+}
+
+// owner -> token_canister_id -> amount
+#[derive(CandidType, Deserialize, Default)]
+pub struct LegacyBalances(pub HashMap<Principal, HashMap<Principal, u128>>);
+
+#[derive(CandidType, Deserialize, Clone, Copy)]
+pub struct LegacyOrder {
+    pub id: OrderId,
+    pub owner: Principal,
+    pub from_token_canister_id: Principal,
+    pub from_amount: u128,
+    pub to_token_canister_id: Principal,
+    pub to_amount: u128,
+}
+
+// Decodes the legacy state if `memory` still holds one. This has to run before
+// the memory manager claims the memory.
+pub fn take_legacy_state(memory: &impl ic_stable_structures::Memory) -> Option<LegacyState> {
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut magic = [0; 4];
+    memory.read(0, &mut magic);
+    if &magic != b"DIDL" {
+        return None;
+    }
+    let mut bytes = vec![0; (memory.size() * WASM_PAGE_SIZE) as usize];
+    memory.read(0, &mut bytes);
+    let mut de = IDLDeserialize::new(&bytes).expect("failed to decode legacy state");
+    let state = de.get_value().expect("failed to decode legacy state");
+    // The rest of the memory is zeroes, which are not candid values.
+    let _ = de.done();
+    Some(state)
+}
+
+impl LegacyState {
+    // Moves the legacy balances and orders into the stable maps, and lists the
+    // ICP ledger that the first release took deposits from.
+    pub fn migrate(self, memory_manager: &MemoryManager<DefaultMemoryImpl>) -> State {
+        let legacy = self.exchange;
+        let mut exchange = Exchange::init(
+            memory_manager,
+            SavedExchange {
+                next_id: legacy.next_id,
+                ..SavedExchange::default()
+            },
+        );
+        for (owner, balances) in legacy.balances.0 {
+            for (token, amount) in balances {
+                exchange.balances.add_balance(&owner, &token, amount);
+            }
+        }
+        // Orders didn't reserve what they offer, so they may offer more than
+        // their owner still has. Those are dropped, oldest orders first served.
+        let mut orders: Vec<LegacyOrder> = legacy.orders.into_values().collect();
+        orders.sort_by_key(|o| o.id);
+        for o in orders {
+            let Exchange {
+                balances, reserved, ..
+            } = &mut exchange;
+            if o.from_amount == 0
+                || !balances.move_balance(
+                    reserved,
+                    &o.owner,
+                    &o.from_token_canister_id,
+                    o.from_amount,
+                )
+            {
+                continue;
+            }
+            exchange.orders.insert(
+                o.id,
+                OrderState {
+                    id: o.id,
+                    owner: o.owner,
+                    from_token_canister_id: o.from_token_canister_id,
+                    from_amount: o.from_amount,
+                    to_token_canister_id: o.to_token_canister_id,
+                    to_amount: o.to_amount,
+                    expires_at: None,
+                },
+            );
+        }
+
+        let mut tokens = TokenRegistry::default();
+        tokens.list(
+            self.ledger.unwrap_or(MAINNET_LEDGER_CANISTER_ID),
+            icp_token_state(),
+        );
+        State {
+            owner: self.owner,
+            admin: AdminState::default(),
+            tokens,
+            deposits: DepositAccounts::default(),
+            journal: Journal::init(memory_manager, SavedJournal::default()),
+            exchange,
+        }
+    }
+}
+
+// Restores the state after an upgrade, migrating it when it was saved in the
+// legacy layout.
+pub fn restore(
+    memory_manager: &MemoryManager<DefaultMemoryImpl>,
+    legacy: Option<LegacyState>,
+) -> State {
+    match legacy {
+        Some(legacy) => legacy.migrate(memory_manager),
+        None => {
+            let saved: SavedState = load(&memory_manager.get(UPGRADES));
+            State::init(memory_manager, saved)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::numbered_principal;
+    use crate::trades::TradeState;
+    use crate::types::OperationKind;
+
+    fn order(id: OrderId) -> OrderState {
+        OrderState {
+            id,
+            owner: numbered_principal(id),
+            from_token_canister_id: numbered_principal(1),
+            from_amount: 1_000,
+            to_token_canister_id: numbered_principal(2),
+            to_amount: 2_000,
+            expires_at: None,
+        }
+    }
+
+    fn trade(id: u64) -> TradeState {
+        TradeState {
+            id,
+            timestamp: id,
+            taker_order_id: id as OrderId,
+            taker: numbered_principal(id as u32 % USERS),
+            taker_token_canister_id: numbered_principal(1),
+            taker_amount: 100,
+            taker_fee: 1,
+            maker_order_id: id as OrderId + 1,
+            maker: numbered_principal(id as u32 % USERS + 1),
+            maker_token_canister_id: numbered_principal(2),
+            maker_amount: 200,
+            maker_fee: 2,
+        }
+    }
+
+    const USERS: u32 = 10_000;
+    const ORDERS: u32 = 5_000;
+    const TRADES: u64 = 10_000;
+    const OPERATIONS: u32 = 5_000;
+
+    #[test]
+    fn upgrades_only_save_what_is_not_in_the_stable_maps() {
+        let raw = DefaultMemoryImpl::default();
+        let memory_manager = MemoryManager::init(raw.clone());
+        let mut state = State::init(&memory_manager, SavedState::default());
+        for user in 0..USERS {
+            state.exchange.balances.add_balance(
+                &numbered_principal(user),
+                &numbered_principal(1),
+                user as u128 + 1,
+            );
+        }
+        for id in 0..ORDERS {
+            let order = order(id);
+            state.exchange.reserved.add_balance(
+                &order.owner,
+                &numbered_principal(1),
+                order.from_amount,
+            );
+            state.exchange.orders.insert(id, order);
+        }
+        state.exchange.next_id = ORDERS;
+        for id in 0..TRADES {
+            state.exchange.trades.record(trade(id));
+        }
+        for user in 0..OPERATIONS {
+            let op = state.journal.begin(
+                OperationKind::Deposit,
+                numbered_principal(user),
+                numbered_principal(1),
+                1_000,
+                10,
+                None,
+                user as u64,
+            );
+            state.journal.settle(op.id, true, user as u64 + 1);
+        }
+
+        let upgrades = memory_manager.get(UPGRADES);
+        save(&upgrades, &state.take_saved());
+        assert_eq!(upgrades.size(), 1);
+        drop(state);
+
+        let memory_manager = MemoryManager::init(raw.clone());
+        let state = restore(&memory_manager, take_legacy_state(&raw));
+        assert_eq!(state.exchange.next_id, ORDERS);
+        assert_eq!(state.exchange.orders.values().count(), ORDERS as usize);
+        assert_eq!(state.exchange.orders.get(&7).unwrap().to_amount, 2_000);
+        for user in [0, 7, ORDERS, USERS - 1].iter() {
+            assert_eq!(
+                state
+                    .exchange
+                    .balances
+                    .balance_of(&numbered_principal(*user), &numbered_principal(1)),
+                *user as u128 + 1
+            );
+        }
+        assert_eq!(
+            state
+                .exchange
+                .reserved
+                .balance_of(&numbered_principal(7), &numbered_principal(1)),
+            1_000
+        );
+        assert_eq!(state.exchange.trades.next_id(), TRADES);
+        assert_eq!(
+            state
+                .exchange
+                .trades
+                .recent_pair_trades(numbered_principal(2), numbered_principal(1), 1)
+                .next()
+                .unwrap()
+                .id,
+            TRADES - 1
+        );
+        assert_eq!(
+            state
+                .exchange
+                .trades
+                .user_trades(&numbered_principal(7))
+                .len(),
+            2
+        );
+        let settled: Vec<_> = state.journal.settled_of(&numbered_principal(7)).collect();
+        assert_eq!(settled.len(), 1);
+        assert_eq!(settled[0].settled_at, Some(8));
+    }
+
+    // The state of the first release as it was declared, to write what its
+    // `pre_upgrade` wrote.
+    mod first_release {
+        use candid::{CandidType, Principal};
+        use std::collections::HashMap;
+
+        #[derive(CandidType, Default)]
+        pub struct State {
+            pub owner: Option<Principal>,
+            pub ledger: Option<Principal>,
+            pub exchange: Exchange,
+        }
+
+        #[derive(CandidType, Default)]
+        pub struct BalancesState(pub HashMap<Principal, HashMap<Principal, u128>>);
+
+        #[derive(CandidType, Default)]
+        pub struct Exchange {
+            pub next_id: u32,
+            pub balances: BalancesState,
+            pub orders: HashMap<u32, OrderState>,
+        }
+
+        #[derive(CandidType, Clone, Copy)]
+        pub struct OrderState {
+            pub id: u32,
+            pub owner: Principal,
+            pub from_token_canister_id: Principal,
+            pub from_amount: u128,
+            pub to_token_canister_id: Principal,
+            pub to_amount: u128,
+        }
+    }
+
+    // Writes `state` the way `ic_cdk::storage::stable_save((state,))` did.
+    fn stable_save(state: first_release::State) -> DefaultMemoryImpl {
+        let mut bytes = Vec::new();
+        candid::write_args(&mut bytes, (state,)).unwrap();
+        let raw = DefaultMemoryImpl::default();
+        raw.grow((bytes.len() as u64).div_ceil(WASM_PAGE_SIZE));
+        raw.write(0, &bytes);
+        raw
+    }
+
+    #[test]
+    fn first_release_state_is_migrated() {
+        let (ledger, token) = (numbered_principal(USERS + 1), numbered_principal(USERS + 2));
+        let mut legacy = first_release::State {
+            owner: Some(numbered_principal(0)),
+            ledger: Some(ledger),
+            ..Default::default()
+        };
+        let mut id = 0;
+        let mut order = |owner, from_amount| {
+            id += 1;
+            first_release::OrderState {
+                id,
+                owner,
+                from_token_canister_id: ledger,
+                from_amount,
+                to_token_canister_id: token,
+                to_amount: 2_000,
+            }
+        };
+        // Every user has two orders that their balance covers.
+        let mut orders = Vec::new();
+        for user in 0..USERS {
+            legacy
+                .exchange
+                .balances
+                .0
+                .entry(numbered_principal(user))
+                .or_default()
+                .insert(ledger, 2_500);
+            orders.push(order(numbered_principal(user), 1_000));
+            orders.push(order(numbered_principal(user), 1_000));
+        }
+        // One more order than user 0 has tokens for, and an empty one.
+        let uncovered = order(numbered_principal(0), 1_000);
+        let empty = order(numbered_principal(1), 0);
+        orders.extend([uncovered, empty].iter());
+        for o in orders {
+            legacy.exchange.orders.insert(o.id, o);
+        }
+        legacy.exchange.next_id = id + 1;
+        let raw = stable_save(legacy);
+        assert!(raw.size() > 1);
+
+        let legacy = take_legacy_state(&raw).unwrap();
+        let memory_manager = MemoryManager::init(raw.clone());
+        let state = restore(&memory_manager, Some(legacy));
+        assert_eq!(state.owner, Some(numbered_principal(0)));
+        assert_eq!(state.exchange.next_id, id + 1);
+        assert!(state.tokens.is_listed(&ledger));
+        assert!(state.tokens.get(&token).is_none());
+        assert_eq!(state.exchange.orders.values().count(), 2 * USERS as usize);
+        assert!(state.exchange.orders.get(&uncovered.id).is_none());
+        assert!(state.exchange.orders.get(&empty.id).is_none());
+        assert_eq!(state.exchange.orders.get(&3).unwrap().to_amount, 2_000);
+        for user in [0, 1, USERS - 1].iter() {
+            let user = numbered_principal(*user);
+            assert_eq!(state.exchange.balances.balance_of(&user, &ledger), 500);
+            assert_eq!(state.exchange.reserved.balance_of(&user, &ledger), 2_000);
+        }
+        assert_eq!(
+            state
+                .exchange
+                .orders
+                .counts
+                .of(&numbered_principal(0), ledger, token),
+            (2, 2 * USERS)
+        );
+
+        // The memory now belongs to the memory manager.
+        assert!(take_legacy_state(&raw).is_none());
+    }
+
+    #[test]
+    fn first_release_lists_the_mainnet_ledger_by_default() {
+        let raw = stable_save(first_release::State::default());
+        let legacy = take_legacy_state(&raw).unwrap();
+        let state = restore(&MemoryManager::init(raw.clone()), Some(legacy));
+        assert!(state.tokens.is_listed(&MAINNET_LEDGER_CANISTER_ID));
+        assert!(state.owner.is_none());
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use candid::Principal;
+
+use crate::journal::Journal;
+use crate::pool::{PoolChangeKind, PoolLog};
+use crate::trades::TradeLog;
+use crate::types::*;
+
+// The most entries a page of a statement has.
+pub const MAX_PAGE_SIZE: usize = 1_000;
+
+#[derive(Clone, Copy)]
+pub struct StatementLine {
+    pub timestamp: u64,
+    pub kind: StatementEntryKind,
+    pub id: u64,
+    pub token: Principal,
+    pub amount: i128,
+    pub balance: i128,
+}
+
+impl From<&StatementLine> for StatementEntry {
+    fn from(l: &StatementLine) -> StatementEntry {
+        StatementEntry {
+            timestamp: l.timestamp,
+            kind: l.kind,
+            id: l.id,
+            token: l.token,
+            amount: l.amount.into(),
+            balance: l.balance.into(),
+        }
+    }
+}
+
+// The balance changes of `owner` through settled deposits and withdrawals,
+// trades, pool swaps and liquidity in [start, end), oldest first. Running
+// balances add up all such changes since the first one.
+pub fn statement(
+    journal: &Journal,
+    trades: &TradeLog,
+    pool_changes: &PoolLog,
+    owner: &Principal,
+    start: u64,
+    end: u64,
+) -> Vec<StatementLine> {
+    let mut lines = Vec::new();
+    let mut change = |timestamp, kind, id, token, amount: i128| {
+        if amount != 0 {
+            lines.push(StatementLine {
+                timestamp,
+                kind,
+                id,
+                token,
+                amount,
+                balance: 0,
+            });
+        }
+    };
+    for op in journal
+        .settled_of(owner)
+        .filter(|o| o.status == OperationStatus::Completed)
+    {
+        let timestamp = op.settled_at.unwrap_or(op.created_at);
+        match op.kind {
+            OperationKind::Deposit => change(
+                timestamp,
+                StatementEntryKind::Deposit,
+                op.id,
+                op.token,
+                op.amount as i128,
+            ),
+            OperationKind::Withdraw => {
+                change(
+                    timestamp,
+                    StatementEntryKind::Withdrawal,
+                    op.id,
+                    op.token,
+                    -(op.amount as i128),
+                );
+                change(
+                    timestamp,
+                    StatementEntryKind::Fee,
+                    op.id,
+                    op.token,
+                    -(op.fee as i128),
+                );
+            }
+        }
+    }
+    for t in trades.user_trades(owner) {
+        let sides = [
+            (
+                t.taker,
+                t.taker_token_canister_id,
+                t.taker_amount,
+                t.maker_token_canister_id,
+                t.maker_amount,
+                t.taker_fee,
+            ),
+            (
+                t.maker,
+                t.maker_token_canister_id,
+                t.maker_amount,
+                t.taker_token_canister_id,
+                t.taker_amount,
+                t.maker_fee,
+            ),
+        ];
+        for (party, paid_token, paid, received_token, received, fee) in sides.iter() {
+            if party != owner {
+                continue;
+            }
+            let trade = StatementEntryKind::Trade;
+            change(t.timestamp, trade, t.id, *paid_token, -(*paid as i128));
+            change(t.timestamp, trade, t.id, *received_token, *received as i128);
+            change(
+                t.timestamp,
+                StatementEntryKind::Fee,
+                t.id,
+                *received_token,
+                -(*fee as i128),
+            );
+        }
+    }
+    for c in pool_changes.changes_of(owner) {
+        let kind = match c.kind {
+            PoolChangeKind::Swap => StatementEntryKind::Swap,
+            PoolChangeKind::Liquidity => StatementEntryKind::Liquidity,
+        };
+        change(c.timestamp, kind, c.id, c.token_a, c.amount_a);
+        change(c.timestamp, kind, c.id, c.token_b, c.amount_b);
+    }
+
+    // The sort is stable, so changes at the same time keep their order.
+    lines.sort_by_key(|l| l.timestamp);
+    let mut balances: HashMap<Principal, i128> = HashMap::new();
+    lines
+        .into_iter()
+        .take_while(|l| l.timestamp < end)
+        .filter_map(|mut l| {
+            let balance = balances.entry(l.token).or_default();
+            *balance += l.amount;
+            l.balance = *balance;
+            (l.timestamp >= start).then_some(l)
+        })
+        .collect()
+}
+
+// At most `limit` lines of a statement from `start` on, without the first `skip`
+// lines at `start`, and where the next page starts if there are more.
+pub fn page(
+    lines: &[StatementLine],
+    start: u64,
+    skip: u32,
+    limit: usize,
+) -> (&[StatementLine], Option<StatementCursor>) {
+    let from = lines.partition_point(|l| l.timestamp < start);
+    let at_start = lines[from..].partition_point(|l| l.timestamp == start);
+    let first = from + (skip as usize).min(at_start);
+    let last = first + limit.min(MAX_PAGE_SIZE).min(lines.len() - first);
+    let next = lines.get(last).map(|l| StatementCursor {
+        start: l.timestamp,
+        skip: (last - lines.partition_point(|x| x.timestamp < l.timestamp)) as u32,
+    });
+    (&lines[first..last], next)
+}
+
+pub fn to_csv(lines: &[StatementLine]) -> String {
+    let mut csv = String::from("timestamp,kind,id,token,amount,balance\n");
+    for l in lines {
+        let kind = match l.kind {
+            StatementEntryKind::Deposit => "deposit",
+            StatementEntryKind::Withdrawal => "withdrawal",
+            StatementEntryKind::Trade => "trade",
+            StatementEntryKind::Fee => "fee",
+            StatementEntryKind::Swap => "swap",
+            StatementEntryKind::Liquidity => "liquidity",
+        };
+        writeln!(
+            csv,
+            "{},{},{},{},{},{}",
+            l.timestamp, kind, l.id, l.token, l.amount, l.balance
+        )
+        .unwrap();
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::journal::SavedJournal;
+    use crate::test_utils::principal;
+    use crate::trades::{SavedTradeLog, TradeState};
+    use ic_stable_structures::memory_manager::MemoryManager;
+    use ic_stable_structures::DefaultMemoryImpl;
+
+    const USER: u8 = 1;
+    const OTHER: u8 = 2;
+    const TOKEN_A: u8 = 3;
+    const TOKEN_B: u8 = 4;
+
+    #[test]
+    fn statements_keep_running_balances() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut journal = Journal::init(&memory_manager, SavedJournal::default());
+        let deposit = journal.begin(
+            OperationKind::Deposit,
+            principal(USER),
+            principal(TOKEN_A),
+            1_000,
+            10,
+            None,
+            1,
+        );
+        journal.settle(deposit.id, true, 2);
+        let failed = journal.begin(
+            OperationKind::Withdraw,
+            principal(USER),
+            principal(TOKEN_A),
+            500,
+            10,
+            None,
+            3,
+        );
+        journal.settle(failed.id, false, 4);
+
+        let mut trades = TradeLog::init(&memory_manager, SavedTradeLog::default());
+        trades.record(TradeState {
+            id: 0,
+            timestamp: 5,
+            taker_order_id: 2,
+            taker: principal(OTHER),
+            taker_token_canister_id: principal(TOKEN_B),
+            taker_amount: 200,
+            taker_fee: 1,
+            maker_order_id: 1,
+            maker: principal(USER),
+            maker_token_canister_id: principal(TOKEN_A),
+            maker_amount: 100,
+            maker_fee: 2,
+        });
+
+        let withdrawal = journal.begin(
+            OperationKind::Withdraw,
+            principal(USER),
+            principal(TOKEN_A),
+            300,
+            10,
+            None,
+            6,
+        );
+        journal.settle(withdrawal.id, true, 7);
+
+        let mut pool_changes = PoolLog::init(&memory_manager);
+        pool_changes.record(
+            principal(USER),
+            PoolChangeKind::Swap,
+            principal(TOKEN_A),
+            -90,
+            principal(TOKEN_B),
+            40,
+            8,
+        );
+        pool_changes.record(
+            principal(OTHER),
+            PoolChangeKind::Swap,
+            principal(TOKEN_B),
+            -10,
+            principal(TOKEN_A),
+            20,
+            8,
+        );
+        pool_changes.record(
+            principal(USER),
+            PoolChangeKind::Liquidity,
+            principal(TOKEN_A),
+            -100,
+            principal(TOKEN_B),
+            -38,
+            9,
+        );
+
+        let lines = statement(&journal, &trades, &pool_changes, &principal(USER), 5, 7);
+        let summary: Vec<(StatementEntryKind, u8, i128, i128)> = lines
+            .iter()
+            .map(|l| (l.kind, l.token.as_slice()[0], l.amount, l.balance))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (StatementEntryKind::Trade, TOKEN_A, -100, 900),
+                (StatementEntryKind::Trade, TOKEN_B, 200, 200),
+                (StatementEntryKind::Fee, TOKEN_B, -2, 198),
+            ]
+        );
+
+        let lines = statement(&journal, &trades, &pool_changes, &principal(USER), 6, 8);
+        assert_eq!(lines[1].balance, 590);
+        assert_eq!(
+            to_csv(&lines[1..]),
+            format!(
+                "timestamp,kind,id,token,amount,balance\n7,fee,{},{},-10,590\n",
+                withdrawal.id,
+                principal(TOKEN_A)
+            )
+        );
+
+        let lines = statement(&journal, &trades, &pool_changes, &principal(USER), 8, 10);
+        let summary: Vec<(StatementEntryKind, u64, u8, i128, i128)> = lines
+            .iter()
+            .map(|l| (l.kind, l.id, l.token.as_slice()[0], l.amount, l.balance))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (StatementEntryKind::Swap, 0, TOKEN_A, -90, 500),
+                (StatementEntryKind::Swap, 0, TOKEN_B, 40, 238),
+                (StatementEntryKind::Liquidity, 2, TOKEN_A, -100, 400),
+                (StatementEntryKind::Liquidity, 2, TOKEN_B, -38, 200),
+            ]
+        );
+
+        // Pages of two lines resume within the lines of the trade at 5.
+        let lines = statement(&journal, &trades, &pool_changes, &principal(USER), 0, 10);
+        let mut pages = Vec::new();
+        let (mut start, mut skip) = (0, 0);
+        loop {
+            let (lines, next) = page(&lines, start, skip, 2);
+            pages.push(
+                lines
+                    .iter()
+                    .map(|l| (l.timestamp, l.balance))
+                    .collect::<Vec<_>>(),
+            );
+            match next {
+                Some(next) => (start, skip) = (next.start, next.skip),
+                None => break,
+            }
+            if pages.len() == 1 {
+                assert_eq!(next, Some(StatementCursor { start: 5, skip: 1 }));
+            }
+        }
+        assert_eq!(
+            pages,
+            vec![
+                vec![(2, 1_000), (5, 900)],
+                vec![(5, 200), (5, 198)],
+                vec![(7, 600), (7, 590)],
+                vec![(8, 500), (8, 238)],
+                vec![(9, 400), (9, 200)],
+            ]
+        );
+        let (lines, next) = page(&lines, 8, 1, 10);
+        assert_eq!((lines.len(), next), (3, None));
+    }
+}
@@ -0,0 +1,13 @@
+use candid::Principal;
+
+// A principal made of one byte, for tests.
+pub fn principal(id: u8) -> Principal {
+    Principal::from_slice(&[id])
+}
+
+// A principal for each of more users than `principal` tells apart. The last
+// byte is not one of the reserved kinds of principal, such as anonymous.
+pub fn numbered_principal(id: u32) -> Principal {
+    let [a, b, c, d] = id.to_be_bytes();
+    Principal::from_slice(&[a, b, c, d, 1])
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+use crate::types::*;
+
+// Metadata cached when a token is listed, so that deposits and withdrawals don't
+// have to ask the token canister for it.
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct TokenState {
+    pub standard: TokenStandard,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: u128,
+    // Delisted tokens can still be withdrawn, but not deposited or traded.
+    pub listed: bool,
+}
+
+impl TokenState {
+    pub fn to_info(&self, token: Principal) -> TokenInfo {
+        TokenInfo {
+            token,
+            standard: self.standard,
+            symbol: self.symbol.clone(),
+            decimals: self.decimals,
+            fee: self.fee.into(),
+            listed: self.listed,
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize, Default)]
+pub struct TokenRegistry(pub HashMap<Principal, TokenState>);
+
+impl TokenRegistry {
+    pub fn get(&self, token: &Principal) -> Option<&TokenState> {
+        self.0.get(token)
+    }
+
+    pub fn is_listed(&self, token: &Principal) -> bool {
+        self.get(token).is_some_and(|t| t.listed)
+    }
+
+    pub fn list(&mut self, token: Principal, state: TokenState) {
+        self.0.insert(
+            token,
+            TokenState {
+                listed: true,
+                ..state
+            },
+        );
+    }
+
+    // Returns false if the token was never listed.
+    pub fn delist(&mut self, token: &Principal) -> bool {
+        match self.0.get_mut(token) {
+            Some(t) => {
+                t.listed = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Replaces the cached metadata of a known token, keeping whether it is listed.
+    pub fn refresh(&mut self, token: Principal, state: TokenState) -> bool {
+        match self.0.get_mut(&token) {
+            Some(t) => {
+                *t = TokenState {
+                    listed: t.listed,
+                    ..state
+                };
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn listed(&self) -> Vec<Principal> {
+        self.0
+            .iter()
+            .filter(|(_, t)| t.listed)
+            .map(|(token, _)| *token)
+            .collect()
+    }
+
+    pub fn tokens(&self) -> Vec<TokenInfo> {
+        let mut tokens: Vec<TokenInfo> = self.0.iter().map(|(k, v)| v.to_info(*k)).collect();
+        tokens.sort_by_key(|t| t.token);
+        tokens
+    }
+
+    // Every pair of listed tokens, each in the order of `utils::pair_key`.
+    pub fn tradable_pairs(&self) -> Vec<Pair> {
+        let mut listed: Vec<Principal> = self
+            .0
+            .iter()
+            .filter(|(_, t)| t.listed)
+            .map(|(k, _)| *k)
+            .collect();
+        listed.sort();
+
+        let mut pairs = Vec::new();
+        for (i, a) in listed.iter().enumerate() {
+            for b in &listed[i + 1..] {
+                pairs.push(Pair {
+                    tokenA: *a,
+                    tokenB: *b,
+                });
+            }
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+
+    fn token_state(symbol: &str) -> TokenState {
+        TokenState {
+            standard: TokenStandard::ICRC2,
+            symbol: symbol.to_string(),
+            decimals: 8,
+            fee: 10,
+            listed: false,
+        }
+    }
+
+    #[test]
+    fn only_listed_tokens_are_tradable() {
+        let mut tokens = TokenRegistry::default();
+        tokens.list(principal(3), token_state("C"));
+        tokens.list(principal(1), token_state("A"));
+        tokens.list(principal(2), token_state("B"));
+        assert!(tokens.delist(&principal(2)));
+        assert!(!tokens.delist(&principal(4)));
+
+        let pairs = tokens.tradable_pairs();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(
+            (pairs[0].tokenA, pairs[0].tokenB),
+            (principal(1), principal(3))
+        );
+        assert!(tokens.get(&principal(2)).is_some());
+        assert!(!tokens.is_listed(&principal(2)));
+    }
+
+    #[test]
+    fn refresh_keeps_the_listing() {
+        let mut tokens = TokenRegistry::default();
+        assert!(!tokens.refresh(principal(1), token_state("A")));
+        tokens.list(principal(1), token_state("A"));
+        tokens.delist(&principal(1));
+
+        let mut refreshed = token_state("A2");
+        refreshed.fee = 20;
+        refreshed.listed = true;
+        assert!(tokens.refresh(principal(1), refreshed));
+        let token = tokens.get(&principal(1)).unwrap();
+        assert_eq!((token.symbol.as_str(), token.fee), ("A2", 20));
+        assert!(!token.listed);
+    }
+}
@@ -0,0 +1,462 @@
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::ops::Bound;
+
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::MemoryManager;
+use ic_stable_structures::{BoundedStorable, DefaultMemoryImpl, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+
+use crate::stable::{principal_key, Memory, PrincipalKey, TRADES, TRADES_BY_PAIR, TRADES_BY_USER};
+use crate::types::*;
+use crate::utils::pair_key;
+
+// Upper bound on the number of trades returned by one page.
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(CandidType, Clone, Deserialize, Serialize, Copy)]
+pub struct TradeState {
+    pub id: TradeId,
+    pub timestamp: u64,
+    pub taker_order_id: OrderId,
+    pub taker: Principal,
+    pub taker_token_canister_id: Principal,
+    // Tokens paid by the taker.
+    pub taker_amount: u128,
+    // Taken from the maker's tokens that the taker receives.
+    pub taker_fee: u128,
+    pub maker_order_id: OrderId,
+    pub maker: Principal,
+    pub maker_token_canister_id: Principal,
+    // Tokens paid by the maker.
+    pub maker_amount: u128,
+    // Taken from the taker's tokens that the maker receives.
+    pub maker_fee: u128,
+}
+
+impl TradeState {
+    // The amounts of `base` and of the other token exchanged at the maker's price,
+    // or None if `base` is not traded.
+    pub fn base_quote_amounts(&self, base: &Principal) -> Option<(u128, u128)> {
+        if self.taker_token_canister_id == *base {
+            Some((self.taker_amount, self.maker_amount))
+        } else if self.maker_token_canister_id == *base {
+            Some((self.maker_amount, self.taker_amount))
+        } else {
+            None
+        }
+    }
+
+    // Price of `base` in the other token of the trade.
+    pub fn price(&self, base: &Principal) -> Option<f64> {
+        self.base_quote_amounts(base)
+            .filter(|(base_amount, _)| *base_amount > 0)
+            .map(|(base_amount, quote_amount)| quote_amount as f64 / base_amount as f64)
+    }
+}
+
+impl From<TradeState> for Trade {
+    fn from(t: TradeState) -> Trade {
+        Trade {
+            id: t.id,
+            timestamp: t.timestamp,
+            takerOrderId: t.taker_order_id,
+            taker: t.taker,
+            takerToken: t.taker_token_canister_id,
+            takerAmount: t.taker_amount.into(),
+            takerFee: t.taker_fee.into(),
+            makerOrderId: t.maker_order_id,
+            maker: t.maker,
+            makerToken: t.maker_token_canister_id,
+            makerAmount: t.maker_amount.into(),
+            makerFee: t.maker_fee.into(),
+        }
+    }
+}
+
+// Trades are encoded with candid like orders. This bounds their encoding with
+// room to spare.
+const MAX_TRADE_SIZE: u32 = 1024;
+
+impl Storable for TradeState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), TradeState).unwrap()
+    }
+}
+
+impl BoundedStorable for TradeState {
+    const MAX_SIZE: u32 = MAX_TRADE_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+type PairKey = (PrincipalKey, PrincipalKey);
+
+// Trades are appended in execution order, so a trade's id is its position in the log.
+// The log only grows, so it is kept in stable memory. The indices hold trade ids
+// per user and per pair, newest first, since stable maps are only iterated forward.
+pub struct TradeLog {
+    trades: StableBTreeMap<TradeId, TradeState, Memory>,
+    by_user: StableBTreeMap<(PrincipalKey, Reverse<TradeId>), (), Memory>,
+    by_pair: StableBTreeMap<(PairKey, Reverse<TradeId>), (), Memory>,
+}
+
+// The trade log as earlier versions saved it on upgrades. Its trades are moved
+// into the stable maps when restored.
+#[derive(CandidType, Deserialize, Default)]
+pub struct SavedTradeLog {
+    pub trades: Vec<TradeState>,
+}
+
+fn pair_index_key(token_a: Principal, token_b: Principal) -> PairKey {
+    let (a, b) = pair_key(token_a, token_b);
+    (principal_key(&a), principal_key(&b))
+}
+
+impl TradeLog {
+    pub fn init(memory_manager: &MemoryManager<DefaultMemoryImpl>, saved: SavedTradeLog) -> Self {
+        let mut log = TradeLog {
+            trades: StableBTreeMap::init(memory_manager.get(TRADES)),
+            by_user: StableBTreeMap::init(memory_manager.get(TRADES_BY_USER)),
+            by_pair: StableBTreeMap::init(memory_manager.get(TRADES_BY_PAIR)),
+        };
+        for trade in saved.trades {
+            log.record(trade);
+        }
+        log
+    }
+
+    pub fn next_id(&self) -> TradeId {
+        self.trades.len() as TradeId
+    }
+
+    pub fn record(&mut self, trade: TradeState) {
+        debug_assert_eq!(trade.id, self.next_id());
+        self.by_user
+            .insert((principal_key(&trade.taker), Reverse(trade.id)), ());
+        if trade.maker != trade.taker {
+            self.by_user
+                .insert((principal_key(&trade.maker), Reverse(trade.id)), ());
+        }
+        let pair = pair_index_key(trade.taker_token_canister_id, trade.maker_token_canister_id);
+        self.by_pair.insert((pair, Reverse(trade.id)), ());
+        self.trades.insert(trade.id, trade);
+    }
+
+    pub fn by_user(&self, user: &Principal, before: Option<TradeId>, limit: usize) -> Vec<Trade> {
+        let user = principal_key(user);
+        let ids = self
+            .by_user
+            .range((start_bound(user, before), Bound::Unbounded))
+            .take_while(move |((u, _), _)| *u == user)
+            .map(|((_, id), _)| id.0);
+        self.page(ids, limit)
+    }
+
+    pub fn by_pair(
+        &self,
+        token_a: Principal,
+        token_b: Principal,
+        before: Option<TradeId>,
+        limit: usize,
+    ) -> Vec<Trade> {
+        let ids = self
+            .pair_ids(token_a, token_b, before)
+            .map(|((_, id), _)| id.0);
+        self.page(ids, limit)
+    }
+
+    // All trades of a user, oldest first.
+    pub fn user_trades(&self, user: &Principal) -> Vec<TradeState> {
+        let user = principal_key(user);
+        let mut trades: Vec<TradeState> = self
+            .by_user
+            .range((start_bound(user, None), Bound::Unbounded))
+            .take_while(|((u, _), _)| *u == user)
+            .map(|((_, id), _)| self.trades.get(&id.0).unwrap())
+            .collect();
+        trades.reverse();
+        trades
+    }
+
+    // Trades of a pair executed in [start, end), oldest first.
+    pub fn pair_trades_between(
+        &self,
+        token_a: Principal,
+        token_b: Principal,
+        start: u64,
+        end: u64,
+    ) -> Vec<TradeState> {
+        // Timestamps never decrease along the log, so the trades executed
+        // before `end` are those below the first id executed at or after it.
+        let mut trades: Vec<TradeState> = self
+            .pair_ids(token_a, token_b, Some(self.first_executed_at(end)))
+            .map(|((_, id), _)| self.trades.get(&id.0).unwrap())
+            .take_while(|t| t.timestamp >= start)
+            .collect();
+        trades.reverse();
+        trades
+    }
+
+    // The latest trades of a pair, newest first.
+    pub fn recent_pair_trades(
+        &self,
+        token_a: Principal,
+        token_b: Principal,
+        limit: usize,
+    ) -> impl Iterator<Item = TradeState> + '_ {
+        self.pair_ids(token_a, token_b, None)
+            .take(limit)
+            .map(move |((_, id), _)| self.trades.get(&id.0).unwrap())
+    }
+
+    // All trades, oldest first.
+    #[cfg(test)]
+    pub fn iter(&self) -> impl Iterator<Item = TradeState> + '_ {
+        self.trades.iter().map(|(_, t)| t)
+    }
+
+    pub fn last_pair_trade(&self, token_a: Principal, token_b: Principal) -> Option<TradeState> {
+        self.recent_pair_trades(token_a, token_b, 1).next()
+    }
+
+    // Open, high, low, close and volume of `base` priced in `quote` per `interval`
+    // nanoseconds. Intervals without trades are left out.
+    pub fn candles(
+        &self,
+        base: Principal,
+        quote: Principal,
+        interval: u64,
+        start: u64,
+        end: u64,
+    ) -> Vec<Candle> {
+        let mut candles: Vec<Candle> = Vec::new();
+        if interval == 0 {
+            return candles;
+        }
+        for trade in self.pair_trades_between(base, quote, start, end) {
+            let (volume, price) = match (trade.base_quote_amounts(&base), trade.price(&base)) {
+                (Some((volume, _)), Some(price)) => (volume, price),
+                _ => continue,
+            };
+            let timestamp = start + (trade.timestamp - start) / interval * interval;
+            match candles.last_mut() {
+                Some(candle) if candle.timestamp == timestamp => {
+                    candle.high = candle.high.max(price);
+                    candle.low = candle.low.min(price);
+                    candle.close = price;
+                    candle.volume += Nat::from(volume);
+                }
+                _ => candles.push(Candle {
+                    timestamp,
+                    open: price,
+                    high: price,
+                    low: price,
+                    close: price,
+                    volume: volume.into(),
+                }),
+            }
+        }
+        candles
+    }
+
+    // The index entries of a pair, newest first, starting below `before` if given.
+    fn pair_ids(
+        &self,
+        token_a: Principal,
+        token_b: Principal,
+        before: Option<TradeId>,
+    ) -> impl Iterator<Item = ((PairKey, Reverse<TradeId>), ())> + '_ {
+        let pair = pair_index_key(token_a, token_b);
+        self.by_pair
+            .range((start_bound(pair, before), Bound::Unbounded))
+            .take_while(move |((p, _), _)| *p == pair)
+    }
+
+    // The id of the first trade executed at or after `timestamp`.
+    fn first_executed_at(&self, timestamp: u64) -> TradeId {
+        let (mut low, mut high) = (0, self.next_id());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.trades.get(&mid).unwrap().timestamp < timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    fn page(&self, ids: impl Iterator<Item = TradeId>, limit: usize) -> Vec<Trade> {
+        ids.take(limit.min(MAX_PAGE_SIZE))
+            .map(|id| self.trades.get(&id).unwrap().into())
+            .collect()
+    }
+}
+
+// Where a newest first scan of an index starts: below the trade id `before` if
+// given, else at the newest trade of `key`.
+fn start_bound<K: Clone>(key: K, before: Option<TradeId>) -> Bound<(K, Reverse<TradeId>)> {
+    match before {
+        Some(before) => Bound::Excluded((key, Reverse(before))),
+        None => Bound::Included((key, Reverse(TradeId::MAX))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+    use crate::utils::nat_to_u128;
+
+    const USER: u8 = 1;
+    const OTHER: u8 = 2;
+    const TOKEN_A: u8 = 3;
+    const TOKEN_B: u8 = 4;
+    const TOKEN_C: u8 = 5;
+
+    fn trade_log() -> TradeLog {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        TradeLog::init(&memory_manager, SavedTradeLog::default())
+    }
+
+    // Records a trade between `taker` and `maker`, who pay the given amounts.
+    fn record(
+        log: &mut TradeLog,
+        timestamp: u64,
+        (taker, taker_token, taker_amount): (u8, u8, u128),
+        (maker, maker_token, maker_amount): (u8, u8, u128),
+    ) {
+        log.record(TradeState {
+            id: log.next_id(),
+            timestamp,
+            taker_order_id: 0,
+            taker: principal(taker),
+            taker_token_canister_id: principal(taker_token),
+            taker_amount,
+            taker_fee: 0,
+            maker_order_id: 0,
+            maker: principal(maker),
+            maker_token_canister_id: principal(maker_token),
+            maker_amount,
+            maker_fee: 0,
+        });
+    }
+
+    fn ids(trades: &[Trade]) -> Vec<TradeId> {
+        trades.iter().map(|t| t.id).collect()
+    }
+
+    #[test]
+    fn trades_are_paged_newest_first() {
+        let mut log = trade_log();
+        for timestamp in 0..5 {
+            record(
+                &mut log,
+                timestamp,
+                (USER, TOKEN_A, 10),
+                (OTHER, TOKEN_B, 20),
+            );
+        }
+        record(&mut log, 5, (OTHER, TOKEN_C, 10), (OTHER, TOKEN_A, 10));
+        let (user, other) = (principal(USER), principal(OTHER));
+
+        assert_eq!(ids(&log.by_user(&user, None, 2)), vec![4, 3]);
+        assert_eq!(ids(&log.by_user(&user, Some(3), 2)), vec![2, 1]);
+        assert_eq!(ids(&log.by_user(&user, Some(1), 10)), vec![0]);
+        assert!(log.by_user(&user, Some(0), 10).is_empty());
+        assert!(log.by_user(&user, None, 0).is_empty());
+        // A trade with oneself is listed once.
+        assert_eq!(ids(&log.by_user(&other, None, 10)), vec![5, 4, 3, 2, 1, 0]);
+
+        let (a, b, c) = (principal(TOKEN_A), principal(TOKEN_B), principal(TOKEN_C));
+        assert_eq!(ids(&log.by_pair(a, b, Some(4), 3)), vec![3, 2, 1]);
+        assert_eq!(ids(&log.by_pair(b, a, Some(4), 3)), vec![3, 2, 1]);
+        assert_eq!(ids(&log.by_pair(c, a, None, 10)), vec![5]);
+        assert!(log.by_pair(b, c, None, 10).is_empty());
+
+        for timestamp in 6..(6 + MAX_PAGE_SIZE as u64) {
+            record(
+                &mut log,
+                timestamp,
+                (USER, TOKEN_A, 10),
+                (OTHER, TOKEN_B, 20),
+            );
+        }
+        let page = log.by_user(&user, None, MAX_PAGE_SIZE + 1);
+        assert_eq!(page.len(), MAX_PAGE_SIZE);
+        assert_eq!(page[0].id, log.next_id() - 1);
+    }
+
+    #[test]
+    fn pair_trades_are_selected_by_time() {
+        let mut log = trade_log();
+        for timestamp in [10, 20, 20, 30].iter() {
+            record(
+                &mut log,
+                *timestamp,
+                (USER, TOKEN_A, 1),
+                (OTHER, TOKEN_B, 1),
+            );
+            if *timestamp == 20 {
+                record(&mut log, 25, (USER, TOKEN_A, 1), (OTHER, TOKEN_C, 1));
+            }
+        }
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+        let between = |token_a, token_b, start, end| -> Vec<TradeId> {
+            log.pair_trades_between(token_a, token_b, start, end)
+                .iter()
+                .map(|t| t.id)
+                .collect()
+        };
+
+        assert_eq!(between(a, b, 20, 30), vec![1, 3]);
+        assert_eq!(between(b, a, 0, 31), vec![0, 1, 3, 5]);
+        assert_eq!(between(a, principal(TOKEN_C), 0, 31), vec![2, 4]);
+        assert!(between(a, b, 0, 10).is_empty());
+        assert!(between(a, b, 31, 40).is_empty());
+    }
+
+    fn summary(candles: &[Candle]) -> Vec<(u64, f64, f64, f64, f64, u128)> {
+        candles
+            .iter()
+            .map(|c| {
+                let volume = nat_to_u128(c.volume.clone());
+                (c.timestamp, c.open, c.high, c.low, c.close, volume)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn candles_bucket_trades_by_interval() {
+        let mut log = trade_log();
+        // A trades at 2, 3 and 1 B.
+        record(&mut log, 1, (USER, TOKEN_A, 10), (OTHER, TOKEN_B, 20));
+        record(&mut log, 5, (USER, TOKEN_B, 30), (OTHER, TOKEN_A, 10));
+        record(&mut log, 25, (USER, TOKEN_A, 10), (OTHER, TOKEN_B, 10));
+        let (a, b) = (principal(TOKEN_A), principal(TOKEN_B));
+
+        // The interval from 10 to 20 has no trades and is left out.
+        assert_eq!(
+            summary(&log.candles(a, b, 10, 0, 40)),
+            vec![(0, 2.0, 3.0, 2.0, 3.0, 20), (20, 1.0, 1.0, 1.0, 1.0, 10)]
+        );
+        assert_eq!(
+            summary(&log.candles(b, a, 10, 0, 40)),
+            vec![
+                (0, 0.5, 0.5, 1.0 / 3.0, 1.0 / 3.0, 50),
+                (20, 1.0, 1.0, 1.0, 1.0, 10)
+            ]
+        );
+        // Intervals start at `start`.
+        assert_eq!(
+            summary(&log.candles(a, b, 10, 5, 40)),
+            vec![(5, 3.0, 3.0, 3.0, 3.0, 10), (25, 1.0, 1.0, 1.0, 1.0, 10)]
+        );
+        assert!(log.candles(a, b, 10, 30, 40).is_empty());
+        assert!(log.candles(a, b, 0, 0, 40).is_empty());
+    }
+}
@@ -1,6 +1,14 @@
-use candid::{CandidType, Nat, Principal};
+use candid::{CandidType, Deserialize, Int, Nat, Principal};
+use serde::Serialize;
+
+use ic_ledger_types::AccountIdentifier;
+
+use crate::icrc::Account;
 
 pub type OrderId = u32;
+pub type TradeId = u64;
+pub type OperationId = u64;
+pub type DepositAccountIndex = u16;
 
 #[allow(non_snake_case)]
 #[derive(CandidType, Clone)]
@@ -11,13 +19,363 @@ pub struct Order {
     pub fromAmount: Nat,
     pub to: Principal,
     pub toAmount: Nat,
+    pub expiresAt: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum OrderType {
+    // Rests on the book until it is filled or cancelled.
+    Limit,
+    // Rests on the book until the given time (nanoseconds since the epoch).
+    GoodTillTime(u64),
+    // Fills what it can immediately, the remainder is cancelled.
+    ImmediateOrCancel,
+    // Fills completely and immediately, or not at all.
+    FillOrKill,
+    // Like ImmediateOrCancel, but `toAmount` may be zero to take any price.
+    // Otherwise it is the slippage bound: no fill is worse than fromAmount/toAmount.
+    Market,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum TriggerCondition {
+    // Triggers when the price falls to or below the trigger price.
+    StopLoss,
+    // Triggers when the price rises to or above the trigger price.
+    TakeProfit,
+}
+
+// The price is that of the order's `from` token in its `to` token, as of the
+// last trade in the pair.
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize)]
+pub struct Trigger {
+    pub condition: TriggerCondition,
+    pub price: f64,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct ConditionalOrder {
+    pub order: Order,
+    pub orderType: OrderType,
+    pub trigger: Trigger,
+}
+
+// What became of a conditional order once a trade triggered it.
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct TriggeredOrder {
+    pub order: ConditionalOrder,
+    pub triggeredAt: u64,
+    pub status: OrderStatus,
+}
+
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize, PartialEq)]
+pub enum OrderStatus {
+    Open,
+    Filled,
+    Cancelled,
+    // A triggered conditional order that could not be placed.
+    Rejected,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct Fill {
+    pub orderId: OrderId,
+    pub counterparty: Principal,
+    pub fromAmount: Nat,
+    pub toAmount: Nat,
+    pub fee: Nat,
+}
+
+#[derive(CandidType, Clone)]
+pub struct OrderPlacement {
+    pub id: OrderId,
+    pub status: OrderStatus,
+    pub fills: Vec<Fill>,
+    pub order: Option<Order>,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct Trade {
+    pub id: TradeId,
+    pub timestamp: u64,
+    pub takerOrderId: OrderId,
+    pub taker: Principal,
+    pub takerToken: Principal,
+    pub takerAmount: Nat,
+    pub takerFee: Nat,
+    pub makerOrderId: OrderId,
+    pub maker: Principal,
+    pub makerToken: Principal,
+    pub makerAmount: Nat,
+    pub makerFee: Nat,
+}
+
+#[derive(CandidType, Clone)]
+pub struct Candle {
+    pub timestamp: u64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: Nat,
+}
+
+// The orders at one price of `base` in `quote`, with the amount of `base` they trade.
+#[derive(CandidType, Clone)]
+pub struct PriceLevel {
+    pub price: f64,
+    pub amount: Nat,
+    pub orders: u32,
+}
+
+// Bids buy `base` and asks sell it, each best price first.
+#[derive(CandidType, Clone)]
+pub struct OrderBookDepth {
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct TopOfBook {
+    pub bestBid: Option<PriceLevel>,
+    pub bestAsk: Option<PriceLevel>,
+    pub spread: Option<f64>,
+}
+
+// The result of clearing a batch auction. The price is that of `base` in
+// `quote`, and None if the book didn't cross.
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct AuctionClearing {
+    pub timestamp: u64,
+    pub price: Option<f64>,
+    pub volume: Nat,
+    pub quoteVolume: Nat,
+    pub trades: u32,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct BatchAuction {
+    pub base: Principal,
+    pub quote: Principal,
+    pub epoch: u64,
+    pub nextClearing: u64,
+    pub lastClearing: Option<AuctionClearing>,
+}
+
+// The last price of `base` in `quote`, and the volumes traded in the last 24 hours.
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct Ticker {
+    pub lastPrice: Option<f64>,
+    pub volume: Nat,
+    pub quoteVolume: Nat,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum SwapVenue {
+    OrderBook,
+    Pool,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct SwapLeg {
+    pub from: Principal,
+    pub to: Principal,
+    pub venue: SwapVenue,
+    pub amountIn: Nat,
+    pub amountOut: Nat,
+}
+
+// The legs of a swap in order, each spending all that the previous one received.
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct SwapRoute {
+    pub legs: Vec<SwapLeg>,
+    pub amountOut: Nat,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct Pool {
+    pub tokenA: Principal,
+    pub tokenB: Principal,
+    pub reserveA: Nat,
+    pub reserveB: Nat,
+    pub totalShares: Nat,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct LiquidityPosition {
+    pub tokenA: Principal,
+    pub tokenB: Principal,
+    pub shares: Nat,
+    pub amountA: Nat,
+    pub amountB: Nat,
+}
+
+// The most open orders, conditional ones included, that a user and a pair may
+// have. Zero means no limit.
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize)]
+pub struct OrderLimits {
+    pub maxOrdersPerUser: u32,
+    pub maxOrdersPerPair: u32,
+}
+
+// Fees in basis points of the tokens received in a fill.
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone, Copy, Deserialize, Serialize, Default)]
+pub struct TradingFees {
+    pub makerFeeBps: u32,
+    pub takerFeeBps: u32,
+}
+
+// The interface a token canister is called through for deposits and withdrawals.
+// ICP and ICRC1 tokens are deposited by transfer to a subaccount of the exchange,
+// DIP20 and ICRC2 tokens by approval.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum TokenStandard {
+    DIP20,
+    ICP,
+    ICRC1,
+    ICRC2,
+}
+
+#[derive(CandidType, Clone)]
+pub struct TokenInfo {
+    pub token: Principal,
+    pub standard: TokenStandard,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: Nat,
+    pub listed: bool,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct Pair {
+    pub tokenA: Principal,
+    pub tokenB: Principal,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType)]
+pub struct ExchangeStatus {
+    pub owner: Option<Principal>,
+    pub withdrawOnly: bool,
+    pub pausedPairs: Vec<Pair>,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum OperationKind {
+    Deposit,
+    Withdraw,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub enum OperationStatus {
+    // The transfer was sent, but it is not known whether it was executed.
+    Pending,
+    Completed,
+    Failed,
+}
+
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct Operation {
+    pub id: OperationId,
+    pub kind: OperationKind,
+    pub owner: Principal,
+    pub token: Principal,
+    pub amount: Nat,
+    pub fee: Nat,
+    pub account: Option<Account>,
+    pub createdAt: u64,
+    pub status: OperationStatus,
+    pub settledAt: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq)]
+pub enum StatementEntryKind {
+    Deposit,
+    Withdrawal,
+    Trade,
+    Fee,
+    Swap,
+    Liquidity,
+}
+
+// A change of a balance. `id` is the operation id of deposits and withdrawals,
+// the trade id of trades and the pool change id of pool swaps and liquidity.
+// `balance` is the balance of the token after it.
+#[derive(CandidType, Clone)]
+pub struct StatementEntry {
+    pub timestamp: u64,
+    pub kind: StatementEntryKind,
+    pub id: u64,
+    pub token: Principal,
+    pub amount: Int,
+    pub balance: Int,
+}
+
+// Where the next page of a statement starts: at `start`, after the first `skip`
+// entries of that time.
+#[derive(CandidType, Clone, Copy, Debug, PartialEq)]
+pub struct StatementCursor {
+    pub start: u64,
+    pub skip: u32,
+}
+
+#[derive(CandidType, Clone)]
+pub struct StatementPage {
+    pub entries: Vec<StatementEntry>,
+    pub next: Option<StatementCursor>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+// A subaccount of the exchange that tokens of the ICP and ICRC standards can be
+// sent to, to be credited to `owner`. `accountId` is its ICP account identifier.
+#[allow(non_snake_case)]
+#[derive(CandidType, Clone)]
+pub struct DepositAccount {
+    pub index: DepositAccountIndex,
+    pub label: String,
+    pub account: Account,
+    pub accountId: AccountIdentifier,
 }
 
 #[derive(CandidType, Clone)]
 pub struct Balance {
     pub owner: Principal,
     pub token: Principal,
+    // The free balance, which can be withdrawn.
     pub amount: Nat,
+    // The balance held by open orders.
+    pub reserved: Nat,
 }
 
 pub type CancelOrderReceipt = Result<OrderId, CancelOrderErr>;
@@ -33,15 +391,34 @@ pub type DepositReceipt = Result<Nat, DepositErr>;
 #[derive(CandidType)]
 pub enum DepositErr {
     BalanceLow,
+    CallFailure,
+    // The block is not a transfer to a deposit account of the caller.
+    InvalidBlock,
+    OperationInProgress,
     TransferFailure,
+    UnlistedToken,
+    WithdrawOnly,
 }
 
-pub type OrderPlacementReceipt = Result<Option<Order>, OrderPlacementErr>;
+pub type DepositAccountReceipt = Result<DepositAccount, DepositAccountErr>;
+
+#[derive(CandidType, Debug)]
+pub enum DepositAccountErr {
+    InvalidLabel,
+    TooManyAccounts,
+}
+
+pub type OrderPlacementReceipt = Result<OrderPlacement, OrderPlacementErr>;
+pub type ConditionalOrderReceipt = Result<ConditionalOrder, OrderPlacementErr>;
 
 #[derive(CandidType)]
 pub enum OrderPlacementErr {
+    InsufficientFreeBalance,
     InvalidOrder,
     OrderBookFull,
+    OrderTooSmall,
+    TradingPaused,
+    UnlistedToken,
 }
 
 pub type WithdrawReceipt = Result<Nat, WithdrawErr>;
@@ -49,5 +426,49 @@ pub type WithdrawReceipt = Result<Nat, WithdrawErr>;
 #[derive(CandidType)]
 pub enum WithdrawErr {
     BalanceLow,
+    CallFailure,
+    InvalidAccount,
+    NotAllowed,
+    OperationInProgress,
     TransferFailure,
+    UnlistedToken,
+}
+
+pub type AddLiquidityReceipt = Result<Nat, PoolErr>;
+pub type RemoveLiquidityReceipt = Result<LiquidityPosition, PoolErr>;
+pub type SwapReceipt = Result<Nat, PoolErr>;
+pub type SwapRouteReceipt = Result<SwapRoute, PoolErr>;
+
+#[derive(CandidType, Debug)]
+pub enum PoolErr {
+    BalanceLow,
+    InsufficientLiquidity,
+    InvalidAmount,
+    NoRoute,
+    NotExistingPool,
+    SlippageExceeded,
+    TradingPaused,
+    UnlistedToken,
+}
+
+pub type AdminReceipt = Result<(), AdminErr>;
+
+#[derive(CandidType)]
+pub enum AdminErr {
+    CallFailure,
+    InvalidArgument,
+    NotAllowed,
+}
+
+pub type TokenReceipt = Result<TokenInfo, AdminErr>;
+
+pub type OperationReceipt = Result<Operation, OperationErr>;
+
+#[derive(CandidType)]
+pub enum OperationErr {
+    NotAllowed,
+    NotExistingOperation,
+    // The token can not deduplicate transfers, so the owner has to resolve the operation.
+    NotRetryable,
+    OperationInProgress,
 }
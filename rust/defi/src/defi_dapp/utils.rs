@@ -1,21 +1,51 @@
 use std::convert::TryInto;
 
-use candid::{Nat, Principal};
+use candid::utils::{ArgumentDecoder, ArgumentEncoder};
+use candid::{decode_args, encode_args, Nat, Principal};
+use ic_cdk::api::call::{CallResult, RejectionCode};
 use ic_ledger_types::Subaccount;
 use num_bigint::BigUint;
 
+use crate::types::DepositAccountIndex;
+
 pub fn nat_to_u128(n: Nat) -> u128 {
-    let n: BigUint = n.try_into().unwrap();
+    let n: BigUint = n.into();
     let n: u128 = n.try_into().unwrap();
 
     n
 }
 
-pub fn principal_to_subaccount(principal_id: &Principal) -> Subaccount {
+// The subaccount of the exchange that `owner` deposits to through its deposit
+// account `index`. Principals are at most 29 bytes long, so the index always
+// fits into the last two bytes.
+pub fn deposit_subaccount(owner: &Principal, index: DepositAccountIndex) -> Subaccount {
     let mut subaccount = [0; std::mem::size_of::<Subaccount>()];
-    let principal_id = principal_id.as_slice();
-    subaccount[0] = principal_id.len().try_into().unwrap();
-    subaccount[1..1 + principal_id.len()].copy_from_slice(principal_id);
+    let owner = owner.as_slice();
+    subaccount[0] = owner.len().try_into().unwrap();
+    subaccount[1..1 + owner.len()].copy_from_slice(owner);
+    subaccount[30..].copy_from_slice(&index.to_be_bytes());
 
     Subaccount(subaccount)
 }
+
+// Both tokens of a pair in a fixed order, to key pair data independently of the trade direction.
+pub fn pair_key(a: Principal, b: Principal) -> (Principal, Principal) {
+    if a < b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+// Like `ic_cdk::call`, but a reply that can not be decoded is returned as an error
+// instead of trapping. Its code is `Unknown`, since the call may have had effects.
+pub async fn call<T: ArgumentEncoder, R: for<'a> ArgumentDecoder<'a>>(
+    canister_id: Principal,
+    method: &str,
+    args: T,
+) -> CallResult<R> {
+    let args = encode_args(args).expect("Failed to encode arguments.");
+    let bytes = ic_cdk::api::call::call_raw(canister_id, method, args, 0).await?;
+
+    decode_args(&bytes).map_err(|e| (RejectionCode::Unknown, e.to_string()))
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use candid::CandidType;
+use ic_ledger_types::{AccountIdentifier, BlockIndex, Memo, Tokens};
+use ic_types::Principal;
+use serde::{Deserialize, Serialize};
+
+// The ledger rejects transfers created longer ago than this, and deduplicates
+// the others. It also accepts creation times that are slightly in the future.
+const TRANSACTION_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT: u64 = 60 * 1_000_000_000;
+
+// What makes two transfer requests the same. Only requests that set a creation
+// time are deduplicated, like on the ledger.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub struct TransferKey {
+    pub caller: Principal,
+    pub to: AccountIdentifier,
+    pub amount: Tokens,
+    pub memo: Memo,
+    pub created_at_time: u64,
+}
+
+// The blocks of the transfers that could still be requested again. Transfers
+// without a block were sent to the ledger, whose answer is still awaited or
+// was lost.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RecentTransfers(HashMap<TransferKey, Option<BlockIndex>>);
+
+impl RecentTransfers {
+    pub fn get(&self, key: &TransferKey) -> Option<BlockIndex> {
+        self.0.get(key).copied().flatten()
+    }
+
+    // Whether the transfer was sent to the ledger before, by the same caller.
+    pub fn was_sent(&self, key: &TransferKey) -> bool {
+        self.0.contains_key(key)
+    }
+
+    // Records that a transfer is sent to the ledger.
+    pub fn send(&mut self, key: TransferKey, now: u64) {
+        self.forget_old(now);
+        self.0.entry(key).or_insert(None);
+    }
+
+    // Records the block of a transfer.
+    pub fn record(&mut self, key: TransferKey, block_index: BlockIndex, now: u64) {
+        self.forget_old(now);
+        self.0.insert(key, Some(block_index));
+    }
+
+    // Forgets a transfer that the ledger refused.
+    pub fn remove(&mut self, key: &TransferKey) {
+        self.0.remove(key);
+    }
+
+    // Forgets the transfers that the ledger would now reject as too old.
+    fn forget_old(&mut self, now: u64) {
+        self.0
+            .retain(|k, _| k.created_at_time + TRANSACTION_WINDOW + PERMITTED_DRIFT >= now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+    use ic_ledger_types::DEFAULT_SUBACCOUNT;
+
+    fn key(memo: u64, created_at_time: u64) -> TransferKey {
+        let caller = principal(1);
+        TransferKey {
+            caller,
+            to: AccountIdentifier::new(&caller, &DEFAULT_SUBACCOUNT),
+            amount: Tokens::from_e8s(100),
+            memo: Memo(memo),
+            created_at_time,
+        }
+    }
+
+    #[test]
+    fn recent_transfers_are_remembered() {
+        let mut recent = RecentTransfers::default();
+        recent.record(key(1, 10), 5, 10);
+        assert_eq!(recent.get(&key(1, 10)), Some(5));
+        assert_eq!(recent.get(&key(2, 10)), None);
+        assert_eq!(recent.get(&key(1, 11)), None);
+
+        // Once the ledger window has passed, the transfer is forgotten.
+        recent.record(key(2, 20), 6, 10 + TRANSACTION_WINDOW + PERMITTED_DRIFT);
+        assert_eq!(recent.get(&key(1, 10)), Some(5));
+        recent.record(key(3, 30), 7, 11 + TRANSACTION_WINDOW + PERMITTED_DRIFT);
+        assert_eq!(recent.get(&key(1, 10)), None);
+        assert_eq!(recent.get(&key(2, 20)), Some(6));
+
+        // A transfer that was sent has no block until the ledger answers.
+        recent.send(key(4, 30), 12 + TRANSACTION_WINDOW);
+        assert!(recent.was_sent(&key(4, 30)) && !recent.was_sent(&key(4, 31)));
+        assert_eq!(recent.get(&key(4, 30)), None);
+        recent.remove(&key(4, 30));
+        assert!(!recent.was_sent(&key(4, 30)));
+    }
+}
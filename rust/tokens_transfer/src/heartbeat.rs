@@ -0,0 +1,36 @@
+use ic_cdk_macros::heartbeat;
+
+use crate::schedule::Payment;
+use crate::{request_transfer, PENDING_TRANSFERS, SCHEDULES};
+
+#[heartbeat]
+async fn heartbeat() {
+    expire_pending_transfers();
+    execute_scheduled_transfers().await;
+}
+
+fn expire_pending_transfers() {
+    let now = ic_cdk::api::time();
+    PENDING_TRANSFERS.with(|p| p.borrow_mut().expire(now));
+}
+
+// Makes the payments that are due, each on behalf of the principal that
+// registered its schedule. They wait for approval like any other transfer.
+async fn execute_scheduled_transfers() {
+    let now = ic_cdk::api::time();
+    let due = SCHEDULES.with(|s| {
+        let mut schedules = s.borrow_mut();
+        schedules.remove_finished(now);
+        schedules.take_due(now)
+    });
+
+    for (id, due, owner, args) in due {
+        let result = request_transfer(owner, args).await;
+        let payment = Payment {
+            due,
+            executed_at: ic_cdk::api::time(),
+            result,
+        };
+        SCHEDULES.with(|s| s.borrow_mut().record(id, payment));
+    }
+}
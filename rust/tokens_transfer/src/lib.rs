@@ -1,12 +1,24 @@
 use std::cell::RefCell;
 use std::hash::Hash;
-use candid::{candid_method, CandidType};
+use candid::{candid_method, de::IDLDeserialize, CandidType};
 
 use ic_cdk_macros::*;
-use ic_ledger_types::{AccountIdentifier, BlockIndex, DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID, Memo, Subaccount, Tokens};
+use ic_ledger_types::{AccountIdentifier, BlockIndex, DEFAULT_SUBACCOUNT, MAINNET_LEDGER_CANISTER_ID, Memo, Subaccount, Timestamp, Tokens};
 use ic_types::Principal;
 use serde::{Deserialize, Serialize};
 
+mod dedup;
+mod heartbeat;
+mod multisig;
+mod policy;
+mod schedule;
+#[cfg(test)]
+mod test_utils;
+use dedup::{RecentTransfers, TransferKey};
+use multisig::{ApprovalError, PendingTransfer, PendingTransferStatus, PendingTransfers};
+use policy::{Authorization, Spending};
+use schedule::{Schedule, ScheduleArgs, ScheduleError, Schedules};
+
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug, Hash, PartialEq)]
 pub struct Conf {
     ledger_canister_id: Principal,
@@ -14,7 +26,17 @@ pub struct Conf {
     // to another account identifier. If set to None then the default subaccount will be used.
     // See the [Ledger doc](https://smartcontracts.org/docs/integration/ledger-quick-start.html#_accounts).
     subaccount: Option<Subaccount>,
-    transaction_fee: Tokens
+    transaction_fee: Tokens,
+    // The principals that may transfer tokens. Nobody else can.
+    authorized: Vec<Authorization>,
+    // How much all of them together may spend per day. None means no limit.
+    daily_limit: Option<Tokens>,
+    // The principals that approve transfers, and how many of them must approve
+    // each one. A threshold of zero executes transfers without approval.
+    signers: Vec<Principal>,
+    threshold: u32,
+    // How long a transfer waits for approval, in nanoseconds.
+    approval_period: u64,
 }
 
 impl Default for Conf {
@@ -23,45 +45,482 @@ impl Default for Conf {
             ledger_canister_id: MAINNET_LEDGER_CANISTER_ID,
             subaccount: None,
             transaction_fee: Tokens::from_e8s(10_000),
+            authorized: Vec::new(),
+            daily_limit: None,
+            signers: Vec::new(),
+            threshold: 0,
+            approval_period: 7 * 24 * 60 * 60 * 1_000_000_000,
         }
     }
 }
 
 thread_local! {
     static CONF: RefCell<Conf> = RefCell::new(Conf::default());
+    // The principal that installed the canister, which may change the `Conf`.
+    static OWNER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    static SPENDING: RefCell<Spending> = RefCell::new(Spending::default());
+    static RECENT_TRANSFERS: RefCell<RecentTransfers> = RefCell::new(RecentTransfers::default());
+    static SCHEDULES: RefCell<Schedules> = RefCell::new(Schedules::default());
+    static PENDING_TRANSFERS: RefCell<PendingTransfers> = RefCell::new(PendingTransfers::default());
 }
 
 #[init]
 #[candid_method(init)]
 fn init(conf: Conf) {
+    check_signers(&conf);
+    OWNER.with(|o| o.replace(Some(ic_cdk::caller())));
     CONF.with(|c| c.replace(conf));
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Hash)]
+// The state saved across upgrades. Fields added after the first release that
+// saved it are optional, so that what older releases saved still decodes.
+#[derive(CandidType, Deserialize)]
+struct StableState {
+    conf: SavedConf,
+    owner: Option<Principal>,
+    spending: Spending,
+    recent_transfers: RecentTransfers,
+    schedules: Schedules,
+    pending_transfers: Option<PendingTransfers>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SavedConf {
+    ledger_canister_id: Principal,
+    subaccount: Option<Subaccount>,
+    transaction_fee: Tokens,
+    authorized: Vec<Authorization>,
+    daily_limit: Option<Tokens>,
+    signers: Option<Vec<Principal>>,
+    threshold: Option<u32>,
+    approval_period: Option<u64>,
+}
+
+impl From<Conf> for SavedConf {
+    fn from(conf: Conf) -> Self {
+        SavedConf {
+            ledger_canister_id: conf.ledger_canister_id,
+            subaccount: conf.subaccount,
+            transaction_fee: conf.transaction_fee,
+            authorized: conf.authorized,
+            daily_limit: conf.daily_limit,
+            signers: Some(conf.signers),
+            threshold: Some(conf.threshold),
+            approval_period: Some(conf.approval_period),
+        }
+    }
+}
+
+impl From<SavedConf> for Conf {
+    fn from(saved: SavedConf) -> Self {
+        let default = Conf::default();
+        Conf {
+            ledger_canister_id: saved.ledger_canister_id,
+            subaccount: saved.subaccount,
+            transaction_fee: saved.transaction_fee,
+            authorized: saved.authorized,
+            daily_limit: saved.daily_limit,
+            signers: saved.signers.unwrap_or(default.signers),
+            threshold: saved.threshold.unwrap_or(default.threshold),
+            approval_period: saved.approval_period.unwrap_or(default.approval_period),
+        }
+    }
+}
+
+impl StableState {
+    // Decodes what `pre_upgrade` saved. The memory is empty after an upgrade
+    // from a release without upgrade hooks, which keeps the defaults.
+    fn decode(bytes: &[u8]) -> StableState {
+        if bytes.is_empty() {
+            return StableState {
+                conf: Conf::default().into(),
+                owner: None,
+                spending: Spending::default(),
+                recent_transfers: RecentTransfers::default(),
+                schedules: Schedules::default(),
+                pending_transfers: None,
+            };
+        }
+        let mut de = IDLDeserialize::new(bytes).unwrap();
+        de.get_value().unwrap()
+    }
+}
+
+impl StableState {
+    // Takes the state out of the canister, to save it before an upgrade.
+    fn take() -> StableState {
+        StableState {
+            conf: CONF.with(|c| c.take()).into(),
+            owner: OWNER.with(|o| o.take()),
+            spending: SPENDING.with(|s| s.take()),
+            recent_transfers: RECENT_TRANSFERS.with(|r| r.take()),
+            schedules: SCHEDULES.with(|s| s.take()),
+            pending_transfers: Some(PENDING_TRANSFERS.with(|p| p.take())),
+        }
+    }
+
+    // Puts the state saved before an upgrade back into the canister.
+    fn restore(self) {
+        let StableState {
+            conf,
+            owner,
+            spending,
+            recent_transfers,
+            schedules,
+            pending_transfers,
+        } = self;
+        CONF.with(|c| c.replace(conf.into()));
+        OWNER.with(|o| o.replace(owner));
+        SPENDING.with(|s| s.replace(spending));
+        RECENT_TRANSFERS.with(|r| r.replace(recent_transfers));
+        SCHEDULES.with(|s| s.replace(schedules));
+        PENDING_TRANSFERS.with(|p| p.replace(pending_transfers.unwrap_or_default()));
+    }
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    ic_cdk::storage::stable_save((StableState::take(),)).unwrap();
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    StableState::decode(&ic_cdk::api::stable::stable_bytes()).restore();
+}
+
+fn is_owner(principal: Principal) -> bool {
+    OWNER.with(|o| *o.borrow()) == Some(principal)
+}
+
+fn check_signers(conf: &Conf) {
+    if conf.threshold as usize > conf.signers.len() {
+        ic_cdk::trap("The threshold is higher than the number of signers.");
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_conf() -> Conf {
+    CONF.with(|c| c.borrow().clone())
+}
+
+#[update]
+#[candid_method(update)]
+fn set_conf(conf: Conf) {
+    if !is_owner(ic_cdk::caller()) {
+        ic_cdk::trap("Only the owner can change the configuration.");
+    }
+    check_signers(&conf);
+    CONF.with(|c| c.replace(conf));
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferError {
+    // The caller is not one of the authorized principals.
+    Unauthorized,
+    // The transfer would spend more than the caller may spend today.
+    PrincipalDailyLimitExceeded { remaining: Tokens },
+    // The transfer would spend more than all callers together may spend today.
+    DailyLimitExceeded { remaining: Tokens },
+    LedgerCallFailed(String),
+    Ledger(ic_ledger_types::TransferError),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Hash, PartialEq)]
 pub struct TransferArgs {
     amount: Tokens,
     to_principal: Principal,
     to_subaccount: Option<Subaccount>,
+    // Passed on to the ledger. Memo(0) if not set.
+    memo: Option<Memo>,
+    // Requests with the same arguments and creation time are executed once.
+    created_at_time: Option<Timestamp>,
 }
 
 #[update]
 #[candid_method(update)]
-async fn transfer(args: TransferArgs) -> Result<BlockIndex, String> {
+async fn transfer(args: TransferArgs) -> Result<TransferReceipt, TransferError> {
+    request_transfer(ic_cdk::caller(), args).await
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum TransferReceipt {
+    Executed { block_index: BlockIndex },
+    // The transfer waits for the approval of the signers.
+    Pending { id: u64 },
+}
+
+// Executes a transfer on behalf of `requester`, or holds it for approval if
+// the configuration requires signers.
+async fn request_transfer(requester: Principal, args: TransferArgs) -> Result<TransferReceipt, TransferError> {
+    let conf = CONF.with(|c| c.borrow().clone());
+    if conf.threshold == 0 {
+        return execute_transfer(requester, args)
+            .await
+            .map(|block_index| TransferReceipt::Executed { block_index });
+    }
+    if !conf.authorized.iter().any(|a| a.principal == requester) {
+        return Err(TransferError::Unauthorized);
+    }
+    let now = ic_cdk::api::time();
+    let id = PENDING_TRANSFERS.with(|p| p.borrow_mut().request(requester, args, &conf, now));
+    // A retry of an executed transfer gets its block.
+    if let Some(PendingTransferStatus::Executed(block_index)) = PENDING_TRANSFERS.with(|p| p.borrow().get(id).map(|t| t.status.clone())) {
+        return Ok(TransferReceipt::Executed { block_index });
+    }
+    match execute_if_approved(id, &conf).await {
+        Some(result) => result.map(|block_index| TransferReceipt::Executed { block_index }),
+        None => Ok(TransferReceipt::Pending { id }),
+    }
+}
+
+// Executes a pending transfer if enough signers approved it.
+async fn execute_if_approved(id: u64, conf: &Conf) -> Option<Result<BlockIndex, TransferError>> {
+    let (requester, args) = PENDING_TRANSFERS.with(|p| p.borrow_mut().take_approved(id, conf))?;
+    let result = execute_transfer(requester, args).await;
+    let now = ic_cdk::api::time();
+    PENDING_TRANSFERS.with(|p| p.borrow_mut().finish(id, result.clone(), now));
+    Some(result)
+}
+
+// Transfers on behalf of `caller`, within its limits.
+async fn execute_transfer(caller: Principal, args: TransferArgs) -> Result<BlockIndex, TransferError> {
+    let now = ic_cdk::api::time();
+    let to_subaccount = args.to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT);
+    let to = AccountIdentifier::new(&args.to_principal, &to_subaccount);
+    let memo = args.memo.unwrap_or(Memo(0));
+    let key = args.created_at_time.map(|t| TransferKey {
+        caller,
+        to,
+        amount: args.amount,
+        memo,
+        created_at_time: t.timestamp_nanos,
+    });
+    if let Some(block_index) = key.and_then(|k| RECENT_TRANSFERS.with(|r| r.borrow().get(&k))) {
+        return Ok(block_index);
+    }
+    // Only a transfer of this caller that was sent before may be a duplicate of
+    // it. Others with the same arguments were sent by other callers.
+    let retried = key.is_some_and(|k| RECENT_TRANSFERS.with(|r| r.borrow().was_sent(&k)));
+
+    let spent = args.amount + CONF.with(|conf| conf.borrow().transaction_fee);
+    CONF.with(|conf| SPENDING.with(|s| s.borrow_mut().spend(&conf.borrow(), caller, spent, now)))?;
+
     ic_cdk::println!("Transferring {} tokens to principal {} subaccount {:?}", &args.amount, &args.to_principal, &args.to_subaccount);
     let ledger_canister_id = CONF.with(|conf| conf.borrow().ledger_canister_id);
-    let to_subaccount = args.to_subaccount.unwrap_or(DEFAULT_SUBACCOUNT);
     let transfer_args = CONF.with(|conf| {
         let conf = conf.borrow();
         ic_ledger_types::TransferArgs {
-            memo: Memo(0),
+            memo,
             amount: args.amount,
             fee: conf.transaction_fee,
             from_subaccount: conf.subaccount,
-            to: AccountIdentifier::new(&args.to_principal, &to_subaccount),
-            created_at_time: None,
+            to,
+            created_at_time: args.created_at_time,
         }
     });
-    ic_ledger_types::transfer(ledger_canister_id, transfer_args).await
-        .map_err(|e| format!("failed to call ledger: {:?}", e))?
-        .map_err(|e| format!("ledger transfer error {:?}", e))
+    if let Some(key) = key {
+        RECENT_TRANSFERS.with(|r| r.borrow_mut().send(key, now));
+    }
+    let result = ic_ledger_types::transfer(ledger_canister_id, transfer_args).await
+        .map_err(|e| TransferError::LedgerCallFailed(format!("failed to call ledger: {:?}", e)))?;
+    // A transfer that the ledger rejected spent nothing, and a duplicate was
+    // counted when it was first sent. One whose call failed may still have been
+    // executed, so it stays counted.
+    if result.is_err() {
+        SPENDING.with(|s| s.borrow_mut().refund(caller, spent, now));
+    }
+    let result = ledger_result(result, retried);
+    match (&result, key) {
+        (Ok(block_index), Some(key)) => RECENT_TRANSFERS.with(|r| r.borrow_mut().record(key, *block_index, now)),
+        (Err(_), Some(key)) if !retried => RECENT_TRANSFERS.with(|r| r.borrow_mut().remove(&key)),
+        _ => {}
+    }
+    result
+}
+
+// A duplicate of a transfer that the caller retried is answered like one found
+// in the recent transfers, with the block of the original transfer.
+fn ledger_result(result: Result<BlockIndex, ic_ledger_types::TransferError>, retried: bool) -> Result<BlockIndex, TransferError> {
+    match result {
+        Ok(block_index) => Ok(block_index),
+        Err(ic_ledger_types::TransferError::TxDuplicate { duplicate_of }) if retried => Ok(duplicate_of),
+        Err(e) => Err(TransferError::Ledger(e)),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn schedule_transfer(args: ScheduleArgs) -> Result<u64, ScheduleError> {
+    let caller = ic_cdk::caller();
+    if !CONF.with(|c| c.borrow().authorized.iter().any(|a| a.principal == caller)) {
+        return Err(ScheduleError::Unauthorized);
+    }
+    SCHEDULES.with(|s| s.borrow_mut().add(caller, args, ic_cdk::api::time()))
+}
+
+#[update]
+#[candid_method(update)]
+fn cancel_schedule(id: u64) -> Result<(), ScheduleError> {
+    let caller = ic_cdk::caller();
+    SCHEDULES.with(|s| s.borrow_mut().cancel(id, caller, is_owner(caller), ic_cdk::api::time()))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_schedule(id: u64) -> Option<Schedule> {
+    SCHEDULES.with(|s| s.borrow().get(id).cloned())
+}
+
+#[query]
+#[candid_method(query)]
+fn list_schedules() -> Vec<Schedule> {
+    SCHEDULES.with(|s| s.borrow().values().cloned().collect())
+}
+
+#[update]
+#[candid_method(update)]
+async fn approve_transfer(id: u64) -> Result<PendingTransfer, ApprovalError> {
+    vote(id, true).await
+}
+
+#[update]
+#[candid_method(update)]
+async fn reject_transfer(id: u64) -> Result<PendingTransfer, ApprovalError> {
+    vote(id, false).await
+}
+
+async fn vote(id: u64, approve: bool) -> Result<PendingTransfer, ApprovalError> {
+    let conf = CONF.with(|c| c.borrow().clone());
+    let now = ic_cdk::api::time();
+    PENDING_TRANSFERS.with(|p| p.borrow_mut().vote(id, ic_cdk::caller(), approve, &conf, now))?;
+    execute_if_approved(id, &conf).await;
+    Ok(PENDING_TRANSFERS.with(|p| p.borrow().get(id).cloned()).unwrap())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_pending_transfer(id: u64) -> Option<PendingTransfer> {
+    PENDING_TRANSFERS.with(|p| p.borrow().get(id).cloned())
+}
+
+#[query]
+#[candid_method(query)]
+fn list_pending_transfers() -> Vec<PendingTransfer> {
+    PENDING_TRANSFERS.with(|p| p.borrow().values().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+    use std::collections::HashMap;
+
+    #[test]
+    fn duplicates_return_the_original_block() {
+        let duplicate = ic_ledger_types::TransferError::TxDuplicate { duplicate_of: 2 };
+        assert_eq!(ledger_result(Ok(3), false), Ok(3));
+        assert_eq!(ledger_result(Err(duplicate.clone()), true), Ok(2));
+        // The same transfer, sent by another caller.
+        assert_eq!(
+            ledger_result(Err(duplicate.clone()), false),
+            Err(TransferError::Ledger(duplicate))
+        );
+        assert_eq!(
+            ledger_result(Err(ic_ledger_types::TransferError::TxCreatedInFuture), true),
+            Err(TransferError::Ledger(ic_ledger_types::TransferError::TxCreatedInFuture))
+        );
+    }
+
+    #[test]
+    fn upgrades_keep_the_owner_spending_and_configuration() {
+        let conf = Conf {
+            authorized: vec![Authorization { principal: principal(2), daily_limit: None }],
+            daily_limit: Some(Tokens::from_e8s(100)),
+            signers: vec![principal(3)],
+            threshold: 1,
+            ..Conf::default()
+        };
+        CONF.with(|c| c.replace(conf.clone()));
+        OWNER.with(|o| o.replace(Some(principal(1))));
+        SPENDING.with(|s| s.borrow_mut().spend(&conf, principal(2), Tokens::from_e8s(60), 0)).unwrap();
+
+        let mut bytes = Vec::new();
+        candid::write_args(&mut bytes, (StableState::take(),)).unwrap();
+        assert!(!is_owner(principal(1)));
+        StableState::decode(&bytes).restore();
+
+        assert!(is_owner(principal(1)));
+        assert_eq!(get_conf(), conf);
+        assert_eq!(
+            SPENDING.with(|s| s.borrow_mut().spend(&conf, principal(2), Tokens::from_e8s(60), 1)),
+            Err(TransferError::DailyLimitExceeded { remaining: Tokens::from_e8s(40) })
+        );
+    }
+
+    // What the release that added scheduled transfers saved.
+    #[derive(CandidType)]
+    struct PreviousConf {
+        ledger_canister_id: Principal,
+        subaccount: Option<Subaccount>,
+        transaction_fee: Tokens,
+        authorized: Vec<Authorization>,
+        daily_limit: Option<Tokens>,
+    }
+
+    #[derive(CandidType)]
+    struct PreviousState {
+        conf: PreviousConf,
+        owner: Option<Principal>,
+        spending: Spending,
+        recent_transfers: HashMap<TransferKey, BlockIndex>,
+        schedules: Schedules,
+    }
+
+    #[test]
+    fn older_states_are_restored() {
+        let state = StableState::decode(&[]);
+        assert_eq!(Conf::from(state.conf), Conf::default());
+        assert_eq!(state.owner, None);
+
+        let authorized = vec![Authorization { principal: principal(2), daily_limit: None }];
+        let key = TransferKey {
+            caller: principal(2),
+            to: AccountIdentifier::new(&principal(4), &DEFAULT_SUBACCOUNT),
+            amount: Tokens::from_e8s(10),
+            memo: Memo(0),
+            created_at_time: 5,
+        };
+        let previous = PreviousState {
+            conf: PreviousConf {
+                ledger_canister_id: principal(3),
+                subaccount: None,
+                transaction_fee: Tokens::from_e8s(5),
+                authorized: authorized.clone(),
+                daily_limit: Some(Tokens::from_e8s(100)),
+            },
+            owner: Some(principal(1)),
+            spending: Spending::default(),
+            recent_transfers: vec![(key, 4)].into_iter().collect(),
+            schedules: Schedules::default(),
+        };
+        // The stable memory is a whole number of pages.
+        let mut bytes = Vec::new();
+        candid::write_args(&mut bytes, (previous,)).unwrap();
+        bytes.resize(65_536, 0);
+        let state = StableState::decode(&bytes);
+        assert_eq!(state.owner, Some(principal(1)));
+        assert_eq!(
+            Conf::from(state.conf),
+            Conf {
+                ledger_canister_id: principal(3),
+                transaction_fee: Tokens::from_e8s(5),
+                authorized,
+                daily_limit: Some(Tokens::from_e8s(100)),
+                ..Conf::default()
+            }
+        );
+        assert_eq!(state.recent_transfers.get(&key), Some(4));
+        assert!(state.pending_transfers.is_none());
+    }
 }
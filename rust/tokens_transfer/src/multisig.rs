@@ -0,0 +1,449 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use candid::CandidType;
+use ic_ledger_types::BlockIndex;
+use ic_types::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::{Conf, TransferArgs, TransferError};
+
+// How long transfers are kept after they were executed, rejected or expired.
+const RETENTION: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// The ledger refuses transfers whose creation time is older than this.
+const LEDGER_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum PendingTransferStatus {
+    // Waiting for the approvals of the signers.
+    Pending,
+    // Approved, and the ledger is being called.
+    Executing,
+    Executed(BlockIndex),
+    Failed(TransferError),
+    Rejected,
+    Expired,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingTransfer {
+    pub id: u64,
+    // The principal that requested the transfer. It is made on its behalf,
+    // within its daily limits.
+    pub requester: Principal,
+    pub args: TransferArgs,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub approvals: Vec<Principal>,
+    pub rejections: Vec<Principal>,
+    pub status: PendingTransferStatus,
+    pub finished_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ApprovalError {
+    // The caller is not one of the signers.
+    NotSigner,
+    NotFound,
+    // The transfer was executed, rejected or has expired already.
+    NotPending,
+    AlreadyVoted,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PendingTransfers {
+    next_id: u64,
+    transfers: BTreeMap<u64, PendingTransfer>,
+    // The ids of the transfers that wait for approval.
+    pending: BTreeSet<u64>,
+    // The finished transfers as (finished_at, id), in the order they finished.
+    finished: VecDeque<(u64, u64)>,
+    // The ids of the transfers that set a creation time, by that time.
+    by_created_at_time: BTreeMap<u64, Vec<u64>>,
+}
+
+impl PendingTransfers {
+    // Holds a transfer for approval, unless it retries one that waits, was
+    // executed or was turned down. Like on the ledger, only requests that set a
+    // creation time are retries.
+    pub fn request(
+        &mut self,
+        requester: Principal,
+        args: TransferArgs,
+        conf: &Conf,
+        now: u64,
+    ) -> u64 {
+        if let Some(id) = self.find(requester, &args) {
+            return id;
+        }
+        self.add(requester, args, conf, now)
+    }
+
+    // A requester that is a signer approves the transfer with its request. A
+    // transfer that sets a creation time can't wait beyond the ledger's window.
+    fn add(&mut self, requester: Principal, args: TransferArgs, conf: &Conf, now: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut expires_at = now.saturating_add(conf.approval_period);
+        if let Some(t) = args.created_at_time {
+            expires_at = expires_at.min(t.timestamp_nanos.saturating_add(LEDGER_WINDOW));
+            self.by_created_at_time
+                .entry(t.timestamp_nanos)
+                .or_default()
+                .push(id);
+        }
+        let approvals = if conf.signers.contains(&requester) {
+            vec![requester]
+        } else {
+            Vec::new()
+        };
+        self.transfers.insert(
+            id,
+            PendingTransfer {
+                id,
+                requester,
+                args,
+                created_at: now,
+                expires_at,
+                approvals,
+                rejections: Vec::new(),
+                status: PendingTransferStatus::Pending,
+                finished_at: None,
+            },
+        );
+        self.pending.insert(id);
+        id
+    }
+
+    // The transfer that `args` retries. One whose execution failed may be
+    // requested again.
+    fn find(&self, requester: Principal, args: &TransferArgs) -> Option<u64> {
+        let created_at_time = args.created_at_time?.timestamp_nanos;
+        self.by_created_at_time
+            .get(&created_at_time)?
+            .iter()
+            .map(|id| &self.transfers[id])
+            .find(|t| {
+                t.requester == requester
+                    && &t.args == args
+                    && !matches!(t.status, PendingTransferStatus::Failed(_))
+            })
+            .map(|t| t.id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&PendingTransfer> {
+        self.transfers.get(&id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &PendingTransfer> {
+        self.transfers.values()
+    }
+
+    // Records the vote of a signer, and rejects the transfer once it can no
+    // longer reach the threshold.
+    pub fn vote(
+        &mut self,
+        id: u64,
+        signer: Principal,
+        approve: bool,
+        conf: &Conf,
+        now: u64,
+    ) -> Result<(), ApprovalError> {
+        if !conf.signers.contains(&signer) {
+            return Err(ApprovalError::NotSigner);
+        }
+        let transfer = self.transfers.get_mut(&id).ok_or(ApprovalError::NotFound)?;
+        if transfer.status == PendingTransferStatus::Pending && transfer.expires_at <= now {
+            self.close(id, PendingTransferStatus::Expired, now);
+            return Err(ApprovalError::NotPending);
+        }
+        if transfer.status != PendingTransferStatus::Pending {
+            return Err(ApprovalError::NotPending);
+        }
+        if transfer.approvals.contains(&signer) || transfer.rejections.contains(&signer) {
+            return Err(ApprovalError::AlreadyVoted);
+        }
+        if approve {
+            transfer.approvals.push(signer);
+        } else {
+            transfer.rejections.push(signer);
+            let rejections = count_signers(&transfer.rejections, conf);
+            if conf.signers.len() - rejections < conf.threshold as usize {
+                self.close(id, PendingTransferStatus::Rejected, now);
+            }
+        }
+        Ok(())
+    }
+
+    // Takes a transfer for execution once enough of the current signers
+    // approved it.
+    pub fn take_approved(&mut self, id: u64, conf: &Conf) -> Option<(Principal, TransferArgs)> {
+        let transfer = self.transfers.get_mut(&id)?;
+        if transfer.status != PendingTransferStatus::Pending
+            || count_signers(&transfer.approvals, conf) < conf.threshold as usize
+        {
+            return None;
+        }
+        transfer.status = PendingTransferStatus::Executing;
+        self.pending.remove(&id);
+        Some((transfer.requester, transfer.args.clone()))
+    }
+
+    pub fn finish(&mut self, id: u64, result: Result<BlockIndex, TransferError>, now: u64) {
+        let status = match result {
+            Ok(block_index) => PendingTransferStatus::Executed(block_index),
+            Err(e) => PendingTransferStatus::Failed(e),
+        };
+        self.close(id, status, now);
+    }
+
+    // Expires the transfers that waited too long, and forgets those that
+    // finished before the retention window.
+    pub fn expire(&mut self, now: u64) {
+        let expired: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|id| self.transfers[id].expires_at <= now)
+            .copied()
+            .collect();
+        for id in expired {
+            self.close(id, PendingTransferStatus::Expired, now);
+        }
+        while let Some((finished_at, id)) = self.finished.front().copied() {
+            if finished_at.saturating_add(RETENTION) > now {
+                break;
+            }
+            self.finished.pop_front();
+            self.forget(id);
+        }
+    }
+
+    fn forget(&mut self, id: u64) {
+        let transfer = match self.transfers.remove(&id) {
+            Some(transfer) => transfer,
+            None => return,
+        };
+        if let Some(t) = transfer.args.created_at_time {
+            let ids = self.by_created_at_time.get_mut(&t.timestamp_nanos).unwrap();
+            ids.retain(|i| *i != id);
+            if ids.is_empty() {
+                self.by_created_at_time.remove(&t.timestamp_nanos);
+            }
+        }
+    }
+
+    fn close(&mut self, id: u64, status: PendingTransferStatus, now: u64) {
+        if let Some(transfer) = self.transfers.get_mut(&id) {
+            transfer.status = status;
+            transfer.finished_at = Some(now);
+            self.pending.remove(&id);
+            self.finished.push_back((now, id));
+        }
+    }
+}
+
+// How many of the current signers are among `principals`.
+fn count_signers(principals: &[Principal], conf: &Conf) -> usize {
+    principals
+        .iter()
+        .filter(|p| conf.signers.contains(p))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::{ScheduleArgs, Schedules};
+    use crate::test_utils::principal;
+    use ic_ledger_types::{Timestamp, Tokens};
+
+    fn args() -> TransferArgs {
+        TransferArgs {
+            amount: Tokens::from_e8s(100),
+            to_principal: principal(9),
+            to_subaccount: None,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    fn conf() -> Conf {
+        Conf {
+            signers: vec![principal(1), principal(2), principal(3)],
+            threshold: 2,
+            approval_period: 100,
+            ..Conf::default()
+        }
+    }
+
+    #[test]
+    fn transfers_are_executed_once_approved() {
+        let conf = conf();
+        let mut pending = PendingTransfers::default();
+        let id = pending.request(principal(1), args(), &conf, 0);
+        assert_eq!(pending.take_approved(id, &conf), None);
+
+        assert_eq!(
+            pending.vote(id, principal(4), true, &conf, 1),
+            Err(ApprovalError::NotSigner)
+        );
+        assert_eq!(
+            pending.vote(id, principal(1), true, &conf, 1),
+            Err(ApprovalError::AlreadyVoted)
+        );
+        assert_eq!(pending.vote(id, principal(2), true, &conf, 1), Ok(()));
+        assert_eq!(
+            pending.take_approved(id, &conf),
+            Some((principal(1), args()))
+        );
+        assert_eq!(pending.take_approved(id, &conf), None);
+        assert_eq!(
+            pending.vote(id, principal(3), true, &conf, 1),
+            Err(ApprovalError::NotPending)
+        );
+
+        pending.finish(id, Ok(7), 2);
+        assert_eq!(
+            pending.get(id).unwrap().status,
+            PendingTransferStatus::Executed(7)
+        );
+    }
+
+    #[test]
+    fn only_retries_are_merged() {
+        let conf = conf();
+        let mut pending = PendingTransfers::default();
+        let first = pending.request(principal(1), args(), &conf, 0);
+        assert_ne!(pending.request(principal(1), args(), &conf, 0), first);
+
+        let retried = TransferArgs {
+            created_at_time: Some(Timestamp { timestamp_nanos: 5 }),
+            ..args()
+        };
+        let id = pending.request(principal(1), retried.clone(), &conf, 0);
+        assert_eq!(pending.request(principal(1), retried.clone(), &conf, 1), id);
+        assert_ne!(pending.request(principal(2), retried.clone(), &conf, 1), id);
+
+        // Retries of an executed transfer find it, those of a failed one don't.
+        assert_eq!(pending.vote(id, principal(2), true, &conf, 2), Ok(()));
+        assert!(pending.take_approved(id, &conf).is_some());
+        pending.finish(id, Ok(7), 3);
+        assert_eq!(pending.request(principal(1), retried.clone(), &conf, 4), id);
+
+        let failed = pending.request(principal(3), retried.clone(), &conf, 4);
+        assert_eq!(pending.vote(failed, principal(2), true, &conf, 5), Ok(()));
+        assert!(pending.take_approved(failed, &conf).is_some());
+        pending.finish(failed, Err(TransferError::Unauthorized), 6);
+        assert_ne!(pending.request(principal(3), retried, &conf, 7), failed);
+    }
+
+    #[test]
+    fn retries_wait_no_longer_than_the_ledger_accepts_them() {
+        let conf = Conf {
+            approval_period: 2 * LEDGER_WINDOW,
+            ..conf()
+        };
+        let mut pending = PendingTransfers::default();
+        let retried = TransferArgs {
+            created_at_time: Some(Timestamp { timestamp_nanos: 5 }),
+            ..args()
+        };
+        let id = pending.request(principal(1), retried, &conf, 10);
+        assert_eq!(pending.get(id).unwrap().expires_at, 5 + LEDGER_WINDOW);
+        let id = pending.request(principal(1), args(), &conf, 10);
+        assert_eq!(pending.get(id).unwrap().expires_at, 10 + 2 * LEDGER_WINDOW);
+
+        pending.expire(5 + LEDGER_WINDOW);
+        pending.expire(5 + LEDGER_WINDOW + RETENTION);
+        assert_eq!(pending.values().count(), 1);
+        assert!(pending.by_created_at_time.is_empty());
+    }
+
+    #[test]
+    fn recurring_payments_wait_for_approval_separately() {
+        let hour = 60 * 60 * 1_000_000_000;
+        let conf = Conf {
+            approval_period: 2 * hour,
+            ..conf()
+        };
+        let mut schedules = Schedules::default();
+        let mut pending = PendingTransfers::default();
+        schedules
+            .add(
+                principal(1),
+                ScheduleArgs {
+                    amount: Tokens::from_e8s(100),
+                    to_principal: principal(9),
+                    to_subaccount: None,
+                    memo: None,
+                    start: 10,
+                    interval: Some(hour),
+                    end: None,
+                },
+                0,
+            )
+            .unwrap();
+
+        // Both payments come due before the signers approve the first one.
+        let mut ids = Vec::new();
+        for now in [10, 10 + hour].iter() {
+            for (_, _, owner, args) in schedules.take_due(*now) {
+                ids.push(pending.request(owner, args, &conf, *now));
+            }
+        }
+        assert_eq!(ids.len(), 2);
+        assert_ne!(ids[0], ids[1]);
+        for id in ids.iter() {
+            assert_eq!(
+                pending.vote(*id, principal(2), true, &conf, 20 + hour),
+                Ok(())
+            );
+            assert_eq!(pending.take_approved(*id, &conf).unwrap().0, principal(1));
+        }
+    }
+
+    #[test]
+    fn transfers_are_rejected_or_expire() {
+        let conf = conf();
+        let mut pending = PendingTransfers::default();
+        let rejected = pending.request(principal(4), args(), &conf, 0);
+        let expired = pending.request(principal(4), args(), &conf, 0);
+
+        assert_eq!(
+            pending.vote(rejected, principal(1), false, &conf, 1),
+            Ok(())
+        );
+        assert_eq!(
+            pending.get(rejected).unwrap().status,
+            PendingTransferStatus::Pending
+        );
+        assert_eq!(
+            pending.vote(rejected, principal(2), false, &conf, 1),
+            Ok(())
+        );
+        assert_eq!(
+            pending.get(rejected).unwrap().status,
+            PendingTransferStatus::Rejected
+        );
+
+        assert_eq!(
+            pending.vote(expired, principal(1), true, &conf, 100),
+            Err(ApprovalError::NotPending)
+        );
+        assert_eq!(
+            pending.get(expired).unwrap().status,
+            PendingTransferStatus::Expired
+        );
+
+        // Finished transfers are forgotten after the retention window.
+        let waiting = pending.request(principal(4), args(), &conf, 100);
+        pending.expire(100 + RETENTION);
+        assert!(pending.get(rejected).is_none());
+        assert!(pending.get(expired).is_none());
+        assert_eq!(
+            pending.get(waiting).unwrap().status,
+            PendingTransferStatus::Expired
+        );
+        assert_eq!(pending.values().count(), 1);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use candid::CandidType;
+use ic_ledger_types::Tokens;
+use ic_types::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::{Conf, TransferError};
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// A principal that may transfer the canister's tokens, and how much it may
+// spend per day. None means no limit of its own.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Hash, PartialEq)]
+pub struct Authorization {
+    pub principal: Principal,
+    pub daily_limit: Option<Tokens>,
+}
+
+// What was spent on the current day (UTC), in total and per principal. A
+// transfer spends its amount and the transaction fee. Amounts are in e8s.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Spending {
+    day: u64,
+    total: u64,
+    by_principal: HashMap<Principal, u64>,
+}
+
+impl Spending {
+    // Records `amount` as spent by `principal` if it is authorized and the
+    // limits of the day allow it.
+    pub fn spend(
+        &mut self,
+        conf: &Conf,
+        principal: Principal,
+        amount: Tokens,
+        now: u64,
+    ) -> Result<(), TransferError> {
+        let authorization = conf
+            .authorized
+            .iter()
+            .find(|a| a.principal == principal)
+            .ok_or(TransferError::Unauthorized)?;
+        self.start_day(now);
+
+        let spent = self.spent_by(&principal);
+        if let Some(remaining) = exceeded(spent, amount, authorization.daily_limit) {
+            return Err(TransferError::PrincipalDailyLimitExceeded { remaining });
+        }
+        if let Some(remaining) = exceeded(self.total, amount, conf.daily_limit) {
+            return Err(TransferError::DailyLimitExceeded { remaining });
+        }
+        self.total += amount.e8s();
+        self.by_principal.insert(principal, spent + amount.e8s());
+        Ok(())
+    }
+
+    // Gives back what a transfer spent at `spent_at` if the ledger did not
+    // execute it, unless the day is over anyway.
+    pub fn refund(&mut self, principal: Principal, amount: Tokens, spent_at: u64) {
+        if spent_at / DAY_NANOS != self.day {
+            return;
+        }
+        self.total -= amount.e8s();
+        let spent = self.spent_by(&principal) - amount.e8s();
+        self.by_principal.insert(principal, spent);
+    }
+
+    fn spent_by(&self, principal: &Principal) -> u64 {
+        self.by_principal.get(principal).copied().unwrap_or(0)
+    }
+
+    fn start_day(&mut self, now: u64) {
+        let day = now / DAY_NANOS;
+        if day != self.day {
+            *self = Spending {
+                day,
+                ..Spending::default()
+            };
+        }
+    }
+}
+
+// What is left of `limit` if spending `amount` on top of `spent` exceeds it.
+fn exceeded(spent: u64, amount: Tokens, limit: Option<Tokens>) -> Option<Tokens> {
+    let limit = limit?.e8s();
+    if spent.saturating_add(amount.e8s()) <= limit {
+        return None;
+    }
+    Some(Tokens::from_e8s(limit.saturating_sub(spent)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+
+    fn e8s(e8s: u64) -> Tokens {
+        Tokens::from_e8s(e8s)
+    }
+
+    #[test]
+    fn spending_is_limited_per_day() {
+        let conf = Conf {
+            authorized: vec![
+                Authorization {
+                    principal: principal(1),
+                    daily_limit: Some(e8s(100)),
+                },
+                Authorization {
+                    principal: principal(2),
+                    daily_limit: None,
+                },
+            ],
+            daily_limit: Some(e8s(150)),
+            ..Conf::default()
+        };
+        let mut spending = Spending::default();
+
+        assert_eq!(
+            spending.spend(&conf, principal(3), e8s(1), 0),
+            Err(TransferError::Unauthorized)
+        );
+        assert_eq!(spending.spend(&conf, principal(1), e8s(60), 0), Ok(()));
+        assert_eq!(
+            spending.spend(&conf, principal(1), e8s(60), 1),
+            Err(TransferError::PrincipalDailyLimitExceeded { remaining: e8s(40) })
+        );
+        assert_eq!(
+            spending.spend(&conf, principal(2), e8s(100), 2),
+            Err(TransferError::DailyLimitExceeded { remaining: e8s(90) })
+        );
+        spending.refund(principal(1), e8s(60), 0);
+        assert_eq!(spending.spend(&conf, principal(2), e8s(100), 3), Ok(()));
+
+        // The next day starts from nothing.
+        assert_eq!(spending.spend(&conf, principal(1), e8s(100), DAY_NANOS), Ok(()));
+        spending.refund(principal(2), e8s(100), 3);
+        assert_eq!(spending.total, 100);
+    }
+}
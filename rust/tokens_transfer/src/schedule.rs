@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+
+use candid::CandidType;
+use ic_ledger_types::{Memo, Subaccount, Tokens};
+use ic_types::Principal;
+use serde::{Deserialize, Serialize};
+
+use crate::{TransferArgs, TransferError, TransferReceipt};
+
+// How many payments of a schedule are kept in its history.
+const MAX_HISTORY: usize = 100;
+
+// The shortest interval between the payments of a recurring schedule.
+const MIN_INTERVAL: u64 = 60 * 1_000_000_000;
+
+// How long completed and cancelled schedules are kept.
+const RETENTION: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
+
+// A transfer to make at `start`, and then every `interval` nanoseconds until
+// `end` if it is recurring. Times are in nanoseconds since the epoch. Payments
+// that were missed, e.g. while the canister was stopped, are skipped.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ScheduleArgs {
+    pub amount: Tokens,
+    pub to_principal: Principal,
+    pub to_subaccount: Option<Subaccount>,
+    pub memo: Option<Memo>,
+    pub start: u64,
+    // None for a one-off transfer.
+    pub interval: Option<u64>,
+    // No payment is due after this time. None means forever.
+    pub end: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ScheduleStatus {
+    Active,
+    Completed,
+    Cancelled,
+}
+
+// A payment that was due at `due` and made at `executed_at`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Payment {
+    pub due: u64,
+    pub executed_at: u64,
+    pub result: Result<TransferReceipt, TransferError>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Schedule {
+    pub id: u64,
+    // The principal that registered the schedule. Payments count against its
+    // daily limits.
+    pub owner: Principal,
+    pub args: ScheduleArgs,
+    pub status: ScheduleStatus,
+    // When the next payment is due, if any.
+    pub next: Option<u64>,
+    // The most recent payments, oldest first.
+    pub history: Vec<Payment>,
+    // When the schedule was completed or cancelled.
+    pub finished_at: Option<u64>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ScheduleError {
+    // The caller may not register or cancel this schedule.
+    Unauthorized,
+    NotFound,
+    // The schedule is completed or cancelled already.
+    NotActive,
+    InvalidSchedule(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Schedules {
+    next_id: u64,
+    schedules: BTreeMap<u64, Schedule>,
+}
+
+impl Schedules {
+    pub fn add(
+        &mut self,
+        owner: Principal,
+        args: ScheduleArgs,
+        now: u64,
+    ) -> Result<u64, ScheduleError> {
+        if args.amount.e8s() == 0 {
+            return Err(ScheduleError::InvalidSchedule("amount is zero".to_string()));
+        }
+        if args.start < now {
+            return Err(ScheduleError::InvalidSchedule(
+                "start is in the past".to_string(),
+            ));
+        }
+        if args
+            .interval
+            .is_some_and(|interval| interval < MIN_INTERVAL)
+        {
+            return Err(ScheduleError::InvalidSchedule(
+                "interval is shorter than a minute".to_string(),
+            ));
+        }
+        if args.end.is_some_and(|end| end < args.start) {
+            return Err(ScheduleError::InvalidSchedule(
+                "end is before start".to_string(),
+            ));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let next = Some(args.start);
+        self.schedules.insert(
+            id,
+            Schedule {
+                id,
+                owner,
+                args,
+                status: ScheduleStatus::Active,
+                next,
+                history: Vec::new(),
+                finished_at: None,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Schedule> {
+        self.schedules.get(&id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Schedule> {
+        self.schedules.values()
+    }
+
+    // Cancels a schedule on behalf of its owner, or of the canister owner.
+    pub fn cancel(
+        &mut self,
+        id: u64,
+        caller: Principal,
+        is_owner: bool,
+        now: u64,
+    ) -> Result<(), ScheduleError> {
+        let schedule = self.schedules.get_mut(&id).ok_or(ScheduleError::NotFound)?;
+        if schedule.owner != caller && !is_owner {
+            return Err(ScheduleError::Unauthorized);
+        }
+        if schedule.status != ScheduleStatus::Active {
+            return Err(ScheduleError::NotActive);
+        }
+        schedule.status = ScheduleStatus::Cancelled;
+        schedule.next = None;
+        schedule.finished_at = Some(now);
+        Ok(())
+    }
+
+    // Takes the payments due at `now`, one per schedule, and moves each
+    // schedule on to its next payment so that it is not taken twice. Only the
+    // latest of the payments that came due since the last time is made.
+    pub fn take_due(&mut self, now: u64) -> Vec<(u64, u64, Principal, TransferArgs)> {
+        let mut due = Vec::new();
+        for schedule in self.schedules.values_mut() {
+            let mut at = match schedule.next {
+                Some(at) if schedule.status == ScheduleStatus::Active && at <= now => at,
+                _ => continue,
+            };
+            let args = &schedule.args;
+            if let Some(interval) = args.interval {
+                let last = args.end.map_or(now, |end| end.min(now));
+                at += (last - at) / interval * interval;
+            }
+            schedule.next = args
+                .interval
+                .and_then(|interval| at.checked_add(interval))
+                .filter(|next| args.end.is_none_or(|end| *next <= end));
+            if schedule.next.is_none() {
+                schedule.status = ScheduleStatus::Completed;
+                schedule.finished_at = Some(now);
+            }
+            let transfer = TransferArgs {
+                amount: args.amount,
+                to_principal: args.to_principal,
+                to_subaccount: args.to_subaccount,
+                memo: args.memo,
+                created_at_time: None,
+            };
+            due.push((schedule.id, at, schedule.owner, transfer));
+        }
+        due
+    }
+
+    // Forgets the schedules that finished before the retention window.
+    pub fn remove_finished(&mut self, now: u64) {
+        self.schedules.retain(|_, schedule| {
+            schedule
+                .finished_at
+                .is_none_or(|t| t.saturating_add(RETENTION) > now)
+        });
+    }
+
+    pub fn record(&mut self, id: u64, payment: Payment) {
+        if let Some(schedule) = self.schedules.get_mut(&id) {
+            if schedule.history.len() == MAX_HISTORY {
+                schedule.history.remove(0);
+            }
+            schedule.history.push(payment);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::principal;
+
+    const MIN: u64 = MIN_INTERVAL;
+
+    fn args(start: u64, interval: Option<u64>, end: Option<u64>) -> ScheduleArgs {
+        ScheduleArgs {
+            amount: Tokens::from_e8s(100),
+            to_principal: principal(2),
+            to_subaccount: None,
+            memo: None,
+            start,
+            interval,
+            end,
+        }
+    }
+
+    fn due_ids(schedules: &mut Schedules, now: u64) -> Vec<(u64, u64)> {
+        schedules
+            .take_due(now)
+            .into_iter()
+            .map(|(id, at, _, _)| (id, at))
+            .collect()
+    }
+
+    #[test]
+    fn payments_are_taken_when_due() {
+        let owner = principal(1);
+        let mut schedules = Schedules::default();
+        let once = schedules.add(owner, args(10, None, None), 0).unwrap();
+        let recurring = schedules
+            .add(owner, args(20, Some(MIN), Some(20 + 4 * MIN)), 0)
+            .unwrap();
+
+        assert_eq!(due_ids(&mut schedules, 5), vec![]);
+        assert_eq!(due_ids(&mut schedules, 10), vec![(once, 10)]);
+        assert_eq!(
+            schedules.get(once).unwrap().status,
+            ScheduleStatus::Completed
+        );
+        assert_eq!(due_ids(&mut schedules, 10), vec![]);
+        assert_eq!(due_ids(&mut schedules, 20), vec![(recurring, 20)]);
+
+        // A late heartbeat makes the latest payment that came due, once.
+        assert_eq!(
+            due_ids(&mut schedules, 25 + 2 * MIN),
+            vec![(recurring, 20 + 2 * MIN)]
+        );
+        assert_eq!(due_ids(&mut schedules, 25 + 2 * MIN), vec![]);
+        assert_eq!(
+            due_ids(&mut schedules, 30 + 5 * MIN),
+            vec![(recurring, 20 + 4 * MIN)]
+        );
+        let finished = schedules.get(recurring).unwrap();
+        assert_eq!(finished.status, ScheduleStatus::Completed);
+        assert_eq!(finished.finished_at, Some(30 + 5 * MIN));
+
+        // Finished schedules are forgotten after the retention window.
+        schedules.remove_finished(10 + RETENTION);
+        assert!(schedules.get(once).is_none());
+        assert!(schedules.get(recurring).is_some());
+    }
+
+    #[test]
+    fn invalid_schedules_are_refused() {
+        let owner = principal(1);
+        let mut schedules = Schedules::default();
+        let invalid = |reason: &str| Err(ScheduleError::InvalidSchedule(reason.to_string()));
+        assert_eq!(
+            schedules.add(owner, args(20, None, None), 21),
+            invalid("start is in the past")
+        );
+        assert_eq!(
+            schedules.add(owner, args(20, Some(MIN - 1), None), 0),
+            invalid("interval is shorter than a minute")
+        );
+        assert_eq!(
+            schedules.add(owner, args(20, Some(MIN), Some(19)), 0),
+            invalid("end is before start")
+        );
+        assert_eq!(schedules.values().count(), 0);
+    }
+
+    #[test]
+    fn schedules_can_be_cancelled() {
+        let owner = principal(1);
+        let other = principal(3);
+        let mut schedules = Schedules::default();
+        let id = schedules.add(owner, args(10, Some(MIN), None), 0).unwrap();
+
+        assert_eq!(
+            schedules.cancel(id, other, false, 1),
+            Err(ScheduleError::Unauthorized)
+        );
+        assert_eq!(
+            schedules.cancel(id + 1, owner, false, 1),
+            Err(ScheduleError::NotFound)
+        );
+        assert_eq!(schedules.cancel(id, other, true, 1), Ok(()));
+        assert_eq!(
+            schedules.cancel(id, owner, false, 1),
+            Err(ScheduleError::NotActive)
+        );
+        assert_eq!(due_ids(&mut schedules, 100), vec![]);
+        schedules.remove_finished(1 + RETENTION);
+        assert!(schedules.get(id).is_none());
+    }
+}
@@ -0,0 +1,6 @@
+use ic_types::Principal;
+
+// A principal made of one byte, for tests.
+pub fn principal(id: u8) -> Principal {
+    Principal::from_slice(&[id])
+}